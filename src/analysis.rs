@@ -0,0 +1,320 @@
+use std::f32::consts::PI;
+
+use crate::{globals::SAMPLE_RATE, utils, wave::Wave};
+
+// loudness measurement after EBU R128 / ITU-R BS.1770
+
+const MOMENTARY_WINDOW: f32 = 0.4;
+const SHORT_TERM_WINDOW: f32 = 3.0;
+const HOP: f32 = 0.1;
+const ABSOLUTE_GATE: f32 = -70.0;
+const RELATIVE_GATE: f32 = -10.0;
+const RANGE_RELATIVE_GATE: f32 = -20.0;
+const TRUE_PEAK_OVERSAMPLING: usize = 4;
+const TRUE_PEAK_TAPS: usize = 12;
+const CLIP_MERGE_WINDOW: f32 = 0.01;
+
+#[derive(Debug, Clone)]
+pub struct Loudness {
+    /// integrated loudness in LUFS
+    pub integrated: f32,
+    /// momentary loudness (400ms window) every 100ms in LUFS
+    pub momentary: Vec<f32>,
+    /// short-term loudness (3s window) every 100ms in LUFS
+    pub short_term: Vec<f32>,
+    /// loudness range in LU
+    pub range: f32,
+    /// true peak in dBTP
+    pub true_peak: f32,
+}
+
+impl Loudness {
+    pub fn of(wave: &Wave) -> Self {
+        let power = weighted_power(wave);
+        let momentary_blocks = block_powers(&power, MOMENTARY_WINDOW);
+        let short_term_blocks = block_powers(&power, SHORT_TERM_WINDOW);
+        Self {
+            integrated: gated_loudness(&momentary_blocks, RELATIVE_GATE),
+            range: loudness_range(&short_term_blocks),
+            momentary: momentary_blocks.into_iter().map(power_to_lufs).collect(),
+            short_term: short_term_blocks.into_iter().map(power_to_lufs).collect(),
+            true_peak: true_peak(wave),
+        }
+    }
+}
+
+pub fn integrated_loudness(wave: &Wave) -> f32 {
+    gated_loudness(
+        &block_powers(&weighted_power(wave), MOMENTARY_WINDOW),
+        RELATIVE_GATE,
+    )
+}
+
+pub fn true_peak(wave: &Wave) -> f32 {
+    let kernel = oversampling_kernel();
+    let peak = f32::max(
+        oversampled_peak(wave.right(), &kernel),
+        oversampled_peak(wave.left(), &kernel),
+    );
+    utils::factor_to_db(peak)
+}
+
+/// a stretch of samples exceeding full scale
+#[derive(Debug, Clone, Copy)]
+pub struct ClipEvent {
+    /// start in seconds
+    pub start: f32,
+    /// end in seconds
+    pub end: f32,
+    /// highest sample in dBFS
+    pub peak: f32,
+}
+
+/// lists where the wave exceeds 0 dBFS, clips closer than 10ms are merged
+pub fn clipping(wave: &Wave) -> Vec<ClipEvent> {
+    let merge = utils::seconds_to_samples(CLIP_MERGE_WINDOW);
+    let mut out: Vec<(usize, usize, f32)> = Vec::new();
+    for (i, (r, l)) in wave.right().iter().zip(wave.left()).enumerate() {
+        let peak = f32::max(r.abs(), l.abs());
+        if peak <= 1.0 {
+            continue;
+        }
+        match out.last_mut() {
+            Some((_, end, max)) if i - *end <= merge => {
+                *end = i;
+                *max = f32::max(*max, peak);
+            }
+            _ => out.push((i, i, peak)),
+        }
+    }
+    out.into_iter()
+        .map(|(start, end, peak)| ClipEvent {
+            start: utils::samples_to_seconds(start),
+            end: utils::samples_to_seconds(end + 1),
+            peak: utils::factor_to_db(peak),
+        })
+        .collect()
+}
+
+#[inline(always)]
+fn power_to_lufs(power: f32) -> f32 {
+    -0.691 + 10.0 * power.log10()
+}
+
+#[inline(always)]
+fn lufs_to_power(lufs: f32) -> f32 {
+    10.0_f32.powf((lufs + 0.691) / 10.0)
+}
+
+/// K-weighted squared signal summed over both channels
+fn weighted_power(wave: &Wave) -> Vec<f32> {
+    let right = k_weighting(wave.right());
+    let left = k_weighting(wave.left());
+    right
+        .into_iter()
+        .zip(left)
+        .map(|(r, l)| r * r + l * l)
+        .collect()
+}
+
+fn k_weighting(signal: &[f32]) -> Vec<f32> {
+    let fs = SAMPLE_RATE as f64;
+
+    // high shelf modelling the acoustic effect of the head
+    let f0 = 1681.974450955533;
+    let gain = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (std::f64::consts::PI * f0 / fs).tan();
+    let vh = 10.0_f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    };
+
+    // RLB high pass
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (std::f64::consts::PI * f0 / fs).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    };
+
+    high_pass.process(&shelf.process(signal))
+}
+
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&self, signal: &[f32]) -> Vec<f32> {
+        let mut out = Vec::with_capacity(signal.len());
+        let (mut x1, mut x2, mut y1, mut y2) = (0.0, 0.0, 0.0, 0.0);
+        for x in signal {
+            let x = *x as f64;
+            let y =
+                self.b[0] * x + self.b[1] * x1 + self.b[2] * x2 - self.a[0] * y1 - self.a[1] * y2;
+            x2 = x1;
+            x1 = x;
+            y2 = y1;
+            y1 = y;
+            out.push(y as f32);
+        }
+        out
+    }
+}
+
+/// mean power of windows of `window` seconds every 100ms
+fn block_powers(power: &[f32], window: f32) -> Vec<f32> {
+    let window = utils::seconds_to_samples(window);
+    let hop = utils::seconds_to_samples(HOP);
+    if power.len() < window {
+        return Vec::new();
+    }
+    let mut out = Vec::with_capacity((power.len() - window) / hop + 1);
+    let mut sum: f64 = power[..window].iter().map(|x| *x as f64).sum();
+    let mut start = 0;
+    loop {
+        out.push((sum / window as f64) as f32);
+        if start + hop + window > power.len() {
+            break;
+        }
+        sum -= power[start..start + hop]
+            .iter()
+            .map(|x| *x as f64)
+            .sum::<f64>();
+        sum += power[start + window..start + window + hop]
+            .iter()
+            .map(|x| *x as f64)
+            .sum::<f64>();
+        start += hop;
+    }
+    out
+}
+
+fn gated_loudness(blocks: &[f32], relative_gate: f32) -> f32 {
+    let gated: Vec<f32> = blocks
+        .iter()
+        .copied()
+        .filter(|p| power_to_lufs(*p) > ABSOLUTE_GATE)
+        .collect();
+    if gated.is_empty() {
+        return f32::NEG_INFINITY;
+    }
+    let threshold = power_to_lufs(mean(&gated)) + relative_gate;
+    let gated: Vec<f32> = gated
+        .into_iter()
+        .filter(|p| power_to_lufs(*p) > threshold)
+        .collect();
+    if gated.is_empty() {
+        return f32::NEG_INFINITY;
+    }
+    power_to_lufs(mean(&gated))
+}
+
+fn loudness_range(short_term: &[f32]) -> f32 {
+    let gated: Vec<f32> = short_term
+        .iter()
+        .copied()
+        .filter(|p| power_to_lufs(*p) > ABSOLUTE_GATE)
+        .collect();
+    if gated.is_empty() {
+        return 0.0;
+    }
+    let threshold = lufs_to_power(power_to_lufs(mean(&gated)) + RANGE_RELATIVE_GATE);
+    let mut gated: Vec<f32> = gated
+        .into_iter()
+        .filter(|p| *p > threshold)
+        .map(power_to_lufs)
+        .collect();
+    if gated.is_empty() {
+        return 0.0;
+    }
+    gated.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let percentile = |p: f32| gated[((gated.len() - 1) as f32 * p).round() as usize];
+    percentile(0.95) - percentile(0.10)
+}
+
+fn mean(vec: &[f32]) -> f32 {
+    (vec.iter().map(|x| *x as f64).sum::<f64>() / vec.len() as f64) as f32
+}
+
+/// polyphase windowed sinc kernel, one phase per oversampled position
+fn oversampling_kernel() -> Vec<Vec<f32>> {
+    let half = TRUE_PEAK_TAPS as f32 / 2.0;
+    (0..TRUE_PEAK_OVERSAMPLING)
+        .map(|phase| {
+            let offset = phase as f32 / TRUE_PEAK_OVERSAMPLING as f32;
+            (0..TRUE_PEAK_TAPS)
+                .map(|tap| {
+                    let x = tap as f32 - half + 1.0 - offset;
+                    let window = 0.5 + 0.5 * (PI * x / (half + 1.0)).cos();
+                    let sinc = if x == 0.0 {
+                        1.0
+                    } else {
+                        (PI * x).sin() / (PI * x)
+                    };
+                    sinc * window
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn oversampled_peak(signal: &[f32], kernel: &[Vec<f32>]) -> f32 {
+    let mut peak = utils::max_abs_f32(signal);
+    let half = TRUE_PEAK_TAPS / 2;
+    for i in 0..signal.len() {
+        for phase in kernel.iter().skip(1) {
+            let mut value = 0.0;
+            for (tap, coeff) in phase.iter().enumerate() {
+                if let Some(x) = (i + tap + 1).checked_sub(half).and_then(|j| signal.get(j)) {
+                    value += x * coeff;
+                }
+            }
+            peak = f32::max(peak, value.abs());
+        }
+    }
+    peak
+}
+
+#[cfg(test)]
+mod test {
+    use std::f32::consts::TAU;
+
+    use super::*;
+
+    fn sine(freq: f32, amplitude: f32, seconds: f32) -> Wave {
+        Wave::from_vec(
+            (0..utils::seconds_to_samples(seconds))
+                .map(|i| amplitude * (TAU * freq * i as f32 / SAMPLE_RATE as f32).sin())
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn sine_loudness() {
+        // a 1kHz sine on both channels reads as its peak level in dBFS
+        let loudness = Loudness::of(&sine(1000.0, 0.1, 5.0));
+        assert!((loudness.integrated + 20.0).abs() < 0.1);
+        assert!(loudness.range < 0.1);
+        assert!((loudness.true_peak + 20.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn silence_is_gated() {
+        assert_eq!(
+            integrated_loudness(&Wave::zeros(SAMPLE_RATE)),
+            f32::NEG_INFINITY
+        );
+    }
+}
@@ -2,10 +2,12 @@ use crate::{time::ClockTick, wave::Wave};
 use std::fmt::Debug;
 
 pub mod delay;
+pub mod limiter;
 pub mod reverb;
 pub mod volume;
 
 pub use delay::Delay;
+pub use limiter::Limiter;
 use serde::{Deserialize, Serialize};
 
 use self::volume::Volume;
@@ -14,6 +16,7 @@ use self::volume::Volume;
 pub enum Effect {
     Delay(Delay),
     Volume(Volume),
+    Limiter(Limiter),
 }
 
 impl Effect {
@@ -21,6 +24,7 @@ impl Effect {
         match self {
             Effect::Delay(eff) => eff.apply(wave, time_triggered),
             Effect::Volume(eff) => eff.apply(wave, time_triggered),
+            Effect::Limiter(eff) => eff.apply(wave, time_triggered),
         }
     }
 
@@ -28,6 +32,7 @@ impl Effect {
         match self {
             Effect::Delay(eff) => eff.set_defaults(),
             Effect::Volume(eff) => eff.set_defaults(),
+            Effect::Limiter(eff) => eff.set_defaults(),
         }
     }
 
@@ -35,6 +40,7 @@ impl Effect {
         match self {
             Effect::Delay(eff) => eff.on(),
             Effect::Volume(eff) => eff.on(),
+            Effect::Limiter(eff) => eff.on(),
         }
     }
 
@@ -42,6 +48,7 @@ impl Effect {
         match self {
             Effect::Delay(eff) => eff.off(),
             Effect::Volume(eff) => eff.off(),
+            Effect::Limiter(eff) => eff.off(),
         }
     }
 
@@ -49,6 +56,7 @@ impl Effect {
         match self {
             Effect::Delay(eff) => eff.toggle(),
             Effect::Volume(eff) => eff.toggle(),
+            Effect::Limiter(eff) => eff.toggle(),
         }
     }
 }
@@ -58,6 +66,7 @@ impl Effect {
         match self {
             Effect::Delay(eff) => Effect::Delay(eff.extract()),
             Effect::Volume(eff) => Effect::Volume(eff.extract()),
+            Effect::Limiter(eff) => Effect::Limiter(eff.extract()),
         }
     }
 
@@ -65,14 +74,16 @@ impl Effect {
         match self {
             Effect::Delay(eff) => eff.set_id(track_id),
             Effect::Volume(eff) => eff.set_id(track_id),
+            Effect::Limiter(eff) => eff.set_id(track_id),
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub enum EffectPanel {
     Leaf(Effect),
     Node(Vec<EffectPanel>),
+    #[default]
     EmptyLeaf,
 }
 
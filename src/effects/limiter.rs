@@ -0,0 +1,153 @@
+use std::collections::VecDeque;
+
+use crate::{
+    globals::SAMPLE_RATE,
+    network::{Receiver, Transform},
+    time::ClockTick,
+    utils,
+    wave::Wave,
+};
+use serde::{Deserialize, Serialize};
+
+const CEILING_RECEIVER: Receiver = Receiver::new(-0.3, (-24.0, 0.0), Transform::Decibel);
+const LOOKAHEAD_RECEIVER: Receiver = Receiver::new(0.005, (0.0005, 0.05), Transform::Linear);
+const RELEASE_RECEIVER: Receiver = Receiver::new(0.1, (0.001, 2.0), Transform::Linear);
+
+/// look-ahead brickwall limiter, the output never exceeds the ceiling
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Limiter {
+    on: bool,
+    ceiling: Receiver,
+    lookahead: Receiver,
+    release: Receiver,
+}
+
+impl Limiter {
+    pub fn new() -> Self {
+        Self {
+            on: true,
+            ceiling: CEILING_RECEIVER,
+            lookahead: LOOKAHEAD_RECEIVER,
+            release: RELEASE_RECEIVER,
+        }
+    }
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Limiter {
+    pub fn apply(&self, wave: &mut Wave, time_triggered: ClockTick) {
+        if !self.on || wave.is_empty() {
+            return;
+        }
+        let ceiling = self.ceiling.get_val(time_triggered);
+        let lookahead = utils::seconds_to_samples(self.lookahead.get_val(time_triggered)).max(1);
+        let release =
+            1.0 - (-1.0 / (self.release.get_val(time_triggered) * SAMPLE_RATE as f32)).exp();
+
+        let required: Vec<f32> = wave
+            .right()
+            .iter()
+            .zip(wave.left())
+            .map(|(r, l)| {
+                let peak = f32::max(r.abs(), l.abs());
+                if peak > ceiling {
+                    ceiling / peak
+                } else {
+                    1.0
+                }
+            })
+            .collect();
+
+        // smallest gain needed within the next `lookahead` samples
+        let mut ahead = Vec::with_capacity(required.len());
+        let mut window: VecDeque<usize> = VecDeque::new();
+        for i in (0..required.len()).rev() {
+            while window.back().is_some_and(|j| required[*j] >= required[i]) {
+                window.pop_back();
+            }
+            window.push_back(i);
+            while window.front().is_some_and(|j| *j > i + lookahead) {
+                window.pop_front();
+            }
+            ahead.push(required[*window.front().unwrap()]);
+        }
+        ahead.reverse();
+
+        // averaging over the look-ahead fades the gain in before a peak without overshooting it
+        let mut gain = Vec::with_capacity(ahead.len());
+        let mut sum = 0.0;
+        let mut last = 1.0;
+        for i in 0..ahead.len() {
+            sum += ahead[i];
+            sum -= if i > lookahead {
+                ahead[i - lookahead - 1]
+            } else {
+                1.0
+            };
+            let attack = sum / (lookahead + 1) as f32;
+            last = f32::min(attack, last + (1.0 - last) * release);
+            gain.push(last);
+        }
+
+        wave.scale_by_vec(gain);
+        wave.clamp(ceiling);
+    }
+
+    pub fn set_defaults(&mut self) {
+        self.ceiling = CEILING_RECEIVER;
+        self.lookahead = LOOKAHEAD_RECEIVER;
+        self.release = RELEASE_RECEIVER;
+    }
+
+    pub fn on(&mut self) {
+        self.on = true
+    }
+
+    pub fn off(&mut self) {
+        self.on = false
+    }
+
+    pub fn toggle(&mut self) {
+        self.on = !self.on
+    }
+}
+
+impl Limiter {
+    pub fn extract(&self) -> Self {
+        Self {
+            on: self.on,
+            ceiling: self.ceiling.extract(),
+            lookahead: self.lookahead.extract(),
+            release: self.release.extract(),
+        }
+    }
+
+    pub fn set_id(&mut self, track_id: u8) {
+        self.ceiling.set_id(track_id);
+        self.lookahead.set_id(track_id);
+        self.release.set_id(track_id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn output_stays_below_ceiling() {
+        let mut wave = Wave::from_vec(
+            (0..10_000)
+                .map(|i| (i as f32 * 0.05).sin() * if i % 3000 < 200 { 4.0 } else { 0.5 })
+                .collect(),
+        );
+        Limiter::new().apply(&mut wave, ClockTick::abs_zero());
+        let ceiling = utils::db_to_factor(-0.3);
+        assert!(utils::max_abs_f32(wave.right()) <= ceiling);
+        assert!(utils::max_abs_f32(wave.left()) <= ceiling);
+    }
+}
@@ -1,6 +1,11 @@
 use serde::{Deserialize, Serialize};
 
-use crate::{network::Receiver, receivers::VOL_RECEIVER, time::ClockTick, wave::Wave};
+use crate::{
+    network::Receiver,
+    receivers::{DB_VOL_RECEIVER, VOL_RECEIVER},
+    time::ClockTick,
+    wave::Wave,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Volume {
@@ -15,6 +20,13 @@ impl Volume {
             on: true,
         }
     }
+
+    pub fn new_db() -> Self {
+        Self {
+            volume: DB_VOL_RECEIVER,
+            on: true,
+        }
+    }
 }
 
 impl Default for Volume {
@@ -18,6 +18,7 @@ pub enum Error {
     ReceiverMisMatch,
     Value,
     Parse,
+    Version,
 }
 
 impl Display for Error {
@@ -1,4 +1,4 @@
-use crate::{time::ClockTick, Error};
+use crate::{time::ClockTick, utils::oscs::Oscillator, Error};
 use std::{
     collections::{hash_map::Entry, HashMap},
     fmt::Debug,
@@ -8,25 +8,41 @@ pub mod constant;
 pub mod envelope;
 pub mod lfo;
 pub mod point_defined;
+pub mod rendered;
 
 pub use constant::Constant;
 pub use envelope::Envelope;
 pub use lfo::Lfo;
 pub use point_defined::PointDefined;
+pub use rendered::Rendered;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
 pub enum GenId {
     Global(u8),
 
-    Track { track_id: u8, key: u8 },
+    Track {
+        track_id: u8,
+        key: u8,
+    },
 
-    Instr { track_id: u8, key: u8 },
-    InstrExtracted { key: u8 },
+    Instr {
+        track_id: u8,
+        key: u8,
+    },
+    InstrExtracted {
+        key: u8,
+    },
 
-    Specific { track_id: u8, kind: Specific },
-    SpecificExtracted { kind: Specific },
+    Specific {
+        track_id: u8,
+        kind: Specific,
+    },
+    SpecificExtracted {
+        kind: Specific,
+    },
 
+    #[default]
     Unbound,
 }
 
@@ -35,6 +51,15 @@ pub enum Specific {
     Vel,
     ModW,
     Pitch,
+    /// the pitch of the note being played, from 0.0 to 1.0 over the midi range
+    Key,
+    /// envelopes of the note being played
+    MainEnvelope,
+    AltEnvelope,
+    /// mpe controllers of the note being played
+    NoteBend,
+    Timbre,
+    Pressure,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -117,12 +142,6 @@ impl GenId {
     }
 }
 
-impl Default for GenId {
-    fn default() -> Self {
-        Self::Unbound
-    }
-}
-
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Generator {
     Empty,
@@ -130,6 +149,7 @@ pub enum Generator {
     Lfo(Lfo),
     PointDefined(PointDefined),
     Envelope(Envelope),
+    Rendered(Rendered),
 }
 
 impl Generator {
@@ -139,6 +159,7 @@ impl Generator {
             Generator::Lfo(f) => f.get_sub_ids(),
             Generator::PointDefined(_) => Vec::new(),
             Generator::Envelope(f) => f.get_sub_ids(),
+            Generator::Rendered(f) => f.get_sub_ids(),
             Generator::Empty => Vec::new(),
         }
     }
@@ -149,6 +170,7 @@ impl Generator {
             Generator::Lfo(gen) => gen.set_id(id),
             Generator::PointDefined(gen) => gen.set_id(id),
             Generator::Envelope(gen) => gen.set_id(id),
+            Generator::Rendered(gen) => gen.set_id(id),
             Generator::Empty => (),
         }
     }
@@ -161,6 +183,7 @@ impl Generator {
             Generator::Lfo(f) => Ok(f.get_val(time)),
             Generator::PointDefined(f) => Ok(f.get_val(time)),
             Generator::Envelope(_) => Err(Error::Type),
+            Generator::Rendered(f) => Ok(f.get_val(time)),
             Generator::Empty => Err(Error::Existence),
         }
     }
@@ -171,6 +194,7 @@ impl Generator {
             Generator::Lfo(f) => f.get_vec(start, samples),
             Generator::PointDefined(f) => f.get_vec(start, samples),
             Generator::Envelope(f) => f.get_vec(start, samples),
+            Generator::Rendered(f) => f.get_vec(start, samples),
             Generator::Empty => todo!(),
         }
     }
@@ -191,6 +215,16 @@ impl Generator {
             _ => Err(Error::Type),
         }
     }
+
+    fn set_rendered(&mut self, start: ClockTick, values: Vec<f32>) -> Result<(), Error> {
+        match self {
+            Generator::Rendered(rendered) => {
+                rendered.set(start, values);
+                Ok(())
+            }
+            _ => Err(Error::Type),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -296,6 +330,14 @@ impl GenSaveBuilder {
         Err(Error::Overflow)
     }
 
+    /// the oscillators of the lfos
+    pub(crate) fn lfo_oscillators_mut(&mut self) -> impl Iterator<Item = &mut Oscillator> {
+        self.map.values_mut().filter_map(|gen| match gen {
+            Generator::Lfo(lfo) => Some(lfo.oscillator_mut()),
+            _ => None,
+        })
+    }
+
     pub fn as_generator_save(self, track_id: u8, kind: TI) -> GeneratorSave {
         GeneratorSave {
             id: Some((track_id, kind)),
@@ -324,6 +366,18 @@ pub struct TrackGManager {
     pub pitchbend: Generator,
     pub velocity: Generator,
     pub mod_wheel: Generator,
+    #[serde(default = "Constant::w_default")]
+    pub key: Generator,
+    #[serde(default = "Rendered::w_default")]
+    pub main_envelope: Generator,
+    #[serde(default = "Rendered::w_default")]
+    pub alt_envelope: Generator,
+    #[serde(default = "Rendered::w_default")]
+    pub note_bend: Generator,
+    #[serde(default = "Rendered::w_default")]
+    pub timbre: Generator,
+    #[serde(default = "Rendered::w_default")]
+    pub pressure: Generator,
     // pub channel_after_touch: Option<PointDefined>,
     pub track: GeneratorSave,
     pub instr: GeneratorSave,
@@ -336,6 +390,12 @@ impl TrackGManager {
             pitchbend: PointDefined::new_val(0.5).unwrap().wrap(),
             velocity: Constant::new().wrap(),
             mod_wheel: PointDefined::new_val(0.0).unwrap().wrap(),
+            key: Constant::w_default(),
+            main_envelope: Rendered::w_default(),
+            alt_envelope: Rendered::w_default(),
+            note_bend: Rendered::w_default(),
+            timbre: Rendered::w_default(),
+            pressure: Rendered::w_default(),
             // channel_after_touch: None,
             track: GeneratorSave::new(Some((id, TI::Track))),
             instr: GeneratorSave::new(Some((id, TI::Instr))),
@@ -347,6 +407,12 @@ impl TrackGManager {
             Specific::Vel => &self.velocity,
             Specific::ModW => &self.mod_wheel,
             Specific::Pitch => &self.pitchbend,
+            Specific::Key => &self.key,
+            Specific::MainEnvelope => &self.main_envelope,
+            Specific::AltEnvelope => &self.alt_envelope,
+            Specific::NoteBend => &self.note_bend,
+            Specific::Timbre => &self.timbre,
+            Specific::Pressure => &self.pressure,
         }
     }
 
@@ -355,6 +421,12 @@ impl TrackGManager {
             Specific::Vel => &mut self.velocity,
             Specific::ModW => &mut self.mod_wheel,
             Specific::Pitch => &mut self.pitchbend,
+            Specific::Key => &mut self.key,
+            Specific::MainEnvelope => &mut self.main_envelope,
+            Specific::AltEnvelope => &mut self.alt_envelope,
+            Specific::NoteBend => &mut self.note_bend,
+            Specific::Timbre => &mut self.timbre,
+            Specific::Pressure => &mut self.pressure,
         }
     }
 }
@@ -478,6 +550,15 @@ impl GeneratorManager {
     pub fn set_const(&mut self, id: GenId, val: f32) -> Result<(), Error> {
         self.get_mut(id)?.set_const(val)
     }
+
+    pub fn set_rendered(
+        &mut self,
+        id: GenId,
+        start: ClockTick,
+        values: Vec<f32>,
+    ) -> Result<(), Error> {
+        self.get_mut(id)?.set_rendered(start, values)
+    }
 }
 
 impl GeneratorManager {
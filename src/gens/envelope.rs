@@ -183,6 +183,30 @@ impl Envelope {
     }
 }
 
+impl Envelope {
+    pub fn extract(&self) -> Self {
+        Self {
+            id: self.id.extract().unwrap_or_default(),
+            attack: self.attack.extract(),
+            decay: self.decay.extract(),
+            sustain: self.sustain.extract(),
+            half_life: self.half_life.as_ref().map(|receiver| receiver.extract()),
+            release: self.release.extract(),
+        }
+    }
+
+    /// binds the receivers of an envelope which isn't stored in the GeneratorManager
+    pub fn set_track_id(&mut self, track_id: u8) {
+        self.attack.set_id(track_id);
+        self.decay.set_id(track_id);
+        self.sustain.set_id(track_id);
+        if let Some(receiver) = &mut self.half_life {
+            receiver.set_id(track_id);
+        }
+        self.release.set_id(track_id);
+    }
+}
+
 impl Envelope {
     pub fn get_vec(&self, _start: ClockTick, _samples: usize) -> Vec<f32> {
         todo!()
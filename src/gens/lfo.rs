@@ -21,6 +21,9 @@ pub struct Lfo {
     freq: Receiver,
     modulation: Receiver,
     phase_shift: f32,
+    /// of the noise oscillators
+    #[serde(default)]
+    seed: u64,
 }
 
 impl Lfo {
@@ -31,6 +34,7 @@ impl Lfo {
             freq: FREQ_RECEIVER,
             modulation: MOD_RECEIVER,
             phase_shift: 0.0,
+            seed: 0,
         }
     }
 
@@ -42,6 +46,10 @@ impl Lfo {
         self.id = id
     }
 
+    pub(crate) fn oscillator_mut(&mut self) -> &mut Oscillator {
+        &mut self.oscillator
+    }
+
     pub fn get_sub_ids(&self) -> Vec<GenId> {
         let mut out = self.freq.get_ids();
         out.append(&mut self.modulation.get_ids());
@@ -54,7 +62,8 @@ impl Lfo {
         self.set_freq(&other.freq)?;
         self.set_modulation(&other.modulation)?;
         self.phase_shift = other.phase_shift;
-        self.oscillator = other.oscillator;
+        self.seed = other.seed;
+        self.oscillator = other.oscillator.clone();
         Ok(())
     }
 
@@ -66,6 +75,10 @@ impl Lfo {
         network::set_receiver(&mut self.modulation, self.id, modulation)
     }
 
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed
+    }
+
     pub fn wrap(self) -> Generator {
         Generator::Lfo(self)
     }
@@ -79,25 +92,30 @@ impl Default for Lfo {
 
 impl Lfo {
     pub fn get_val(&self, time: ClockTick) -> f32 {
-        let phase =
-            ((TIME_MANAGER.read().unwrap().tick_to_second(time) * TAU * self.freq.get_val(time)
-                / (SAMPLE_RATE as f32))
-                + self.phase_shift)
-                % TAU;
-        (self
-            .oscillator
-            .get_sample(phase, self.modulation.get_val(time))
-            + 1.0)
-            / 2.0
+        let freq = self.freq.get_val(time);
+        let phase = ((TIME_MANAGER.read().unwrap().tick_to_second(time) * TAU * freq
+            / (SAMPLE_RATE as f32))
+            + self.phase_shift)
+            % TAU;
+        // the noise is seeded with the time to get a new value for every tick
+        let seed = self.seed ^ (time.f32() as u64).wrapping_mul(0x9E37_79B9);
+        let value = self.oscillator.get_sample_seeded(
+            phase.rem_euclid(TAU),
+            self.modulation.get_val(time),
+            freq,
+            seed,
+        );
+        (value + 1.0) / 2.0
     }
 
     pub fn get_vec(&self, start: ClockTick, samples: usize) -> Vec<f32> {
         self.oscillator
-            .play_shifted(
+            .play_seeded(
                 &self.freq.get_vec(start, samples),
                 &self.modulation.get_vec(start, samples),
                 samples,
                 self.phase_shift,
+                self.seed,
             )
             .into_iter()
             .map(|x| (x + 1.0) / 2.0)
@@ -0,0 +1,79 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{globals::TIME_MANAGER, time::ClockTick};
+
+use super::{GenId, Generator};
+
+/// values computed for the note being played, like its envelopes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rendered {
+    id: GenId,
+    start: ClockTick,
+    #[serde(skip)]
+    values: Vec<f32>,
+}
+
+impl Rendered {
+    pub fn wrap(self) -> Generator {
+        Generator::Rendered(self)
+    }
+
+    pub fn w_default() -> Generator {
+        Generator::Rendered(Self::default())
+    }
+
+    pub fn new() -> Self {
+        Self {
+            id: GenId::Unbound,
+            start: ClockTick::abs_zero(),
+            values: Vec::new(),
+        }
+    }
+
+    pub fn get_sub_ids(&self) -> Vec<GenId> {
+        Vec::new()
+    }
+
+    pub(crate) fn set_id(&mut self, id: GenId) {
+        self.id = id
+    }
+
+    /// `values` start at `start`, before and after they are zero
+    pub fn set(&mut self, start: ClockTick, values: Vec<f32>) {
+        self.start = start;
+        self.values = values
+    }
+}
+
+impl Rendered {
+    pub fn get_val(&self, time: ClockTick) -> f32 {
+        let time_manager = TIME_MANAGER.read().unwrap();
+        let index = time_manager
+            .tick_to_sample(time)
+            .checked_sub(time_manager.tick_to_sample(self.start));
+        index
+            .and_then(|i| self.values.get(i))
+            .copied()
+            .unwrap_or(0.0)
+    }
+
+    pub fn get_vec(&self, start: ClockTick, samples: usize) -> Vec<f32> {
+        let time_manager = TIME_MANAGER.read().unwrap();
+        let start = time_manager.tick_to_sample(start);
+        let own_start = time_manager.tick_to_sample(self.start);
+        (start..start + samples)
+            .map(|i| {
+                i.checked_sub(own_start)
+                    .and_then(|i| self.values.get(i))
+                    .copied()
+                    .unwrap_or(0.0)
+            })
+            .collect()
+    }
+}
+
+impl Default for Rendered {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -2,7 +2,10 @@ use std::sync::RwLock;
 
 use once_cell::sync::Lazy;
 
-use crate::{gens::GeneratorManager, resources::ResourceManager, time::TimeManager};
+use crate::{
+    gens::GeneratorManager, resources::ResourceManager, time::TimeManager, tuning::TuningManager,
+    RenderWarning,
+};
 
 pub static SAMPLE_RATE: usize = 44100;
 
@@ -11,3 +14,7 @@ pub static TIME_MANAGER: Lazy<RwLock<TimeManager>> = Lazy::new(RwLock::default);
 pub static GENRATOR_MANAGER: Lazy<RwLock<GeneratorManager>> = Lazy::new(RwLock::default);
 
 pub static RESOURCE_MANAGER: Lazy<RwLock<ResourceManager>> = Lazy::new(RwLock::default);
+
+pub static TUNING_MANAGER: Lazy<RwLock<TuningManager>> = Lazy::new(RwLock::default);
+
+pub static RENDER_WARNINGS: Lazy<RwLock<Vec<RenderWarning>>> = Lazy::new(RwLock::default);
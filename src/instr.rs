@@ -1,16 +1,33 @@
-use self::drums::Drums;
+use self::{
+    drum_machine::DrumMachine, drums::Drums, fm::FmSynth, granular::Granular, layered::Layered,
+    pluck::PluckedString, sampler::Sampler,
+};
 use crate::{tracks::midi, wave::Wave, Error};
+pub use preset::InstrumentBuilder;
 use serde::{Deserialize, Serialize};
-use std::{fs::File, path::Path};
+use std::path::Path;
 pub use synth::Synthesizer;
 
+pub mod drum_machine;
 pub mod drums;
+pub mod fm;
+pub mod granular;
+pub mod layered;
+pub mod pluck;
+pub mod preset;
+pub mod sampler;
 pub mod synth;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum MidiInstrument {
     Synthesizer(Box<Synthesizer>),
+    Fm(Box<FmSynth>),
     Drums(Box<Drums>),
+    DrumMachine(Box<DrumMachine>),
+    Sampler(Box<Sampler>),
+    PluckedString(Box<PluckedString>),
+    Granular(Box<Granular>),
+    Layered(Box<Layered>),
     Empty { name: String },
 }
 
@@ -30,7 +47,13 @@ impl MidiInstrument {
     pub fn play_note(&self, note: midi::Note) -> Wave {
         match self {
             MidiInstrument::Synthesizer(synth) => synth.play_note(note),
+            MidiInstrument::Fm(fm) => fm.play_note(note),
             MidiInstrument::Drums(drums) => drums.play_note(note),
+            MidiInstrument::DrumMachine(drums) => drums.play_note(note),
+            MidiInstrument::Sampler(sampler) => sampler.play_note(note),
+            MidiInstrument::PluckedString(string) => string.play_note(note),
+            MidiInstrument::Granular(granular) => granular.play_note(note),
+            MidiInstrument::Layered(layered) => layered.play_note(note),
             MidiInstrument::Empty { name: _ } => Wave::new(),
         }
     }
@@ -38,7 +61,13 @@ impl MidiInstrument {
     pub fn play_notes(&self, notes: &[midi::Note]) -> Wave {
         match self {
             MidiInstrument::Synthesizer(synth) => synth.play_notes(notes),
+            MidiInstrument::Fm(fm) => fm.play_notes(notes),
             MidiInstrument::Drums(drums) => drums.play_notes(notes),
+            MidiInstrument::DrumMachine(drums) => drums.play_notes(notes),
+            MidiInstrument::Sampler(sampler) => sampler.play_notes(notes),
+            MidiInstrument::PluckedString(string) => string.play_notes(notes),
+            MidiInstrument::Granular(granular) => granular.play_notes(notes),
+            MidiInstrument::Layered(layered) => layered.play_notes(notes),
             MidiInstrument::Empty { name: _ } => Wave::new(),
         }
     }
@@ -46,21 +75,45 @@ impl MidiInstrument {
     pub fn name(&self) -> String {
         match self {
             MidiInstrument::Synthesizer(synth) => synth.name(),
+            MidiInstrument::Fm(fm) => fm.name(),
             MidiInstrument::Drums(drums) => drums.name(),
+            MidiInstrument::DrumMachine(drums) => drums.name(),
+            MidiInstrument::Sampler(sampler) => sampler.name(),
+            MidiInstrument::PluckedString(string) => string.name(),
+            MidiInstrument::Granular(granular) => granular.name(),
+            MidiInstrument::Layered(layered) => layered.name(),
             MidiInstrument::Empty { name } => name.clone(),
         }
     }
 
-    pub fn save_to(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn std::error::Error>> {
+    /// reads a preset of any instrument kind, it is added to a track with `MidiTrack::add_instrument`
+    pub fn load(path: impl AsRef<Path>) -> Result<InstrumentBuilder, Box<dyn std::error::Error>> {
+        InstrumentBuilder::load(path)
+    }
+
+    pub fn extract(&self) -> Result<InstrumentBuilder, Error> {
         match self {
             MidiInstrument::Synthesizer(synth) => {
-                let data = synth.extract();
-                let file = File::create(path)?;
-                ron::ser::to_writer_pretty(file, &data, Default::default())?;
-                Ok(())
+                Ok(InstrumentBuilder::Synthesizer(synth.extract()))
+            }
+            MidiInstrument::Fm(fm) => Ok(InstrumentBuilder::Fm(fm.extract())),
+            MidiInstrument::Drums(drums) => Ok(InstrumentBuilder::Drums(drums.extract()?)),
+            MidiInstrument::DrumMachine(drums) => {
+                Ok(InstrumentBuilder::DrumMachine(drums.extract()))
+            }
+            MidiInstrument::Sampler(sampler) => Ok(InstrumentBuilder::Sampler(sampler.extract()?)),
+            MidiInstrument::PluckedString(string) => {
+                Ok(InstrumentBuilder::PluckedString(string.extract()))
             }
-            MidiInstrument::Drums(_drums) => todo!(),
-            MidiInstrument::Empty { name: _ } => Err(Error::Type)?,
+            MidiInstrument::Granular(granular) => {
+                Ok(InstrumentBuilder::Granular(granular.extract()?))
+            }
+            MidiInstrument::Layered(layered) => Ok(InstrumentBuilder::Layered(layered.extract()?)),
+            MidiInstrument::Empty { name: _ } => Err(Error::Type),
         }
     }
+
+    pub fn save_to(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn std::error::Error>> {
+        self.extract()?.save_to(path)
+    }
 }
@@ -0,0 +1,402 @@
+use std::{collections::HashMap, f32::consts::TAU, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    effects::EffectPanel,
+    globals::{SAMPLE_RATE, TIME_MANAGER},
+    network::{Receiver, Transform},
+    receivers::{DB_VOL_RECEIVER, VOL_RECEIVER},
+    time::ClockTick,
+    tracks::midi::{Note, Pitch},
+    utils::{self, Rng},
+    wave::Wave,
+    Error, RenderWarning,
+};
+
+use super::{
+    drums::{self, Voice, DRUM_MAP},
+    InstrumentBuilder, MidiInstrument,
+};
+
+const PITCH_RECEIVER: Receiver = Receiver::new(55.0, (20.0, 5000.0), Transform::Linear);
+const DECAY_RECEIVER: Receiver = Receiver::new(0.5, (0.01, 5.0), Transform::Linear);
+const TONE_RECEIVER: Receiver = Receiver::new(0.5, (0.0, 1.0), Transform::Linear);
+
+/// the inharmonic ratios of the six square waves of a TR-808 cymbal
+const METAL_RATIOS: [f32; 6] = [1.0, 1.4827, 1.8003, 2.5459, 2.6303, 3.8966];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DrumKind {
+    /// sine sweeping down to `pitch`, `tone` sets the depth of the sweep
+    Kick,
+    /// sine body at `pitch` and noise, `tone` is the amount of noise
+    Snare,
+    /// square waves at inharmonic ratios of `pitch`, high passed, `tone` mixes in noise
+    Metal,
+    /// like the kick with a slower and shallower sweep
+    Tom,
+    /// band passed noise around `pitch` in three short bursts and a tail, `tone` sets the width of the band
+    Clap,
+}
+
+/// one synthesized drum, the receivers are evaluated when the note starts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DrumVoice {
+    pub kind: DrumKind,
+    /// in Hz
+    pub pitch: Receiver,
+    /// the time in seconds it takes to fade by 60 dB
+    pub decay: Receiver,
+    pub tone: Receiver,
+    pub level: Receiver,
+    /// playing a voice cuts off the other voices in its choke group
+    #[serde(default)]
+    pub choke: Option<u32>,
+}
+
+impl DrumVoice {
+    pub fn new(kind: DrumKind, pitch: f32, decay: f32, tone: f32) -> Result<Self, Error> {
+        Ok(Self {
+            kind,
+            pitch: PITCH_RECEIVER.csv(pitch)?,
+            decay: DECAY_RECEIVER.csv(decay)?,
+            tone: TONE_RECEIVER.csv(tone)?,
+            level: DB_VOL_RECEIVER,
+            choke: None,
+        })
+    }
+
+    fn with_choke(mut self, group: u32) -> Self {
+        self.choke = Some(group);
+        self
+    }
+
+    /// renders the voice at full level
+    pub fn render(&self, time: ClockTick, seed: u64) -> Wave {
+        let pitch = self.pitch.get_val(time);
+        let decay = self.decay.get_val(time);
+        let tone = self.tone.get_val(time);
+        let len = utils::seconds_to_samples(decay).max(1);
+        let mut rng = Rng::new(seed);
+
+        let mut out = match self.kind {
+            DrumKind::Kick => swept_sine(pitch, pitch * (1.0 + 8.0 * tone), 0.03, len),
+            DrumKind::Tom => swept_sine(pitch, pitch * (1.0 + tone), 0.1, len),
+            DrumKind::Snare => {
+                let mut noise: Vec<f32> = (0..len).map(|_| rng.noise()).collect();
+                high_pass(&mut noise, 1000.0);
+                let body = swept_sine(pitch, pitch * 1.5, 0.01, len);
+                body.iter()
+                    .zip(noise)
+                    .enumerate()
+                    .map(|(i, (b, n))| b * (1.0 - tone) * fade(i, decay * 0.4) + n * tone)
+                    .collect()
+            }
+            DrumKind::Metal => {
+                let mut phases = [0.0_f32; 6];
+                let mut metal: Vec<f32> = (0..len)
+                    .map(|_| {
+                        let mut sum = 0.0;
+                        for (phase, ratio) in phases.iter_mut().zip(METAL_RATIOS) {
+                            *phase = (*phase + pitch * ratio / SAMPLE_RATE as f32).fract();
+                            sum += if *phase < 0.5 { 1.0 } else { -1.0 };
+                        }
+                        sum / 6.0 * (1.0 - tone) + rng.noise() * tone
+                    })
+                    .collect();
+                high_pass(&mut metal, 7000.0);
+                high_pass(&mut metal, 7000.0);
+                metal
+            }
+            DrumKind::Clap => {
+                let noise: Vec<f32> = (0..len).map(|_| rng.noise()).collect();
+                let mut clap = band_pass(&noise, pitch, 0.5 + 4.5 * (1.0 - tone));
+                let burst = utils::seconds_to_samples(0.01).max(1);
+                for (i, x) in clap.iter_mut().enumerate() {
+                    let bursts = if i < 3 * burst {
+                        fade(i % burst, 0.02)
+                    } else {
+                        0.0
+                    };
+                    let tail = if i >= 2 * burst {
+                        fade(i - 2 * burst, decay)
+                    } else {
+                        0.0
+                    };
+                    *x *= f32::max(bursts, tail);
+                }
+                clap
+            }
+        };
+        if self.kind != DrumKind::Clap {
+            for (i, x) in out.iter_mut().enumerate() {
+                *x *= fade(i, decay);
+            }
+        }
+        let mut wave = Wave::from_vec(out);
+        wave.scale(self.level.get_val(time));
+        wave
+    }
+
+    fn extract(&self) -> Self {
+        Self {
+            kind: self.kind,
+            pitch: self.pitch.extract(),
+            decay: self.decay.extract(),
+            tone: self.tone.extract(),
+            level: self.level.extract(),
+            choke: self.choke,
+        }
+    }
+
+    fn set_id(&mut self, track_id: u8) {
+        self.pitch.set_id(track_id);
+        self.decay.set_id(track_id);
+        self.tone.set_id(track_id);
+        self.level.set_id(track_id);
+    }
+}
+
+/// exponential fade reaching -60 dB after `decay` seconds
+fn fade(sample: usize, decay: f32) -> f32 {
+    (-6.9 * utils::samples_to_seconds(sample) / decay).exp()
+}
+
+/// sine gliding exponentially from `start` to `end` Hz with the time constant `sweep` in seconds
+fn swept_sine(end: f32, start: f32, sweep: f32, len: usize) -> Vec<f32> {
+    let mut phase = 0.0_f32;
+    (0..len)
+        .map(|i| {
+            let t = utils::samples_to_seconds(i);
+            let freq = end + (start - end) * (-t / sweep).exp();
+            phase = (phase + freq / SAMPLE_RATE as f32).fract();
+            (phase * TAU).sin()
+        })
+        .collect()
+}
+
+fn high_pass(signal: &mut [f32], cutoff: f32) {
+    let rc = 1.0 / (TAU * cutoff);
+    let dt = 1.0 / SAMPLE_RATE as f32;
+    let a = rc / (rc + dt);
+    let mut last_in = 0.0;
+    let mut last_out = 0.0;
+    for x in signal {
+        last_out = a * (last_out + *x - last_in);
+        last_in = *x;
+        *x = last_out;
+    }
+}
+
+/// state variable band pass with the quality factor `q`
+fn band_pass(signal: &[f32], center: f32, q: f32) -> Vec<f32> {
+    let f = 2.0
+        * (std::f32::consts::PI * center.min(SAMPLE_RATE as f32 / 6.0) / SAMPLE_RATE as f32).sin();
+    let damping = 1.0 / q;
+    let mut low = 0.0;
+    let mut band = 0.0;
+    signal
+        .iter()
+        .map(|x| {
+            low += f * band;
+            let high = x - low - damping * band;
+            band += f * high;
+            band
+        })
+        .collect()
+}
+
+/// drum kit synthesizing its sounds, so it doesn't depend on any samples
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DrumMachine {
+    pub(crate) name: String,
+    pub(crate) effects: EffectPanel,
+    pub(crate) volume: Receiver,
+    pub(crate) voices: HashMap<Pitch, DrumVoice>,
+}
+
+impl DrumMachine {
+    fn play_voice(&self, voice: &DrumVoice, note: Note, start: usize) -> Wave {
+        let seed = (start as u64) << 7 | note.pitch.get() as u64;
+        let mut wave = voice.render(note.on, seed);
+        wave.scale(note.velocity);
+        wave.scale_by_vec(self.volume.get_vec(note.on, wave.len()));
+        self.effects.apply_to(&mut wave, note.on);
+        wave
+    }
+
+    fn get_voice(&self, pitch: Pitch) -> Option<&DrumVoice> {
+        let voice = self.voices.get(&pitch);
+        if voice.is_none() {
+            crate::warn(RenderWarning::UnmappedNote {
+                instrument: self.name.clone(),
+                pitch: pitch.get(),
+                replacement: None,
+            });
+        }
+        voice
+    }
+
+    pub fn play_note(&self, note: Note) -> Wave {
+        match self.get_voice(note.pitch) {
+            Some(voice) => self.play_voice(voice, note, 0),
+            None => Wave::new(),
+        }
+    }
+
+    pub fn play_notes(&self, notes: &[Note]) -> Wave {
+        let mut notes = notes.to_vec();
+        notes.sort_by_key(|note| note.on);
+        let mut voices = Vec::<Voice>::new();
+        for note in notes {
+            let Some(voice) = self.get_voice(note.pitch) else {
+                continue;
+            };
+            let start = TIME_MANAGER.read().unwrap().tick_to_sample(note.on);
+            if let Some(group) = voice.choke {
+                drums::choke(&mut voices, group, start);
+            }
+            voices.push(Voice {
+                start,
+                choke: voice.choke,
+                wave: self.play_voice(voice, note, start),
+            });
+        }
+        let mut wave = Wave::new();
+        for voice in voices {
+            wave.add(&voice.wave, voice.start);
+        }
+        wave
+    }
+
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    pub fn wrap_midi(self) -> MidiInstrument {
+        MidiInstrument::DrumMachine(Box::new(self))
+    }
+}
+
+impl DrumMachine {
+    pub fn extract(&self) -> DrumMachineBuilder {
+        DrumMachineBuilder {
+            name: self.name.clone(),
+            effects: self.effects.extract(),
+            volume: self.volume.extract(),
+            voices: self
+                .voices
+                .iter()
+                .map(|(pitch, voice)| (*pitch, voice.extract()))
+                .collect(),
+        }
+    }
+
+    pub fn save_to(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn std::error::Error>> {
+        InstrumentBuilder::DrumMachine(self.extract()).save_to(path)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DrumMachineBuilder {
+    pub name: String,
+    pub effects: EffectPanel,
+    pub volume: Receiver,
+    pub voices: HashMap<Pitch, DrumVoice>,
+}
+
+impl DrumMachineBuilder {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            effects: EffectPanel::EmptyLeaf,
+            volume: VOL_RECEIVER,
+            voices: HashMap::new(),
+        }
+    }
+
+    pub fn set_voice(&mut self, pitch: Pitch, voice: DrumVoice) {
+        self.voices.insert(pitch, voice);
+    }
+
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        match InstrumentBuilder::load(path)? {
+            InstrumentBuilder::DrumMachine(drums) => Ok(drums),
+            _ => Err(Error::Type)?,
+        }
+    }
+
+    pub(crate) fn build(self, track_id: u8) -> DrumMachine {
+        let mut effects = self.effects;
+        effects.set_id(track_id);
+
+        let mut volume = self.volume;
+        volume.set_id(track_id);
+
+        let mut voices = self.voices;
+        for voice in voices.values_mut() {
+            voice.set_id(track_id);
+        }
+
+        DrumMachine {
+            name: self.name,
+            effects,
+            volume,
+            voices,
+        }
+    }
+}
+
+/// a voice for every General MIDI percussion key the drum machine can imitate
+impl Default for DrumMachineBuilder {
+    fn default() -> Self {
+        let mut builder = Self::new("drum machine");
+        for pitch in DRUM_MAP.keys().copied() {
+            let voice = match (pitch, drums::drum_kind(pitch)) {
+                (39, _) => DrumVoice::new(DrumKind::Clap, 1200.0, 0.3, 0.5),
+                (42, _) => {
+                    DrumVoice::new(DrumKind::Metal, 205.0, 0.08, 0.3).map(|v| v.with_choke(1))
+                }
+                (44, _) => {
+                    DrumVoice::new(DrumKind::Metal, 205.0, 0.12, 0.3).map(|v| v.with_choke(1))
+                }
+                (46, _) => {
+                    DrumVoice::new(DrumKind::Metal, 205.0, 0.6, 0.3).map(|v| v.with_choke(1))
+                }
+                (_, Some("bass drum")) => DrumVoice::new(DrumKind::Kick, 50.0, 0.6, 0.5),
+                (_, Some("snare")) => DrumVoice::new(DrumKind::Snare, 185.0, 0.25, 0.6),
+                (_, Some("tom")) => {
+                    let freq = 80.0 * utils::fast_pow2((pitch as f32 - 41.0) / 9.0);
+                    DrumVoice::new(DrumKind::Tom, freq, 0.5, 0.4)
+                }
+                (_, Some("ride")) => DrumVoice::new(DrumKind::Metal, 260.0, 1.2, 0.2),
+                (_, Some("cymbal")) => DrumVoice::new(DrumKind::Metal, 230.0, 1.8, 0.5),
+                _ => continue,
+            };
+            builder.set_voice(
+                Pitch::new(pitch).unwrap(),
+                voice.expect("default drum voice out of range"),
+            );
+        }
+        builder.volume = VOL_RECEIVER.sv(3.0);
+        builder
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn voices_fade_out() {
+        let builder = DrumMachineBuilder::default();
+        for pitch in [36, 38, 39, 42, 45, 49] {
+            let wave = builder.voices[&Pitch::new(pitch).unwrap()].render(ClockTick::abs_zero(), 1);
+            assert!(!wave.is_empty());
+            assert!(utils::max_abs_f32(wave.right()) > 0.01);
+            assert!(wave.right().iter().all(|x| x.is_finite()));
+            assert!(wave.right().last().unwrap().abs() < 0.01);
+        }
+    }
+}
@@ -1,6 +1,5 @@
 use std::{
     collections::HashMap,
-    fs::File,
     path::{Path, PathBuf},
 };
 
@@ -14,38 +13,249 @@ use crate::{
     receivers::VOL_RECEIVER,
     resources::SampleId,
     tracks::midi::{Note, Pitch},
+    utils,
     wave::Wave,
-    Error,
+    Error, RenderWarning,
 };
 
+use super::InstrumentBuilder;
+
+/// length of the fade out of a choked voice in seconds
+const CHOKE_FADE: f32 = 0.005;
+
+/// what drums do with a note that has no pad, the note is reported as a render warning in any case
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Unmapped {
+    /// leaves the note out
+    #[default]
+    Skip,
+    /// plays the pad with the closest pitch
+    Nearest,
+    /// plays the closest pad of the same kind of drum by the General MIDI names, skips the note if there is none
+    GeneralMidi,
+}
+
+/// the kinds of drums in the General MIDI percussion map, checked in order
+const DRUM_KINDS: [&str; 15] = [
+    "bass drum",
+    "snare",
+    "hi hat",
+    "tom",
+    "ride",
+    "cymbal",
+    "bongo",
+    "conga",
+    "timbale",
+    "agogo",
+    "whistle",
+    "guiro",
+    "wood block",
+    "cuica",
+    "triangle",
+];
+
+pub(crate) fn drum_kind(pitch: u8) -> Option<&'static str> {
+    let name = DRUM_MAP.get(&pitch)?.to_lowercase().replace('-', " ");
+    DRUM_KINDS.into_iter().find(|kind| name.contains(kind))
+}
+
+/// the pitch of the pad played for an unmapped note
+fn replacement(pitch: u8, mapped: impl Iterator<Item = u8>, unmapped: Unmapped) -> Option<u8> {
+    let distance = |p: &u8| (p.abs_diff(pitch), *p);
+    match unmapped {
+        Unmapped::Skip => None,
+        Unmapped::Nearest => mapped.min_by_key(distance),
+        Unmapped::GeneralMidi => {
+            let kind = drum_kind(pitch)?;
+            mapped
+                .filter(|p| drum_kind(*p) == Some(kind))
+                .min_by_key(distance)
+        }
+    }
+}
+
+/// samples played from a velocity upwards, rotated round robin
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Layer<S> {
+    #[serde(default)]
+    pub lo_vel: f32,
+    pub samples: Vec<S>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Pad<S> {
+    pub layers: Vec<Layer<S>>,
+    /// playing a pad cuts off the voices of all pads in its choke group
+    #[serde(default)]
+    pub choke: Option<u32>,
+    /// in cents
+    #[serde(default)]
+    pub tune: f32,
+    /// in dB
+    #[serde(default)]
+    pub gain: f32,
+    #[serde(default)]
+    pub pan: f32,
+}
+
+impl<S> Pad<S> {
+    pub fn new(sample: S) -> Self {
+        Self {
+            layers: vec![Layer {
+                lo_vel: 0.0,
+                samples: vec![sample],
+            }],
+            choke: None,
+            tune: 0.0,
+            gain: 0.0,
+            pan: 0.0,
+        }
+    }
+
+    pub fn add_layer(&mut self, lo_vel: f32, samples: Vec<S>) {
+        self.layers.push(Layer { lo_vel, samples });
+        self.layers
+            .sort_by(|a, b| a.lo_vel.partial_cmp(&b.lo_vel).unwrap());
+    }
+
+    /// the layer with the highest lower bound not above the velocity
+    fn layer(&self, velocity: f32) -> (usize, &Layer<S>) {
+        let index = self
+            .layers
+            .iter()
+            .rposition(|layer| layer.lo_vel <= velocity)
+            .unwrap_or(0);
+        (index, &self.layers[index])
+    }
+
+    fn map_samples<T, E>(&self, mut f: impl FnMut(&S) -> Result<T, E>) -> Result<Pad<T>, E> {
+        let mut layers = Vec::with_capacity(self.layers.len());
+        for layer in &self.layers {
+            layers.push(Layer {
+                lo_vel: layer.lo_vel,
+                samples: layer.samples.iter().map(&mut f).collect::<Result<_, _>>()?,
+            });
+        }
+        Ok(Pad {
+            layers,
+            choke: self.choke,
+            tune: self.tune,
+            gain: self.gain,
+            pan: self.pan,
+        })
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(from = "SavedDrums")]
 pub struct Drums {
     pub(crate) name: String,
     pub(crate) effects: EffectPanel,
     pub(crate) volume: Receiver,
-    pub(crate) samples: HashMap<Pitch, SampleId>,
+    pub(crate) pads: HashMap<Pitch, Pad<SampleId>>,
+    #[serde(default)]
+    pub(crate) unmapped: Unmapped,
+}
+
+/// drums as they are saved in a song, before pads they had a single sample per pitch
+#[derive(Deserialize)]
+struct SavedDrums {
+    name: String,
+    effects: EffectPanel,
+    volume: Receiver,
+    #[serde(default)]
+    pads: HashMap<Pitch, Pad<SampleId>>,
+    #[serde(default)]
+    samples: HashMap<Pitch, SampleId>,
+    #[serde(default)]
+    unmapped: Unmapped,
+}
+
+impl From<SavedDrums> for Drums {
+    fn from(value: SavedDrums) -> Self {
+        let mut pads = value.pads;
+        for (pitch, id) in value.samples {
+            pads.entry(pitch).or_insert_with(|| Pad::new(id));
+        }
+        Self {
+            name: value.name,
+            effects: value.effects,
+            volume: value.volume,
+            pads,
+            unmapped: value.unmapped,
+        }
+    }
 }
 
 impl Drums {
-    pub fn play_note(&self, note: Note) -> Wave {
-        let mut wave = RESOURCE_MANAGER.read().unwrap().get_sample(
-            *self
-                .samples
-                .get(&note.pitch)
-                .expect("drums played unmapped sample"),
-        );
-        wave.scale(note.velocity);
+    fn play_pad(&self, pad: &Pad<SampleId>, id: SampleId, note: Note) -> Wave {
+        let mut wave = RESOURCE_MANAGER.read().unwrap().get_sample(id);
+        if pad.tune != 0.0 {
+            wave = wave.resample(utils::fast_pow2(pad.tune / 1200.0));
+        }
+        wave.scale(note.velocity * utils::db_to_factor(pad.gain));
+        wave.pan(pad.pan);
         wave.scale_by_vec(self.volume.get_vec(note.on, wave.len()));
         wave
     }
 
+    /// the pad played for the pitch and its pitch, which differs from the note's for unmapped notes
+    fn get_pad(&self, pitch: Pitch) -> Option<(Pitch, &Pad<SampleId>)> {
+        if let Some(pad) = self.pads.get(&pitch) {
+            return Some((pitch, pad));
+        }
+        let replacement = replacement(
+            pitch.get(),
+            self.pads.keys().map(|p| p.get()),
+            self.unmapped,
+        );
+        crate::warn(RenderWarning::UnmappedNote {
+            instrument: self.name.clone(),
+            pitch: pitch.get(),
+            replacement,
+        });
+        let pitch = Pitch::new(replacement?)?;
+        Some((pitch, &self.pads[&pitch]))
+    }
+
+    pub fn play_note(&self, note: Note) -> Wave {
+        match self.get_pad(note.pitch) {
+            Some((_, pad)) => {
+                let (_, layer) = pad.layer(note.velocity);
+                self.play_pad(pad, layer.samples[0], note)
+            }
+            None => Wave::new(),
+        }
+    }
+
+    pub fn set_unmapped(&mut self, unmapped: Unmapped) {
+        self.unmapped = unmapped
+    }
+
     pub fn play_notes(&self, notes: &[Note]) -> Wave {
-        let mut wave = Wave::new();
+        let mut notes = notes.to_vec();
+        notes.sort_by_key(|note| note.on);
+        let mut round_robin = RoundRobin::default();
+        let mut voices = Vec::<Voice>::new();
         for note in notes {
-            wave.add(
-                &self.play_note(*note),
-                TIME_MANAGER.read().unwrap().tick_to_sample(note.on),
-            )
+            let Some((pitch, pad)) = self.get_pad(note.pitch) else {
+                continue;
+            };
+            let id = *round_robin.next(pitch, pad, note.velocity);
+
+            let start = TIME_MANAGER.read().unwrap().tick_to_sample(note.on);
+            if let Some(group) = pad.choke {
+                choke(&mut voices, group, start);
+            }
+            voices.push(Voice {
+                start,
+                choke: pad.choke,
+                wave: self.play_pad(pad, id, note),
+            });
+        }
+        let mut wave = Wave::new();
+        for voice in voices {
+            wave.add(&voice.wave, voice.start);
         }
         wave
     }
@@ -55,24 +265,66 @@ impl Drums {
     }
 }
 
+/// how many notes every layer of every pad has played
+#[derive(Default)]
+struct RoundRobin(HashMap<(Pitch, usize), usize>);
+
+impl RoundRobin {
+    /// the sample of the layer for the velocity whose turn it is
+    fn next<'a, S>(&mut self, pitch: Pitch, pad: &'a Pad<S>, velocity: f32) -> &'a S {
+        let (index, layer) = pad.layer(velocity);
+        let counter = self.0.entry((pitch, index)).or_default();
+        let sample = &layer.samples[*counter % layer.samples.len()];
+        *counter += 1;
+        sample
+    }
+}
+
+pub(crate) struct Voice {
+    pub(crate) start: usize,
+    pub(crate) choke: Option<u32>,
+    pub(crate) wave: Wave,
+}
+
+/// fades out the voices of the choke group which are still sounding at `start`
+pub(crate) fn choke(voices: &mut [Voice], group: u32, start: usize) {
+    let fade = utils::seconds_to_samples(CHOKE_FADE).max(1);
+    for voice in voices {
+        if voice.choke != Some(group) || voice.start + voice.wave.len() <= start {
+            continue;
+        }
+        let keep = start.saturating_sub(voice.start);
+        let len = voice.wave.len().min(keep + fade);
+        voice.wave.resize(len, 0.0);
+        voice.wave.scale_by_vec(
+            (0..len)
+                .map(|i| 1.0 - i.saturating_sub(keep) as f32 / fade as f32)
+                .collect(),
+        );
+    }
+}
+
 impl Drums {
     pub fn extract(&self) -> Result<DrumsBuilder, Error> {
-        let mut samples = HashMap::new();
-        for (pitch, id) in &self.samples {
-            samples.insert(*pitch, RESOURCE_MANAGER.read().unwrap().get_path(*id)?);
+        let mut pads = HashMap::new();
+        for (pitch, pad) in &self.pads {
+            pads.insert(
+                *pitch,
+                pad.map_samples(|id| RESOURCE_MANAGER.read().unwrap().get_path(*id))?,
+            );
         }
         Ok(DrumsBuilder {
             name: self.name.clone(),
             effects: self.effects.extract(),
             volume: self.volume.extract(),
-            samples,
+            samples: HashMap::new(),
+            pads,
+            unmapped: self.unmapped,
         })
     }
 
     pub fn save_to(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn std::error::Error>> {
-        let file = File::create(path)?;
-        ron::ser::to_writer_pretty(file, &self.extract()?, Default::default())?;
-        Ok(())
+        InstrumentBuilder::Drums(self.extract()?).save_to(path)
     }
 }
 
@@ -81,17 +333,66 @@ pub struct DrumsBuilder {
     pub(crate) name: String,
     pub(crate) effects: EffectPanel,
     pub(crate) volume: Receiver,
+    /// pads with a single sample
+    #[serde(default)]
     pub(crate) samples: HashMap<Pitch, PathBuf>,
+    #[serde(default)]
+    pub(crate) pads: HashMap<Pitch, Pad<PathBuf>>,
+    #[serde(default)]
+    pub(crate) unmapped: Unmapped,
 }
 
 impl DrumsBuilder {
     pub fn from_path(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
-        let file = File::open(path)?;
-        let drums = ron::de::from_reader(file)?;
-        Ok(drums)
+        match InstrumentBuilder::load(path)? {
+            InstrumentBuilder::Drums(drums) => Ok(drums),
+            _ => Err(Error::Type)?,
+        }
+    }
+
+    pub fn add_pad(&mut self, pitch: Pitch, pad: Pad<PathBuf>) {
+        self.samples.remove(&pitch);
+        self.pads.insert(pitch, pad);
+    }
+
+    pub fn set_unmapped(&mut self, unmapped: Unmapped) {
+        self.unmapped = unmapped
+    }
+
+    pub(crate) fn build(self, track_id: u8) -> Result<Drums, Box<dyn std::error::Error>> {
+        let mut effects = self.effects;
+        effects.set_id(track_id);
+
+        let mut volume = self.volume;
+        volume.set_id(track_id);
+
+        let mut pads = HashMap::new();
+        let single = self
+            .samples
+            .into_iter()
+            .map(|(pitch, path)| (pitch, Pad::new(path)));
+        for (pitch, mut pad) in single.chain(self.pads) {
+            pad.layers.retain(|layer| !layer.samples.is_empty());
+            if pad.layers.is_empty() {
+                continue;
+            }
+            pad.layers
+                .sort_by(|a, b| a.lo_vel.partial_cmp(&b.lo_vel).unwrap());
+            let pad = pad.map_samples(|path| RESOURCE_MANAGER.write().unwrap().add_sample(path))?;
+            pads.insert(pitch, pad);
+        }
+
+        Ok(Drums {
+            name: self.name,
+            effects,
+            volume,
+            pads,
+            unmapped: self.unmapped,
+        })
     }
 }
 
+/// expects the samples in `samples/`, `DrumMachineBuilder::default` renders without any files
 impl Default for DrumsBuilder {
     fn default() -> Self {
         Self {
@@ -107,12 +408,13 @@ impl Default for DrumsBuilder {
                     PathBuf::from("samples/hihat_half.wav"),
                 ),
             ]),
+            pads: HashMap::new(),
+            unmapped: Unmapped::default(),
         }
     }
 }
 
-#[allow(dead_code)]
-static DRUM_MAP: Lazy<HashMap<u8, &str>> = Lazy::new(|| {
+pub(crate) static DRUM_MAP: Lazy<HashMap<u8, &str>> = Lazy::new(|| {
     HashMap::from([
         (35, "Acoustic Bass Drum"),
         (36, "Bass Drum 1"),
@@ -163,3 +465,94 @@ static DRUM_MAP: Lazy<HashMap<u8, &str>> = Lazy::new(|| {
         (81, "Open Triangle"),
     ])
 });
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn choke_fades_out_sounding_voices() {
+        let mut voices = vec![
+            Voice {
+                start: 0,
+                choke: Some(1),
+                wave: Wave::ones(10_000),
+            },
+            Voice {
+                start: 0,
+                choke: None,
+                wave: Wave::ones(10_000),
+            },
+        ];
+        choke(&mut voices, 1, 1000);
+        let fade = utils::seconds_to_samples(CHOKE_FADE);
+        assert_eq!(voices[0].wave.len(), 1000 + fade);
+        assert_eq!(voices[0].wave.right()[999], 1.0);
+        assert!(voices[0].wave.right()[1000 + fade - 1] < 0.01);
+        assert_eq!(voices[1].wave.len(), 10_000);
+    }
+
+    #[test]
+    fn songs_keep_their_old_drums() {
+        let text = format!(
+            "(name: \"kit\", effects: {}, volume: {}, samples: {{ {}: (3) }})",
+            ron::to_string(&EffectPanel::EmptyLeaf).unwrap(),
+            ron::to_string(&VOL_RECEIVER).unwrap(),
+            ron::to_string(&Pitch::new(36).unwrap()).unwrap(),
+        );
+        let drums: Drums = ron::from_str(&text).unwrap();
+        let pad = &drums.pads[&Pitch::new(36).unwrap()];
+        assert_eq!(pad.layers.len(), 1);
+        assert_eq!(pad.layers[0].samples, vec![ron::from_str("(3)").unwrap()]);
+        assert_eq!(drums.unmapped, Unmapped::Skip);
+    }
+
+    #[test]
+    fn velocity_layers() {
+        let mut pad = Pad::new("soft");
+        pad.add_layer(0.8, vec!["hard"]);
+        pad.add_layer(0.4, vec!["medium"]);
+        let sample = |velocity| pad.layer(velocity).1.samples[0];
+        assert_eq!(sample(0.0), "soft");
+        assert_eq!(sample(0.39), "soft");
+        assert_eq!(sample(0.4), "medium");
+        assert_eq!(sample(0.79), "medium");
+        assert_eq!(sample(1.0), "hard");
+    }
+
+    #[test]
+    fn round_robin_per_layer() {
+        let mut pad = Pad::new("soft");
+        pad.add_layer(0.5, vec!["hard 1", "hard 2", "hard 3"]);
+        let kick = Pitch::new(36).unwrap();
+        let snare = Pitch::new(38).unwrap();
+        let mut round_robin = RoundRobin::default();
+        let played: Vec<&str> = [
+            (kick, 1.0),
+            (kick, 1.0),
+            (kick, 0.2),
+            (snare, 1.0),
+            (kick, 1.0),
+            (kick, 1.0),
+        ]
+        .into_iter()
+        .map(|(pitch, velocity)| *round_robin.next(pitch, &pad, velocity))
+        .collect();
+        assert_eq!(
+            played,
+            vec!["hard 1", "hard 2", "soft", "hard 1", "hard 3", "hard 1"]
+        );
+    }
+
+    #[test]
+    fn unmapped_replacements() {
+        let mapped = [36, 38, 42, 46];
+        let find = |pitch, unmapped| replacement(pitch, mapped.into_iter(), unmapped);
+        assert_eq!(find(40, Unmapped::Skip), None);
+        assert_eq!(find(40, Unmapped::Nearest), Some(38));
+        assert_eq!(find(44, Unmapped::Nearest), Some(42));
+        assert_eq!(find(35, Unmapped::GeneralMidi), Some(36));
+        assert_eq!(find(44, Unmapped::GeneralMidi), Some(42));
+        assert_eq!(find(49, Unmapped::GeneralMidi), None);
+    }
+}
@@ -0,0 +1,428 @@
+use std::{
+    f32::consts::{PI, TAU},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    effects::EffectPanel,
+    gens::Envelope,
+    globals::{SAMPLE_RATE, TIME_MANAGER},
+    network::{Receiver, Transform},
+    receivers::VOL_RECEIVER,
+    time::ClockTick,
+    tracks::midi::{Note, NoteExpression},
+    utils,
+    wave::Wave,
+    Error,
+};
+
+use super::{InstrumentBuilder, MidiInstrument};
+
+pub const MAX_OPERATORS: usize = 6;
+
+/// phase deviation in radians caused by a modulator at full level
+const MODULATION_INDEX: f32 = 8.0;
+
+const PITCH_RECEIVER: Receiver = Receiver::new(0.0, (-4800.0, 4800.0), Transform::Linear);
+const RATIO_RECEIVER: Receiver = Receiver::new(1.0, (0.0, 32.0), Transform::Linear);
+const DETUNE_RECEIVER: Receiver = Receiver::new(0.0, (-100.0, 100.0), Transform::Linear);
+const LEVEL_RECEIVER: Receiver = Receiver::new(1.0, (0.0, 1.0), Transform::Linear);
+const FEEDBACK_RECEIVER: Receiver = Receiver::new(0.0, (0.0, 1.0), Transform::Linear);
+
+/// which operators modulate which, operators can only be modulated by operators with a higher index
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Algorithm {
+    /// every operator modulates the one before it, only the first is heard
+    Stack,
+    /// the operators are split into two stacks, the first of each is heard
+    TwoStacks,
+    /// all other operators modulate the first one
+    Branch,
+    /// every operator is heard, no modulation
+    Parallel,
+    /// `modulators[i]` modulate operator `i`
+    Custom {
+        modulators: Vec<Vec<usize>>,
+        carriers: Vec<usize>,
+    },
+}
+
+impl Algorithm {
+    /// the modulators of each operator and the operators that are heard
+    fn routing(&self, operators: usize) -> Result<(Vec<Vec<usize>>, Vec<usize>), Error> {
+        if operators == 0 || operators > MAX_OPERATORS {
+            return Err(Error::Value);
+        }
+        let half = operators.div_ceil(2);
+        let (modulators, carriers) = match self {
+            Algorithm::Stack => (
+                (0..operators)
+                    .map(|i| (i + 1..operators).take(1).collect())
+                    .collect(),
+                vec![0],
+            ),
+            Algorithm::TwoStacks => (
+                (0..operators)
+                    .map(|i| match i + 1 {
+                        next if next == half || next == operators => vec![],
+                        next => vec![next],
+                    })
+                    .collect(),
+                if operators > 1 {
+                    vec![0, half]
+                } else {
+                    vec![0]
+                },
+            ),
+            Algorithm::Branch => (
+                (0..operators)
+                    .map(|i| {
+                        if i == 0 {
+                            (1..operators).collect()
+                        } else {
+                            vec![]
+                        }
+                    })
+                    .collect(),
+                vec![0],
+            ),
+            Algorithm::Parallel => (vec![vec![]; operators], (0..operators).collect()),
+            Algorithm::Custom {
+                modulators,
+                carriers,
+            } => {
+                let valid_modulators = modulators.len() == operators
+                    && modulators
+                        .iter()
+                        .enumerate()
+                        .all(|(i, mods)| mods.iter().all(|m| *m > i && *m < operators));
+                if !valid_modulators
+                    || carriers.is_empty()
+                    || carriers.iter().any(|c| *c >= operators)
+                {
+                    return Err(Error::Value);
+                }
+                (modulators.clone(), carriers.clone())
+            }
+        };
+        Ok((modulators, carriers))
+    }
+}
+
+/// sine oscillator whose phase is modulated by other operators and its own output
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Operator {
+    /// frequency as a multiple of the note's frequency
+    pub ratio: Receiver,
+    /// in cents
+    pub detune: Receiver,
+    /// output level, for modulators this sets the modulation depth
+    pub level: Receiver,
+    pub feedback: Receiver,
+    pub envelope: Envelope,
+}
+
+impl Operator {
+    pub fn new(ratio: f32, level: f32) -> Result<Self, Error> {
+        if ratio <= 0.0 {
+            return Err(Error::Value);
+        }
+        Ok(Self {
+            ratio: RATIO_RECEIVER.csv(ratio)?,
+            detune: DETUNE_RECEIVER,
+            level: LEVEL_RECEIVER.csv(level)?,
+            feedback: FEEDBACK_RECEIVER,
+            envelope: Envelope::default(),
+        })
+    }
+
+    fn extract(&self) -> Self {
+        Self {
+            ratio: self.ratio.extract(),
+            detune: self.detune.extract(),
+            level: self.level.extract(),
+            feedback: self.feedback.extract(),
+            envelope: self.envelope.extract(),
+        }
+    }
+
+    fn set_id(&mut self, track_id: u8) {
+        self.ratio.set_id(track_id);
+        self.detune.set_id(track_id);
+        self.level.set_id(track_id);
+        self.feedback.set_id(track_id);
+        self.envelope.set_track_id(track_id);
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FmSynth {
+    pub(crate) name: String,
+    pub(crate) effects: EffectPanel,
+    pub(crate) volume: Receiver,
+    pub(crate) pitch: Receiver,
+    pub(crate) algorithm: Algorithm,
+    pub(crate) operators: Vec<Operator>,
+}
+
+impl FmSynth {
+    fn play_freq(
+        &self,
+        note_on: ClockTick,
+        note_off: ClockTick,
+        freq: f32,
+        velocity: f32,
+        expression: Option<&NoteExpression>,
+    ) -> Wave {
+        let (modulators, carriers) = self
+            .algorithm
+            .routing(self.operators.len())
+            .expect("fm synth was built with an invalid algorithm");
+        let sus_samples = TIME_MANAGER
+            .read()
+            .unwrap()
+            .duration_to_samples(note_on, note_off);
+
+        let envelopes: Vec<Vec<f32>> = self
+            .operators
+            .iter()
+            .map(|op| op.envelope.get_envelope(note_on, sus_samples))
+            .collect();
+        let len = carriers
+            .iter()
+            .map(|c| envelopes[*c].len())
+            .max()
+            .unwrap_or(0);
+        let mut cent_offsets = self.pitch.get_vec(note_on, len);
+        if let Some(expression) = expression {
+            utils::add_elementwise(&mut cent_offsets, &expression.bend_cents(note_on, len));
+        }
+
+        // the receivers follow their automation and modulation while the note plays
+        let steps: Vec<Vec<f32>> = self
+            .operators
+            .iter()
+            .map(|op| {
+                let ratio = op.ratio.get_vec(note_on, len);
+                let detune = op.detune.get_vec(note_on, len);
+                (0..len)
+                    .map(|i| {
+                        freq * ratio[i] * utils::fast_pow2(detune[i] / 1200.0) / SAMPLE_RATE as f32
+                    })
+                    .collect()
+            })
+            .collect();
+        let levels: Vec<Vec<f32>> = self
+            .operators
+            .iter()
+            .map(|op| op.level.get_vec(note_on, len))
+            .collect();
+        let feedback: Vec<Vec<f32>> = self
+            .operators
+            .iter()
+            .map(|op| op.feedback.get_vec(note_on, len))
+            .collect();
+
+        let n = self.operators.len();
+        let mut phases = vec![0.0_f32; n];
+        let mut outs = vec![0.0_f32; n];
+        let mut last = vec![0.0_f32; n];
+        let mut out = Vec::with_capacity(len);
+        for (i, cents) in cent_offsets.iter().enumerate() {
+            let bend = utils::fast_pow2(cents / 1200.0);
+            // modulators have higher indices, so they are computed first
+            for k in (0..n).rev() {
+                let modulation: f32 = modulators[k].iter().map(|m| outs[*m]).sum();
+                let self_modulation = feedback[k][i] * (outs[k] + last[k]) * 0.5 * PI;
+                last[k] = outs[k];
+                let envelope = envelopes[k].get(i).copied().unwrap_or(0.0);
+                outs[k] = (TAU * phases[k] + modulation * MODULATION_INDEX + self_modulation).sin()
+                    * envelope
+                    * levels[k][i];
+                phases[k] = (phases[k] + steps[k][i] * bend).fract();
+            }
+            out.push(carriers.iter().map(|c| outs[*c]).sum::<f32>() / carriers.len() as f32);
+        }
+
+        let mut wave = Wave::from_vec(out);
+        wave.scale(velocity);
+        wave.scale_by_vec(self.volume.get_vec(note_on, wave.len()));
+        self.effects.apply_to(&mut wave, note_on);
+        wave
+    }
+
+    pub fn play_note(&self, note: Note) -> Wave {
+        match note.pitch.get_freq() {
+            Some(freq) => self.play_freq(
+                note.on,
+                note.off,
+                freq,
+                note.velocity,
+                note.expression.as_deref(),
+            ),
+            None => Wave::new(),
+        }
+    }
+
+    pub fn play_notes(&self, notes: &[Note]) -> Wave {
+        let mut wave = Wave::new();
+        for note in notes {
+            wave.add(
+                &self.play_note(note.clone()),
+                TIME_MANAGER.read().unwrap().tick_to_sample(note.on),
+            );
+        }
+        wave
+    }
+
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    pub fn wrap_midi(self) -> MidiInstrument {
+        MidiInstrument::Fm(Box::new(self))
+    }
+}
+
+impl FmSynth {
+    pub fn extract(&self) -> FmSynthBuilder {
+        FmSynthBuilder {
+            name: self.name.clone(),
+            effects: self.effects.extract(),
+            volume: self.volume.extract(),
+            pitch: self.pitch.extract(),
+            algorithm: self.algorithm.clone(),
+            operators: self.operators.iter().map(Operator::extract).collect(),
+        }
+    }
+
+    pub fn save_to(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn std::error::Error>> {
+        InstrumentBuilder::Fm(self.extract()).save_to(path)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FmSynthBuilder {
+    pub name: String,
+    pub effects: EffectPanel,
+    pub volume: Receiver,
+    pub pitch: Receiver,
+    pub algorithm: Algorithm,
+    pub operators: Vec<Operator>,
+}
+
+impl FmSynthBuilder {
+    pub fn new(name: &str, algorithm: Algorithm, operators: Vec<Operator>) -> Result<Self, Error> {
+        algorithm.routing(operators.len())?;
+        Ok(Self {
+            name: name.to_string(),
+            effects: EffectPanel::EmptyLeaf,
+            volume: VOL_RECEIVER,
+            pitch: PITCH_RECEIVER,
+            algorithm,
+            operators,
+        })
+    }
+
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        match InstrumentBuilder::load(path)? {
+            InstrumentBuilder::Fm(fm) => Ok(fm),
+            _ => Err(Error::Type)?,
+        }
+    }
+
+    pub(crate) fn build(self, track_id: u8) -> Result<FmSynth, Error> {
+        self.algorithm.routing(self.operators.len())?;
+
+        let mut effects = self.effects;
+        effects.set_id(track_id);
+
+        let mut volume = self.volume;
+        volume.set_id(track_id);
+
+        let mut pitch = self.pitch;
+        pitch.set_id(track_id);
+
+        let mut operators = self.operators;
+        for op in &mut operators {
+            op.set_id(track_id);
+        }
+
+        Ok(FmSynth {
+            name: self.name,
+            effects,
+            volume,
+            pitch,
+            algorithm: self.algorithm,
+            operators,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{tracks::midi::note, utils::XYPairs};
+
+    #[test]
+    fn algorithm_routing() {
+        assert_eq!(
+            Algorithm::Stack.routing(4).unwrap(),
+            (vec![vec![1], vec![2], vec![3], vec![]], vec![0])
+        );
+        assert_eq!(
+            Algorithm::TwoStacks.routing(6).unwrap(),
+            (
+                vec![vec![1], vec![2], vec![], vec![4], vec![5], vec![]],
+                vec![0, 3]
+            )
+        );
+        assert_eq!(
+            Algorithm::Branch.routing(3).unwrap(),
+            (vec![vec![1, 2], vec![], vec![]], vec![0])
+        );
+        let cycle = Algorithm::Custom {
+            modulators: vec![vec![1], vec![0]],
+            carriers: vec![0],
+        };
+        assert!(cycle.routing(2).is_err());
+        assert!(Algorithm::Parallel.routing(MAX_OPERATORS + 1).is_err());
+    }
+
+    #[test]
+    fn epiano_preset() {
+        let fm = FmSynthBuilder::from_path("instr/fm_epiano.ron").unwrap();
+        assert_eq!(fm.operators[1].ratio.get_val(ClockTick::abs_zero()), 14.0);
+        let fm = fm.build(0).unwrap();
+        assert_eq!(fm.operators.len(), 4);
+    }
+
+    #[test]
+    fn notes_follow_their_bend() {
+        let fm = FmSynthBuilder::new(
+            "sine",
+            Algorithm::Parallel,
+            vec![Operator::new(1.0, 1.0).unwrap()],
+        )
+        .unwrap()
+        .build(0)
+        .unwrap();
+        let mut bent = note(60, 0, 1000, 1.0);
+        bent.expression = Some(Box::new(NoteExpression {
+            bend: XYPairs::from_point(ClockTick::new(0), 1.0),
+            bend_range: 12.0,
+            ..Default::default()
+        }));
+        let crossings = |wave: Wave| {
+            wave.right()
+                .windows(2)
+                .filter(|w| (w[0] < 0.0) != (w[1] < 0.0))
+                .count() as f32
+        };
+        let plain = crossings(fm.play_note(note(60, 0, 1000, 1.0)));
+        let octave_up = crossings(fm.play_note(bent));
+        assert!((octave_up / plain - 2.0).abs() < 0.05);
+    }
+}
@@ -0,0 +1,294 @@
+use std::{f32::consts::PI, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    effects::EffectPanel,
+    gens::Envelope,
+    globals::{RESOURCE_MANAGER, SAMPLE_RATE, TIME_MANAGER},
+    network::{Receiver, Transform},
+    receivers::VOL_RECEIVER,
+    resources::{SampleId, SampleSource},
+    tracks::midi::Note,
+    utils::{self, Rng},
+    wave::Wave,
+    Error,
+};
+
+use super::{InstrumentBuilder, MidiInstrument};
+
+/// in seconds
+const GRAIN_SIZE_RECEIVER: Receiver = Receiver::new(0.08, (0.005, 1.0), Transform::Linear);
+/// grains per second
+const DENSITY_RECEIVER: Receiver = Receiver::new(30.0, (1.0, 500.0), Transform::Linear);
+const POSITION_RECEIVER: Receiver = Receiver::new(0.0, (0.0, 1.0), Transform::Linear);
+const JITTER_RECEIVER: Receiver = Receiver::new(0.05, (0.0, 1.0), Transform::Linear);
+/// in cents
+const PITCH_RECEIVER: Receiver = Receiver::new(0.0, (-4800.0, 4800.0), Transform::Linear);
+const WINDOW_RECEIVER: Receiver = Receiver::new(1.0, (0.0, 1.0), Transform::Linear);
+
+/// plays overlapping grains read from a sample, the receivers are evaluated at the start of every grain
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Granular {
+    pub(crate) name: String,
+    pub(crate) effects: EffectPanel,
+    pub(crate) volume: Receiver,
+    pub(crate) envelope: Envelope,
+    pub(crate) sample: SampleId,
+    pub(crate) root: u8,
+    pub(crate) grain_size: Receiver,
+    pub(crate) density: Receiver,
+    pub(crate) position: Receiver,
+    pub(crate) jitter: Receiver,
+    pub(crate) pitch: Receiver,
+    pub(crate) window: Receiver,
+}
+
+impl Granular {
+    pub fn play_note(&self, note: Note) -> Wave {
+        let time_manager = TIME_MANAGER.read().unwrap();
+        let sus_samples = time_manager.duration_to_samples(note.on, note.off);
+        let seed = (time_manager.tick_to_sample(note.on) as u64) << 7 | note.pitch.get() as u64;
+        drop(time_manager);
+
+        let Some(freq) = note.pitch.get_freq() else {
+            return Wave::new();
+        };
+        let envelope = self.envelope.get_envelope(note.on, sus_samples);
+        let len = envelope.len();
+        let sample = RESOURCE_MANAGER.read().unwrap().get_sample(self.sample);
+        if sample.is_empty() {
+            return Wave::new();
+        }
+
+        let grain_size = self.grain_size.get_vec(note.on, len);
+        let density = self.density.get_vec(note.on, len);
+        let position = self.position.get_vec(note.on, len);
+        let jitter = self.jitter.get_vec(note.on, len);
+        let pitch = self.pitch.get_vec(note.on, len);
+        let window = self.window.get_vec(note.on, len);
+        let bend = note.bend_factors(len);
+        let transpose = freq / utils::pitch_to_freq(self.root);
+
+        let mut rng = Rng::new(seed);
+        let mut wave = Wave::zeros(len);
+        let mut start = 0;
+        while start < len {
+            let grain_len = utils::seconds_to_samples(grain_size[start]).max(2);
+            let interval = (SAMPLE_RATE as f32 / density[start]).max(1.0);
+            let pos = (position[start] + jitter[start] * rng.noise()).clamp(0.0, 1.0)
+                * sample.len() as f32;
+            let ratio = transpose * bend[start] * utils::fast_pow2(pitch[start] / 1200.0);
+
+            let mut grain = grain(&sample, pos, ratio, grain_len, window[start]);
+            // keeps the loudness steady when the grains overlap
+            grain.scale(1.0 / (grain_len as f32 / interval).max(1.0).sqrt());
+            grain.resize(grain.len().min(len - start), 0.0);
+            wave.add(&grain, start);
+
+            start += interval as usize;
+        }
+
+        wave.scale_by_vec(envelope);
+        wave.scale(note.velocity);
+        wave.scale_by_vec(self.volume.get_vec(note.on, wave.len()));
+        self.effects.apply_to(&mut wave, note.on);
+        wave
+    }
+
+    pub fn play_notes(&self, notes: &[Note]) -> Wave {
+        let mut wave = Wave::new();
+        for note in notes {
+            wave.add(
+                &self.play_note(note.clone()),
+                TIME_MANAGER.read().unwrap().tick_to_sample(note.on),
+            );
+        }
+        wave
+    }
+
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    pub fn wrap_midi(self) -> MidiInstrument {
+        MidiInstrument::Granular(Box::new(self))
+    }
+}
+
+impl Granular {
+    pub fn extract(&self) -> Result<GranularBuilder, Error> {
+        Ok(GranularBuilder {
+            name: self.name.clone(),
+            effects: self.effects.extract(),
+            volume: self.volume.extract(),
+            envelope: self.envelope.extract(),
+            sample: RESOURCE_MANAGER.read().unwrap().get_source(self.sample)?,
+            root: self.root,
+            grain_size: self.grain_size.extract(),
+            density: self.density.extract(),
+            position: self.position.extract(),
+            jitter: self.jitter.extract(),
+            pitch: self.pitch.extract(),
+            window: self.window.extract(),
+        })
+    }
+
+    pub fn save_to(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn std::error::Error>> {
+        InstrumentBuilder::Granular(self.extract()?).save_to(path)
+    }
+}
+
+/// `len` samples read from `pos` onwards, stepping `ratio` samples per output sample
+fn grain(sample: &Wave, pos: f32, ratio: f32, len: usize, shape: f32) -> Wave {
+    let mut right = Vec::with_capacity(len);
+    let mut left = Vec::with_capacity(len);
+    for i in 0..len {
+        let pos = pos + i as f32 * ratio;
+        let index = pos as usize;
+        if index + 1 >= sample.len() {
+            break;
+        }
+        let frac = pos - index as f32;
+        let window = window(i, len, shape);
+        right.push(
+            (sample.right()[index] * (1.0 - frac) + sample.right()[index + 1] * frac) * window,
+        );
+        left.push((sample.left()[index] * (1.0 - frac) + sample.left()[index + 1] * frac) * window);
+    }
+    Wave::from_vecs(right, left)
+}
+
+/// tukey window, a `shape` of 1.0 is a hann window and 0.0 a rectangle
+fn window(i: usize, len: usize, shape: f32) -> f32 {
+    let x = i as f32 / (len - 1) as f32;
+    let taper = shape.clamp(0.0, 1.0) / 2.0;
+    let edge = x.min(1.0 - x);
+    if edge >= taper {
+        1.0
+    } else {
+        0.5 * (1.0 - (PI * edge / taper).cos())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GranularBuilder {
+    pub name: String,
+    pub effects: EffectPanel,
+    pub volume: Receiver,
+    pub envelope: Envelope,
+    pub sample: SampleSource,
+    /// the key at which the grains play unchanged
+    pub root: u8,
+    pub grain_size: Receiver,
+    pub density: Receiver,
+    /// where the grains start, from the start to the end of the sample
+    pub position: Receiver,
+    /// random offset of the grains' start, as a share of the sample's length
+    pub jitter: Receiver,
+    pub pitch: Receiver,
+    pub window: Receiver,
+}
+
+impl GranularBuilder {
+    pub fn new(name: &str, sample: SampleSource, root: u8) -> Self {
+        Self {
+            name: name.to_string(),
+            effects: EffectPanel::EmptyLeaf,
+            volume: VOL_RECEIVER,
+            envelope: Envelope::new_adsr(0.05, 0.0, 1.0, 0.5).unwrap(),
+            sample,
+            root,
+            grain_size: GRAIN_SIZE_RECEIVER,
+            density: DENSITY_RECEIVER,
+            position: POSITION_RECEIVER,
+            jitter: JITTER_RECEIVER,
+            pitch: PITCH_RECEIVER,
+            window: WINDOW_RECEIVER,
+        }
+    }
+
+    /// `size` in seconds and `density` in grains per second
+    pub fn set_grains(&mut self, size: f32, density: f32) -> Result<(), Error> {
+        self.grain_size = GRAIN_SIZE_RECEIVER.csv(size)?;
+        self.density = DENSITY_RECEIVER.csv(density)?;
+        Ok(())
+    }
+
+    pub fn set_position(&mut self, position: f32, jitter: f32) -> Result<(), Error> {
+        self.position = POSITION_RECEIVER.csv(position)?;
+        self.jitter = JITTER_RECEIVER.csv(jitter)?;
+        Ok(())
+    }
+
+    pub fn set_window(&mut self, shape: f32) -> Result<(), Error> {
+        self.window = WINDOW_RECEIVER.csv(shape)?;
+        Ok(())
+    }
+
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        match InstrumentBuilder::load(path)? {
+            InstrumentBuilder::Granular(granular) => Ok(granular),
+            _ => Err(Error::Type)?,
+        }
+    }
+
+    pub(crate) fn build(self, track_id: u8) -> Result<Granular, Box<dyn std::error::Error>> {
+        let sample = RESOURCE_MANAGER.write().unwrap().add_source(&self.sample)?;
+
+        let mut effects = self.effects;
+        effects.set_id(track_id);
+
+        let mut envelope = self.envelope;
+        envelope.set_track_id(track_id);
+
+        let mut receivers = [
+            self.volume,
+            self.grain_size,
+            self.density,
+            self.position,
+            self.jitter,
+            self.pitch,
+            self.window,
+        ];
+        for receiver in &mut receivers {
+            receiver.set_id(track_id);
+        }
+        let [volume, grain_size, density, position, jitter, pitch, window] = receivers;
+
+        Ok(Granular {
+            name: self.name,
+            effects,
+            volume,
+            envelope,
+            sample,
+            root: self.root,
+            grain_size,
+            density,
+            position,
+            jitter,
+            pitch,
+            window,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn grains_are_windowed() {
+        assert_eq!(window(0, 101, 1.0), 0.0);
+        assert!((window(50, 101, 1.0) - 1.0).abs() < 1e-6);
+        assert_eq!(window(10, 101, 0.0), 1.0);
+        assert!(window(5, 101, 0.5) < 1.0 && window(30, 101, 0.5) == 1.0);
+
+        let sample = Wave::from_vec((0..1000).map(|i| i as f32).collect());
+        let octave_up = grain(&sample, 100.0, 2.0, 50, 0.0);
+        assert_eq!(octave_up.len(), 50);
+        assert_eq!(octave_up.right()[10], 120.0);
+        // stops at the end of the sample
+        assert_eq!(grain(&sample, 990.0, 1.0, 50, 0.0).len(), 9);
+    }
+}
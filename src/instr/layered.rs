@@ -0,0 +1,271 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    tracks::midi::{Note, Pitch},
+    utils,
+    wave::Wave,
+    Error,
+};
+
+use super::{InstrumentBuilder, MidiInstrument};
+
+/// an instrument playing the notes inside its key and velocity range
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Layer<I> {
+    pub instrument: I,
+    pub lo_key: u8,
+    pub hi_key: u8,
+    pub lo_vel: f32,
+    pub hi_vel: f32,
+    /// keys at both ends of the key range over which the layer fades in
+    #[serde(default)]
+    pub key_fade: u8,
+    /// velocity at both ends of the velocity range over which the layer fades in
+    #[serde(default)]
+    pub vel_fade: f32,
+    /// in dB
+    #[serde(default)]
+    pub gain: f32,
+    /// in semitones
+    #[serde(default)]
+    pub transpose: i8,
+}
+
+impl<I> Layer<I> {
+    pub fn new(instrument: I, lo_key: u8, hi_key: u8) -> Self {
+        Self {
+            instrument,
+            lo_key,
+            hi_key,
+            lo_vel: 0.0,
+            hi_vel: 1.0,
+            key_fade: 0,
+            vel_fade: 0.0,
+            gain: 0.0,
+            transpose: 0,
+        }
+    }
+
+    /// the share of the note the layer plays, zero outside of its ranges
+    pub fn weight(&self, note: &Note) -> f32 {
+        let key = note.pitch.get();
+        if key < self.lo_key
+            || key > self.hi_key
+            || note.velocity < self.lo_vel
+            || note.velocity > self.hi_vel
+        {
+            return 0.0;
+        }
+        // the ends of the midi and velocity range are never faded
+        let edge = |open: bool, distance: f32| if open { distance } else { f32::INFINITY };
+        let key_distance = edge(self.lo_key > 0, (key - self.lo_key) as f32)
+            .min(edge(self.hi_key < 127, (self.hi_key - key) as f32));
+        let vel_distance = edge(self.lo_vel > 0.0, note.velocity - self.lo_vel)
+            .min(edge(self.hi_vel < 1.0, self.hi_vel - note.velocity));
+        fade_in(key_distance + 1.0, self.key_fade as f32 + 1.0)
+            * fade_in(vel_distance, self.vel_fade)
+            * utils::db_to_factor(self.gain)
+    }
+
+    /// the note as the instrument of the layer plays it
+    fn transposed(&self, note: &Note) -> Option<Note> {
+        let pitch =
+            Pitch::new(u8::try_from(note.pitch.get() as i16 + self.transpose as i16).ok()?)?;
+        Some(Note {
+            pitch,
+            ..note.clone()
+        })
+    }
+
+    fn map_instrument<T, E>(self, f: impl FnOnce(I) -> Result<T, E>) -> Result<Layer<T>, E> {
+        Ok(Layer {
+            instrument: f(self.instrument)?,
+            lo_key: self.lo_key,
+            hi_key: self.hi_key,
+            lo_vel: self.lo_vel,
+            hi_vel: self.hi_vel,
+            key_fade: self.key_fade,
+            vel_fade: self.vel_fade,
+            gain: self.gain,
+            transpose: self.transpose,
+        })
+    }
+}
+
+fn fade_in(distance: f32, fade: f32) -> f32 {
+    if fade <= 0.0 {
+        1.0
+    } else {
+        (distance / fade).min(1.0)
+    }
+}
+
+/// several instruments split across the keyboard or stacked on top of each other
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Layered {
+    pub(crate) name: String,
+    pub(crate) layers: Vec<Layer<MidiInstrument>>,
+}
+
+impl Layered {
+    pub fn play_note(&self, note: Note) -> Wave {
+        let mut wave = Wave::new();
+        for layer in &self.layers {
+            let weight = layer.weight(&note);
+            if let (true, Some(transposed)) = (weight > 0.0, layer.transposed(&note)) {
+                let mut sound = layer.instrument.play_note(transposed);
+                sound.scale(weight);
+                wave.add(&sound, 0);
+            }
+        }
+        wave
+    }
+
+    /// the notes of a layer go through the instrument's `play_notes` grouped by their weight,
+    /// so crossfaded notes keep what the instrument does across notes
+    pub fn play_notes(&self, notes: &[Note]) -> Wave {
+        let mut wave = Wave::new();
+        for layer in &self.layers {
+            let mut groups: Vec<(f32, Vec<Note>)> = Vec::new();
+            for note in notes {
+                let weight = layer.weight(note);
+                let (true, Some(transposed)) = (weight > 0.0, layer.transposed(note)) else {
+                    continue;
+                };
+                match groups.iter_mut().find(|(w, _)| *w == weight) {
+                    Some((_, group)) => group.push(transposed),
+                    None => groups.push((weight, vec![transposed])),
+                }
+            }
+            for (weight, group) in groups {
+                let mut sound = layer.instrument.play_notes(&group);
+                sound.scale(weight);
+                wave.add(&sound, 0);
+            }
+        }
+        wave
+    }
+
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    pub fn wrap_midi(self) -> MidiInstrument {
+        MidiInstrument::Layered(Box::new(self))
+    }
+}
+
+impl Layered {
+    pub fn extract(&self) -> Result<LayeredBuilder, Error> {
+        let mut layers = Vec::with_capacity(self.layers.len());
+        for layer in &self.layers {
+            layers.push(
+                layer
+                    .clone()
+                    .map_instrument(|instrument| instrument.extract())?,
+            );
+        }
+        Ok(LayeredBuilder {
+            name: self.name.clone(),
+            layers,
+        })
+    }
+
+    pub fn save_to(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn std::error::Error>> {
+        InstrumentBuilder::Layered(self.extract()?).save_to(path)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LayeredBuilder {
+    pub name: String,
+    pub layers: Vec<Layer<InstrumentBuilder>>,
+}
+
+impl LayeredBuilder {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            layers: Vec::new(),
+        }
+    }
+
+    pub fn add_layer(&mut self, layer: Layer<InstrumentBuilder>) {
+        self.layers.push(layer)
+    }
+
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        match InstrumentBuilder::load(path)? {
+            InstrumentBuilder::Layered(layered) => Ok(layered),
+            _ => Err(Error::Type)?,
+        }
+    }
+
+    /// fails with `Error::Value` for more than one synthesizer, nested layers included,
+    /// as a synthesizer keeps its envelopes and lfos in the generators of the track
+    /// and a second one would replace those of the first
+    pub(crate) fn build(self, track_id: u8) -> Result<Layered, Box<dyn std::error::Error>> {
+        if self.synthesizers() > 1 {
+            Err(Error::Value)?
+        }
+        let mut layers = Vec::with_capacity(self.layers.len());
+        for layer in self.layers {
+            layers.push(layer.map_instrument(|instrument| instrument.build(track_id))?);
+        }
+        Ok(Layered {
+            name: self.name,
+            layers,
+        })
+    }
+
+    fn synthesizers(&self) -> usize {
+        self.layers
+            .iter()
+            .map(|layer| match &layer.instrument {
+                InstrumentBuilder::Synthesizer(_) => 1,
+                InstrumentBuilder::Layered(layered) => layered.synthesizers(),
+                _ => 0,
+            })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{instr::synth::SynthBuilder, tracks::midi::note};
+
+    #[test]
+    fn crossfaded_weights() {
+        let mut layer = Layer::new((), 48, 72);
+        layer.key_fade = 3;
+        layer.lo_vel = 0.5;
+        layer.vel_fade = 0.2;
+        assert_eq!(layer.weight(&note(47, 0, 0, 1.0)), 0.0);
+        assert_eq!(layer.weight(&note(48, 0, 0, 1.0)), 0.25);
+        assert_eq!(layer.weight(&note(60, 0, 0, 1.0)), 1.0);
+        assert_eq!(layer.weight(&note(60, 0, 0, 0.4)), 0.0);
+        assert!((layer.weight(&note(60, 0, 0, 0.6)) - 0.5).abs() < 1e-6);
+
+        layer.transpose = -12;
+        assert_eq!(
+            layer.transposed(&note(60, 0, 0, 1.0)).unwrap().pitch.get(),
+            48
+        );
+        assert!(layer.transposed(&note(5, 0, 0, 1.0)).is_none());
+    }
+
+    #[test]
+    fn one_synthesizer_only() {
+        let synth = || InstrumentBuilder::Synthesizer(SynthBuilder::new("synth"));
+        let mut nested = LayeredBuilder::new("nested");
+        nested.add_layer(Layer::new(synth(), 0, 127));
+        let mut layered = LayeredBuilder::new("layered");
+        layered.add_layer(Layer::new(synth(), 0, 63));
+        layered.add_layer(Layer::new(InstrumentBuilder::Layered(nested), 64, 127));
+        let err = layered.build(0).unwrap_err();
+        assert!(matches!(err.downcast_ref::<Error>(), Some(Error::Value)));
+    }
+}
@@ -0,0 +1,375 @@
+use std::{f32::consts::TAU, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    effects::EffectPanel,
+    globals::{SAMPLE_RATE, TIME_MANAGER},
+    network::{Receiver, Transform},
+    receivers::VOL_RECEIVER,
+    time::ClockTick,
+    tracks::midi::{Note, NoteExpression},
+    utils::{self, Rng},
+    wave::Wave,
+    Error,
+};
+
+use super::{InstrumentBuilder, MidiInstrument};
+
+const PITCH_RECEIVER: Receiver = Receiver::new(0.0, (-4800.0, 4800.0), Transform::Linear);
+const PICK_POSITION_RECEIVER: Receiver = Receiver::new(0.2, (0.0, 0.5), Transform::Linear);
+const DAMPING_RECEIVER: Receiver = Receiver::new(0.3, (0.0, 1.0), Transform::Linear);
+const BRIGHTNESS_RECEIVER: Receiver = Receiver::new(0.5, (0.0, 1.0), Transform::Linear);
+const BODY_RECEIVER: Receiver = Receiver::new(0.3, (0.0, 1.0), Transform::Linear);
+const STEREO_RECEIVER: Receiver = Receiver::new(0.5, (0.0, 1.0), Transform::Linear);
+
+/// the time in seconds the string takes to fade by 60 dB after the note is released
+const RELEASE: f32 = 0.15;
+/// how far the strings of the two channels are detuned at full stereo width, in cents
+const STEREO_DETUNE: f32 = 6.0;
+/// resonances of a guitar body in Hz and their gain
+const BODY_MODES: [(f32, f32); 3] = [(98.0, 1.0), (204.0, 0.7), (398.0, 0.4)];
+
+/// extended Karplus-Strong string, the receivers besides the pitch and volume are evaluated when the note starts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PluckedString {
+    pub(crate) name: String,
+    pub(crate) effects: EffectPanel,
+    pub(crate) volume: Receiver,
+    pub(crate) pitch: Receiver,
+    pub(crate) pick_position: Receiver,
+    pub(crate) damping: Receiver,
+    pub(crate) brightness: Receiver,
+    pub(crate) body: Receiver,
+    pub(crate) stereo: Receiver,
+}
+
+impl PluckedString {
+    fn play_freq(
+        &self,
+        note_on: ClockTick,
+        note_off: ClockTick,
+        freq: f32,
+        velocity: f32,
+        expression: Option<&NoteExpression>,
+    ) -> Wave {
+        let time_manager = TIME_MANAGER.read().unwrap();
+        let sus_samples = time_manager.duration_to_samples(note_on, note_off);
+        let seed = (time_manager.tick_to_sample(note_on) as u64) << 7;
+        drop(time_manager);
+
+        let len = sus_samples + utils::seconds_to_samples(RELEASE);
+        let mut cent_offsets = self.pitch.get_vec(note_on, len);
+        if let Some(expression) = expression {
+            utils::add_elementwise(&mut cent_offsets, &expression.bend_cents(note_on, len));
+        }
+        let stereo = self.stereo.get_val(note_on);
+        let string = |seed: u64, detune: f32| {
+            let freq: Vec<f32> = cent_offsets
+                .iter()
+                .map(|cents| freq * utils::fast_pow2((cents + detune) / 1200.0))
+                .collect();
+            self.pluck(note_on, &freq, sus_samples, velocity, seed)
+        };
+        let first = string(seed, 0.0);
+        let (right, left) = if stereo > 0.0 {
+            let second = string(seed | 1, stereo * STEREO_DETUNE);
+            let mix = stereo / 2.0;
+            (
+                mix_channels(&first, &second, mix),
+                mix_channels(&second, &first, mix),
+            )
+        } else {
+            (first.clone(), first)
+        };
+
+        let body = self.body.get_val(note_on);
+        let mut wave = Wave::from_vecs(add_body(right, body), add_body(left, body));
+        wave.scale(velocity);
+        wave.scale_by_vec(self.volume.get_vec(note_on, wave.len()));
+        self.effects.apply_to(&mut wave, note_on);
+        wave
+    }
+
+    /// a single string following `freq`, it is damped quickly after `sus_samples`
+    fn pluck(
+        &self,
+        note_on: ClockTick,
+        freq: &[f32],
+        sus_samples: usize,
+        velocity: f32,
+        seed: u64,
+    ) -> Vec<f32> {
+        let brightness = self.brightness.get_val(note_on);
+        // loop filter weight, 0.5 is the plain Karplus-Strong average
+        let smoothing = 0.5 * (1.0 - brightness);
+        // from 10 seconds down to 0.2 seconds to fade by 60 dB
+        let decay = 10.0 * 0.02_f32.powf(self.damping.get_val(note_on));
+
+        let period = SAMPLE_RATE as f32 / freq[0].max(20.0);
+        let excitation = self.excitation(note_on, period, brightness * velocity, seed);
+
+        let mut out: Vec<f32> = Vec::with_capacity(freq.len());
+        for (i, freq) in freq.iter().enumerate() {
+            let period = SAMPLE_RATE as f32 / freq.max(20.0);
+            let decay = if i < sus_samples { decay } else { RELEASE };
+            let gain = 10_f32.powf(-3.0 * period / SAMPLE_RATE as f32 / decay);
+            let delay = period - smoothing;
+            let feedback = (1.0 - smoothing) * read(&out, i as f32 - delay)
+                + smoothing * read(&out, i as f32 - delay - 1.0);
+            out.push(excitation.get(i).copied().unwrap_or(0.0) + gain * feedback);
+        }
+        out
+    }
+
+    /// a burst of noise one period long, darker for lower `brightness` and combed by the pick position
+    fn excitation(&self, note_on: ClockTick, period: f32, brightness: f32, seed: u64) -> Vec<f32> {
+        let len = period.ceil() as usize;
+        let mut rng = Rng::new(seed);
+        let coefficient = 0.1 + 0.9 * brightness.clamp(0.0, 1.0);
+        let mut last = 0.0;
+        let noise: Vec<f32> = (0..len)
+            .map(|_| {
+                last += coefficient * (rng.noise() - last);
+                last
+            })
+            .collect();
+
+        let pick = (self.pick_position.get_val(note_on) * period) as usize;
+        (0..len)
+            .map(|i| match i.checked_sub(pick) {
+                Some(j) if pick > 0 => noise[i] - noise[j],
+                _ => noise[i],
+            })
+            .collect()
+    }
+
+    pub fn play_note(&self, note: Note) -> Wave {
+        match note.pitch.get_freq() {
+            Some(freq) => self.play_freq(
+                note.on,
+                note.off,
+                freq,
+                note.velocity,
+                note.expression.as_deref(),
+            ),
+            None => Wave::new(),
+        }
+    }
+
+    pub fn play_notes(&self, notes: &[Note]) -> Wave {
+        let mut wave = Wave::new();
+        for note in notes {
+            wave.add(
+                &self.play_note(note.clone()),
+                TIME_MANAGER.read().unwrap().tick_to_sample(note.on),
+            );
+        }
+        wave
+    }
+
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    pub fn wrap_midi(self) -> MidiInstrument {
+        MidiInstrument::PluckedString(Box::new(self))
+    }
+}
+
+impl PluckedString {
+    pub fn extract(&self) -> PluckedStringBuilder {
+        PluckedStringBuilder {
+            name: self.name.clone(),
+            effects: self.effects.extract(),
+            volume: self.volume.extract(),
+            pitch: self.pitch.extract(),
+            pick_position: self.pick_position.extract(),
+            damping: self.damping.extract(),
+            brightness: self.brightness.extract(),
+            body: self.body.extract(),
+            stereo: self.stereo.extract(),
+        }
+    }
+
+    pub fn save_to(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn std::error::Error>> {
+        InstrumentBuilder::PluckedString(self.extract()).save_to(path)
+    }
+}
+
+/// linear interpolation into the already computed samples, silence before the start
+fn read(signal: &[f32], position: f32) -> f32 {
+    if position < 0.0 {
+        return 0.0;
+    }
+    let index = position as usize;
+    let frac = position - index as f32;
+    let at = |i: usize| signal.get(i).copied().unwrap_or(0.0);
+    at(index) * (1.0 - frac) + at(index + 1) * frac
+}
+
+fn mix_channels(main: &[f32], other: &[f32], mix: f32) -> Vec<f32> {
+    main.iter()
+        .zip(other)
+        .map(|(a, b)| a * (1.0 - mix) + b * mix)
+        .collect()
+}
+
+/// mixes in the string filtered by the resonances of a guitar body
+fn add_body(string: Vec<f32>, amount: f32) -> Vec<f32> {
+    if amount <= 0.0 {
+        return string;
+    }
+    let mut out = string.clone();
+    for (center, gain) in BODY_MODES {
+        let resonance = resonator(&string, center, 8.0);
+        for (o, r) in out.iter_mut().zip(resonance) {
+            *o += amount * gain * r;
+        }
+    }
+    out
+}
+
+/// band pass biquad with a peak gain of 1.0
+fn resonator(signal: &[f32], center: f32, q: f32) -> Vec<f32> {
+    let w = TAU * center / SAMPLE_RATE as f32;
+    let alpha = w.sin() / (2.0 * q);
+    let a0 = 1.0 + alpha;
+    let (b0, a1, a2) = (alpha / a0, -2.0 * w.cos() / a0, (1.0 - alpha) / a0);
+    let (mut x1, mut x2, mut y1, mut y2) = (0.0, 0.0, 0.0, 0.0);
+    signal
+        .iter()
+        .map(|x| {
+            let y = b0 * (x - x2) - a1 * y1 - a2 * y2;
+            x2 = x1;
+            x1 = *x;
+            y2 = y1;
+            y1 = y;
+            y
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PluckedStringBuilder {
+    pub name: String,
+    pub effects: EffectPanel,
+    pub volume: Receiver,
+    /// in cents
+    pub pitch: Receiver,
+    /// where the string is plucked, from the bridge to its middle
+    pub pick_position: Receiver,
+    /// shortens the decay of the string
+    pub damping: Receiver,
+    /// how much of the high harmonics survives the pluck and the loop filter
+    pub brightness: Receiver,
+    /// amount of body resonance
+    pub body: Receiver,
+    /// width between two slightly detuned strings
+    pub stereo: Receiver,
+}
+
+impl PluckedStringBuilder {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            effects: EffectPanel::EmptyLeaf,
+            volume: VOL_RECEIVER,
+            pitch: PITCH_RECEIVER,
+            pick_position: PICK_POSITION_RECEIVER,
+            damping: DAMPING_RECEIVER,
+            brightness: BRIGHTNESS_RECEIVER,
+            body: BODY_RECEIVER,
+            stereo: STEREO_RECEIVER,
+        }
+    }
+
+    pub fn set_pick_position(&mut self, pick_position: f32) -> Result<(), Error> {
+        self.pick_position = PICK_POSITION_RECEIVER.csv(pick_position)?;
+        Ok(())
+    }
+
+    pub fn set_damping(&mut self, damping: f32) -> Result<(), Error> {
+        self.damping = DAMPING_RECEIVER.csv(damping)?;
+        Ok(())
+    }
+
+    pub fn set_brightness(&mut self, brightness: f32) -> Result<(), Error> {
+        self.brightness = BRIGHTNESS_RECEIVER.csv(brightness)?;
+        Ok(())
+    }
+
+    pub fn set_body(&mut self, body: f32) -> Result<(), Error> {
+        self.body = BODY_RECEIVER.csv(body)?;
+        Ok(())
+    }
+
+    pub fn set_stereo(&mut self, stereo: f32) -> Result<(), Error> {
+        self.stereo = STEREO_RECEIVER.csv(stereo)?;
+        Ok(())
+    }
+
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        match InstrumentBuilder::load(path)? {
+            InstrumentBuilder::PluckedString(string) => Ok(string),
+            _ => Err(Error::Type)?,
+        }
+    }
+
+    pub(crate) fn build(self, track_id: u8) -> PluckedString {
+        let mut receivers = [
+            self.volume,
+            self.pitch,
+            self.pick_position,
+            self.damping,
+            self.brightness,
+            self.body,
+            self.stereo,
+        ];
+        for receiver in &mut receivers {
+            receiver.set_id(track_id);
+        }
+        let [volume, pitch, pick_position, damping, brightness, body, stereo] = receivers;
+
+        let mut effects = self.effects;
+        effects.set_id(track_id);
+
+        PluckedString {
+            name: self.name,
+            effects,
+            volume,
+            pitch,
+            pick_position,
+            damping,
+            brightness,
+            body,
+            stereo,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn string_rings_at_its_pitch() {
+        let string = PluckedStringBuilder::new("string").build(0);
+        let freq = vec![441.0; SAMPLE_RATE];
+        let out = string.pluck(ClockTick::abs_zero(), &freq, SAMPLE_RATE / 2, 1.0, 3);
+
+        // the string repeats itself every period while it fades
+        let period = SAMPLE_RATE / 441;
+        let start = SAMPLE_RATE / 10;
+        let correlation: f32 = (start..start + period)
+            .map(|i| out[i] * out[i + period])
+            .sum();
+        let energy: f32 = (start..start + period).map(|i| out[i] * out[i]).sum();
+        assert!(correlation > 0.8 * energy);
+
+        let rms = |range: std::ops::Range<usize>| {
+            (out[range.clone()].iter().map(|x| x * x).sum::<f32>() / range.len() as f32).sqrt()
+        };
+        assert!(rms(0..4410) > rms(SAMPLE_RATE - 4410..SAMPLE_RATE) * 10.0);
+    }
+}
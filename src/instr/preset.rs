@@ -0,0 +1,214 @@
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    resources::{SampleSource, WavetableSource},
+    utils::{self, oscs::Oscillator},
+    Error,
+};
+
+use super::{
+    drum_machine::DrumMachineBuilder, drums::DrumsBuilder, fm::FmSynthBuilder,
+    granular::GranularBuilder, layered::LayeredBuilder, pluck::PluckedStringBuilder,
+    sampler::SamplerBuilder, synth::SynthBuilder, MidiInstrument,
+};
+
+/// the newest preset format, files with a higher version can't be read
+pub const PRESET_VERSION: u32 = 1;
+
+/// an instrument of any kind ready to be added to a track
+#[derive(Debug, Serialize, Deserialize)]
+pub enum InstrumentBuilder {
+    Synthesizer(SynthBuilder),
+    Fm(FmSynthBuilder),
+    Drums(DrumsBuilder),
+    DrumMachine(DrumMachineBuilder),
+    Sampler(SamplerBuilder),
+    PluckedString(PluckedStringBuilder),
+    Granular(GranularBuilder),
+    Layered(LayeredBuilder),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Preset {
+    version: u32,
+    instrument: InstrumentBuilder,
+}
+
+impl InstrumentBuilder {
+    /// reads a preset, sample paths in it are relative to the preset file
+    ///
+    /// files holding a bare synth or drum kit from before the preset format are read as well,
+    /// their sample paths stay relative to the working directory as they were written
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        match ron::from_str::<Preset>(&text) {
+            Ok(preset) if preset.version <= PRESET_VERSION => {
+                let mut builder = preset.instrument;
+                let dir = path.parent().unwrap_or(Path::new(""));
+                builder.map_paths(&mut |sample| dir.join(sample));
+                Ok(builder)
+            }
+            Ok(_) => Err(Error::Version)?,
+            Err(err) => {
+                if let Ok(synth) = ron::from_str(&text) {
+                    Ok(Self::Synthesizer(synth))
+                } else if let Ok(drums) = ron::from_str(&text) {
+                    Ok(Self::Drums(drums))
+                } else {
+                    Err(err)?
+                }
+            }
+        }
+    }
+
+    /// writes the preset with the sample paths relative to the preset file
+    pub fn save_to(mut self, path: impl AsRef<Path>) -> Result<(), Box<dyn std::error::Error>> {
+        let path = path.as_ref();
+        let dir = path.parent().unwrap_or(Path::new(""));
+        self.map_paths(&mut |sample| utils::relative_path(sample, dir));
+        let file = File::create(path)?;
+        let preset = Preset {
+            version: PRESET_VERSION,
+            instrument: self,
+        };
+        ron::ser::to_writer_pretty(file, &preset, Default::default())?;
+        Ok(())
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            InstrumentBuilder::Synthesizer(synth) => &synth.name,
+            InstrumentBuilder::Fm(fm) => &fm.name,
+            InstrumentBuilder::Drums(drums) => &drums.name,
+            InstrumentBuilder::DrumMachine(drums) => &drums.name,
+            InstrumentBuilder::Sampler(sampler) => &sampler.name,
+            InstrumentBuilder::PluckedString(string) => &string.name,
+            InstrumentBuilder::Granular(granular) => &granular.name,
+            InstrumentBuilder::Layered(layered) => &layered.name,
+        }
+    }
+
+    /// the instrument for the track with the given id
+    pub(crate) fn build(self, track_id: u8) -> Result<MidiInstrument, Box<dyn std::error::Error>> {
+        Ok(match self {
+            InstrumentBuilder::Synthesizer(synth) => synth.build(track_id)?.wrap_midi(),
+            InstrumentBuilder::Fm(fm) => fm.build(track_id)?.wrap_midi(),
+            InstrumentBuilder::Drums(drums) => {
+                MidiInstrument::Drums(Box::new(drums.build(track_id)?))
+            }
+            InstrumentBuilder::DrumMachine(drums) => drums.build(track_id).wrap_midi(),
+            InstrumentBuilder::Sampler(sampler) => sampler.build(track_id)?.wrap_midi(),
+            InstrumentBuilder::PluckedString(string) => string.build(track_id).wrap_midi(),
+            InstrumentBuilder::Granular(granular) => granular.build(track_id)?.wrap_midi(),
+            InstrumentBuilder::Layered(layered) => layered.build(track_id)?.wrap_midi(),
+        })
+    }
+
+    fn map_paths(&mut self, f: &mut dyn FnMut(&Path) -> PathBuf) {
+        match self {
+            InstrumentBuilder::Synthesizer(synth) => {
+                for oscillator in synth
+                    .oscillators
+                    .oscillators_mut()
+                    .iter_mut()
+                    .chain(synth.instr_generator.lfo_oscillators_mut())
+                {
+                    if let Oscillator::WavetableSource(WavetableSource::Wav { path, .. }) =
+                        oscillator
+                    {
+                        *path = f(path);
+                    }
+                }
+            }
+            InstrumentBuilder::Fm(_)
+            | InstrumentBuilder::DrumMachine(_)
+            | InstrumentBuilder::PluckedString(_) => (),
+            InstrumentBuilder::Drums(drums) => {
+                for path in drums.samples.values_mut() {
+                    *path = f(path);
+                }
+                for pad in drums.pads.values_mut() {
+                    for layer in &mut pad.layers {
+                        for path in &mut layer.samples {
+                            *path = f(path);
+                        }
+                    }
+                }
+            }
+            InstrumentBuilder::Sampler(sampler) => {
+                for zone in &mut sampler.zones {
+                    map_source(&mut zone.sample, f);
+                }
+            }
+            InstrumentBuilder::Granular(granular) => map_source(&mut granular.sample, f),
+            InstrumentBuilder::Layered(layered) => {
+                for layer in &mut layered.layers {
+                    layer.instrument.map_paths(f);
+                }
+            }
+        }
+    }
+}
+
+fn map_source(source: &mut SampleSource, f: &mut dyn FnMut(&Path) -> PathBuf) {
+    match source {
+        SampleSource::Wav(path) => *path = f(path),
+        SampleSource::Sf2(sample) => sample.path = f(&sample.path),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{instr::drums::Pad, tracks::midi::Pitch};
+
+    #[test]
+    fn sample_paths_relative_to_preset() {
+        let dir = std::env::temp_dir().join("song_preset_test");
+        fs::create_dir_all(dir.join("kits")).unwrap();
+        let sample = dir.join("samples").join("snare.wav");
+
+        let mut drums = DrumsBuilder::default();
+        drums.samples.clear();
+        drums.add_pad(Pitch::new(38).unwrap(), Pad::new(sample.clone()));
+        let path = dir.join("kits").join("kit.ron");
+        InstrumentBuilder::Drums(drums).save_to(&path).unwrap();
+
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.contains("version: 1"));
+        assert!(text.contains(&format!(
+            "{:?}",
+            Path::new("..").join("samples").join("snare.wav")
+        )));
+
+        let InstrumentBuilder::Drums(drums) = InstrumentBuilder::load(&path).unwrap() else {
+            panic!("preset was loaded as another instrument");
+        };
+        let loaded = &drums.pads[&Pitch::new(38).unwrap()].layers[0].samples[0];
+        assert_eq!(
+            *loaded,
+            dir.join("kits")
+                .join("..")
+                .join("samples")
+                .join("snare.wav")
+        );
+    }
+
+    #[test]
+    fn old_files_keep_their_paths() {
+        let InstrumentBuilder::Drums(drums) = InstrumentBuilder::load("instr/drums.ron").unwrap()
+        else {
+            panic!("drum kit was loaded as another instrument");
+        };
+        assert_eq!(
+            drums.samples[&Pitch::new(36).unwrap()],
+            Path::new("samples/kick.wav")
+        );
+    }
+}
@@ -0,0 +1,479 @@
+use std::{collections::HashMap, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    effects::EffectPanel,
+    gens::Envelope,
+    globals::{RESOURCE_MANAGER, TIME_MANAGER},
+    io::{sf2::Sf2, sfz},
+    network::Receiver,
+    receivers::VOL_RECEIVER,
+    resources::{SampleId, SampleSource},
+    tracks::midi::Note,
+    utils,
+    wave::Wave,
+    Error,
+};
+
+use super::{InstrumentBuilder, MidiInstrument};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LoopMode {
+    /// plays the sample until the note and its release ends
+    #[default]
+    NoLoop,
+    /// plays the whole sample ignoring note off and the envelope
+    OneShot,
+    /// loops between start and end until the release ends
+    Continuous { start: usize, end: usize },
+    /// loops between start and end while the note is held, then plays out the sample
+    Sustain { start: usize, end: usize },
+}
+
+/// a sample mapped to a range of keys and velocities
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Zone<S> {
+    pub sample: S,
+    pub lo_key: u8,
+    pub hi_key: u8,
+    /// the key at which the sample plays unchanged
+    pub root: u8,
+    #[serde(default)]
+    pub lo_vel: f32,
+    #[serde(default = "full_velocity")]
+    pub hi_vel: f32,
+    /// zones in the same group share a round-robin counter
+    #[serde(default)]
+    pub group: u32,
+    #[serde(default = "one")]
+    pub seq_length: u8,
+    /// the zone plays every `seq_length`th note of its group starting at `seq_position`
+    #[serde(default = "one")]
+    pub seq_position: u8,
+    #[serde(default)]
+    pub loop_mode: LoopMode,
+    /// in cents
+    #[serde(default)]
+    pub tune: f32,
+    /// in dB
+    #[serde(default)]
+    pub gain: f32,
+    #[serde(default)]
+    pub pan: f32,
+    /// replaces the envelope of the sampler for this zone
+    #[serde(default)]
+    pub envelope: Option<Envelope>,
+}
+
+fn full_velocity() -> f32 {
+    1.0
+}
+
+fn one() -> u8 {
+    1
+}
+
+impl<S> Zone<S> {
+    pub fn new(sample: S, lo_key: u8, hi_key: u8, root: u8) -> Self {
+        Self {
+            sample,
+            lo_key,
+            hi_key,
+            root,
+            lo_vel: 0.0,
+            hi_vel: 1.0,
+            group: 0,
+            seq_length: 1,
+            seq_position: 1,
+            loop_mode: LoopMode::NoLoop,
+            tune: 0.0,
+            gain: 0.0,
+            pan: 0.0,
+            envelope: None,
+        }
+    }
+
+    fn contains(&self, note: &Note) -> bool {
+        (self.lo_key..=self.hi_key).contains(&note.pitch.get())
+            && note.velocity >= self.lo_vel
+            && note.velocity <= self.hi_vel
+    }
+
+    fn in_turn(&self, counter: usize) -> bool {
+        self.seq_length <= 1 || counter % self.seq_length as usize + 1 == self.seq_position as usize
+    }
+
+    fn map_sample<T>(&self, sample: T) -> Zone<T> {
+        Zone {
+            sample,
+            lo_key: self.lo_key,
+            hi_key: self.hi_key,
+            root: self.root,
+            lo_vel: self.lo_vel,
+            hi_vel: self.hi_vel,
+            group: self.group,
+            seq_length: self.seq_length,
+            seq_position: self.seq_position,
+            loop_mode: self.loop_mode,
+            tune: self.tune,
+            gain: self.gain,
+            pan: self.pan,
+            envelope: self.envelope.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sampler {
+    pub(crate) name: String,
+    pub(crate) effects: EffectPanel,
+    pub(crate) volume: Receiver,
+    pub(crate) envelope: Envelope,
+    pub(crate) zones: Vec<Zone<SampleId>>,
+}
+
+impl Sampler {
+    fn play_zones(&self, note: &Note, zones: &[&Zone<SampleId>]) -> Wave {
+        let sus_samples = TIME_MANAGER
+            .read()
+            .unwrap()
+            .duration_to_samples(note.on, note.off);
+        let envelope = self.envelope.get_envelope(note.on, sus_samples);
+
+        let mut wave = Wave::new();
+        let Some(freq) = note.pitch.get_freq() else {
+            return wave;
+        };
+        // long enough for a one shot bent down as far as the note goes
+        let slowest = note.expression.as_ref().map_or(1.0, |expression| {
+            utils::fast_pow2(-expression.bend_range / 12.0)
+        });
+        for zone in zones {
+            let sample = RESOURCE_MANAGER.read().unwrap().get_sample(zone.sample);
+            let ratio =
+                freq / utils::pitch_to_freq(zone.root) * utils::fast_pow2(zone.tune / 1200.0);
+            let mut sound = match zone.loop_mode {
+                LoopMode::OneShot => {
+                    let len = (sample.len() as f32 / ratio / slowest) as usize;
+                    let bend = note.bend_factors(len);
+                    render_sample(&sample, ratio, &bend, zone.loop_mode, sus_samples, len)
+                }
+                _ => {
+                    let mut envelope = match &zone.envelope {
+                        Some(envelope) => envelope.get_envelope(note.on, sus_samples),
+                        None => envelope.clone(),
+                    };
+                    let sound = render_sample(
+                        &sample,
+                        ratio,
+                        &note.bend_factors(envelope.len()),
+                        zone.loop_mode,
+                        sus_samples,
+                        envelope.len(),
+                    );
+                    envelope.truncate(sound.len());
+                    let mut sound = sound;
+                    sound.scale_by_vec(envelope);
+                    sound
+                }
+            };
+            sound.scale(utils::db_to_factor(zone.gain));
+            sound.pan(zone.pan);
+            wave.add(&sound, 0);
+        }
+        wave.scale(note.velocity);
+        wave.scale_by_vec(self.volume.get_vec(note.on, wave.len()));
+        self.effects.apply_to(&mut wave, note.on);
+        wave
+    }
+
+    pub fn play_note(&self, note: Note) -> Wave {
+        self.play_zones(&note, &matching_zones(&self.zones, &note, &HashMap::new()))
+    }
+
+    pub fn play_notes(&self, notes: &[Note]) -> Wave {
+        let mut wave = Wave::new();
+        for (note, zones) in round_robin(&self.zones, notes) {
+            wave.add(
+                &self.play_zones(&note, &zones),
+                TIME_MANAGER.read().unwrap().tick_to_sample(note.on),
+            );
+        }
+        wave
+    }
+
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    pub fn wrap_midi(self) -> MidiInstrument {
+        MidiInstrument::Sampler(Box::new(self))
+    }
+}
+
+fn matching_zones<'a, S>(
+    zones: &'a [Zone<S>],
+    note: &Note,
+    counters: &HashMap<u32, usize>,
+) -> Vec<&'a Zone<S>> {
+    zones
+        .iter()
+        .filter(|zone| {
+            zone.contains(note) && zone.in_turn(*counters.get(&zone.group).unwrap_or(&0))
+        })
+        .collect()
+}
+
+/// the zones of every note in the order the notes start, every note advances the groups it's in
+fn round_robin<'a, S>(zones: &'a [Zone<S>], notes: &[Note]) -> Vec<(Note, Vec<&'a Zone<S>>)> {
+    let mut notes = notes.to_vec();
+    notes.sort_by_key(|note| note.on);
+    let mut counters = HashMap::<u32, usize>::new();
+    notes
+        .into_iter()
+        .map(|note| {
+            let matching = matching_zones(zones, &note, &counters);
+            let mut groups: Vec<u32> = zones
+                .iter()
+                .filter(|zone| zone.contains(&note))
+                .map(|zone| zone.group)
+                .collect();
+            groups.sort_unstable();
+            groups.dedup();
+            for group in groups {
+                *counters.entry(group).or_default() += 1;
+            }
+            (note, matching)
+        })
+        .collect()
+}
+
+/// reads through the sample with linear interpolation, stepping `ratio` samples per output sample
+/// `bend` scales the ratio of every rendered sample, it is 1.0 past its end
+fn render_sample(
+    sample: &Wave,
+    ratio: f32,
+    bend: &[f32],
+    loop_mode: LoopMode,
+    sus_samples: usize,
+    len: usize,
+) -> Wave {
+    let mut right = Vec::with_capacity(len);
+    let mut left = Vec::with_capacity(len);
+    let mut pos = 0.0_f64;
+    for i in 0..len {
+        let loop_points = match loop_mode {
+            LoopMode::Continuous { start, end } => Some((start, end)),
+            LoopMode::Sustain { start, end } if i < sus_samples => Some((start, end)),
+            _ => None,
+        };
+        if let Some((start, end)) = loop_points {
+            let end = end.min(sample.len());
+            if start < end && pos >= end as f64 {
+                pos = start as f64 + (pos - end as f64) % (end - start) as f64;
+            }
+        }
+        let index = pos as usize;
+        if index + 1 >= sample.len() {
+            break;
+        }
+        let frac = (pos - index as f64) as f32;
+        right.push(sample.right()[index] * (1.0 - frac) + sample.right()[index + 1] * frac);
+        left.push(sample.left()[index] * (1.0 - frac) + sample.left()[index + 1] * frac);
+        pos += (ratio * bend.get(i).copied().unwrap_or(1.0)) as f64;
+    }
+    Wave::from_vecs(right, left)
+}
+
+impl Sampler {
+    pub fn extract(&self) -> Result<SamplerBuilder, Error> {
+        let mut zones = Vec::with_capacity(self.zones.len());
+        for zone in &self.zones {
+            zones.push(zone.map_sample(RESOURCE_MANAGER.read().unwrap().get_source(zone.sample)?));
+        }
+        Ok(SamplerBuilder {
+            name: self.name.clone(),
+            effects: self.effects.extract(),
+            volume: self.volume.extract(),
+            envelope: self.envelope.extract(),
+            zones,
+        })
+    }
+
+    pub fn save_to(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn std::error::Error>> {
+        InstrumentBuilder::Sampler(self.extract()?).save_to(path)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SamplerBuilder {
+    pub name: String,
+    pub effects: EffectPanel,
+    pub volume: Receiver,
+    pub envelope: Envelope,
+    pub zones: Vec<Zone<SampleSource>>,
+}
+
+impl SamplerBuilder {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            effects: EffectPanel::EmptyLeaf,
+            volume: VOL_RECEIVER,
+            envelope: Envelope::new_adsr(0.005, 0.0, 1.0, 0.3).unwrap(),
+            zones: Vec::new(),
+        }
+    }
+
+    pub fn add_zone(&mut self, zone: Zone<SampleSource>) {
+        self.zones.push(zone)
+    }
+
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        match InstrumentBuilder::load(path)? {
+            InstrumentBuilder::Sampler(sampler) => Ok(sampler),
+            _ => Err(Error::Type)?,
+        }
+    }
+
+    /// imports an sfz file, opcodes the sampler can't represent are returned
+    pub fn from_sfz(
+        path: impl AsRef<Path>,
+    ) -> Result<(Self, Vec<sfz::Unsupported>), Box<dyn std::error::Error>> {
+        sfz::parse_sfz_file(path)
+    }
+
+    /// imports the preset with the given bank and program number from a SoundFont 2 file
+    pub fn from_sf2(
+        path: impl AsRef<Path>,
+        bank: u16,
+        program: u16,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Sf2::open(path)?.instrument(bank, program)?)
+    }
+
+    pub(crate) fn build(self, track_id: u8) -> Result<Sampler, Box<dyn std::error::Error>> {
+        let mut effects = self.effects;
+        effects.set_id(track_id);
+
+        let mut volume = self.volume;
+        volume.set_id(track_id);
+
+        let mut envelope = self.envelope;
+        envelope.set_track_id(track_id);
+
+        let mut zones = Vec::with_capacity(self.zones.len());
+        for mut zone in self.zones {
+            if let Some(envelope) = &mut zone.envelope {
+                envelope.set_track_id(track_id);
+            }
+            let id = RESOURCE_MANAGER.write().unwrap().add_source(&zone.sample)?;
+            zones.push(zone.map_sample(id));
+        }
+
+        Ok(Sampler {
+            name: self.name,
+            effects,
+            volume,
+            envelope,
+            zones,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tracks::midi::note;
+
+    #[test]
+    fn zones_by_key_and_velocity() {
+        let mut soft = Zone::new("soft", 48, 59, 54);
+        soft.hi_vel = 0.5;
+        let mut loud = Zone::new("loud", 48, 59, 54);
+        loud.lo_vel = 0.5;
+        let high = Zone::new("high", 60, 72, 66);
+        let zones = [soft, loud, high];
+        let samples = |note| {
+            matching_zones(&zones, &note, &HashMap::new())
+                .iter()
+                .map(|zone| zone.sample)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(samples(note(50, 0, 1, 0.2)), vec!["soft"]);
+        assert_eq!(samples(note(50, 0, 1, 0.5)), vec!["soft", "loud"]);
+        assert_eq!(samples(note(59, 0, 1, 0.9)), vec!["loud"]);
+        assert_eq!(samples(note(60, 0, 1, 0.9)), vec!["high"]);
+        assert!(samples(note(73, 0, 1, 0.9)).is_empty());
+    }
+
+    #[test]
+    fn round_robin_order() {
+        let zones: Vec<Zone<&str>> = ["a", "b", "c"]
+            .into_iter()
+            .enumerate()
+            .map(|(i, sample)| {
+                let mut zone = Zone::new(sample, 0, 127, 60);
+                zone.seq_length = 3;
+                zone.seq_position = i as u8 + 1;
+                zone
+            })
+            .chain([Zone::new("always", 0, 127, 60)].map(|mut zone| {
+                zone.group = 1;
+                zone
+            }))
+            .collect();
+        // the notes are played in the order they start
+        let notes: Vec<_> = (0..4)
+            .rev()
+            .map(|i| note(60, i * 10, i * 10 + 5, 1.0))
+            .collect();
+        let played: Vec<Vec<&str>> = round_robin(&zones, &notes)
+            .into_iter()
+            .map(|(_, zones)| zones.iter().map(|zone| zone.sample).collect())
+            .collect();
+        assert_eq!(
+            played,
+            vec![
+                vec!["a", "always"],
+                vec!["b", "always"],
+                vec!["c", "always"],
+                vec!["a", "always"]
+            ]
+        );
+    }
+
+    #[test]
+    fn loops_wrap_around() {
+        let ramp: Vec<f32> = (0..8).map(|i| i as f32).collect();
+        let sample = Wave::from_vecs(ramp.clone(), ramp);
+
+        let looped = render_sample(
+            &sample,
+            1.0,
+            &[],
+            LoopMode::Continuous { start: 2, end: 6 },
+            0,
+            12,
+        );
+        assert_eq!(
+            looped.right(),
+            &[0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 2.0, 3.0, 4.0, 5.0, 2.0, 3.0]
+        );
+
+        // after the release the sample plays out from where the loop was
+        let sustained = render_sample(
+            &sample,
+            1.0,
+            &[],
+            LoopMode::Sustain { start: 2, end: 6 },
+            8,
+            20,
+        );
+        assert_eq!(
+            sustained.right(),
+            &[0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 2.0, 3.0, 4.0, 5.0, 6.0]
+        );
+    }
+}
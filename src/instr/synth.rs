@@ -1,21 +1,27 @@
 use crate::{
     effects::EffectPanel,
-    gens::{Envelope, GenId, GenSaveBuilder, Lfo, Specific},
+    gens::{Envelope, GenId, GenSaveBuilder, Lfo, Specific, TI},
     globals::{GENRATOR_MANAGER, TIME_MANAGER},
     network::{Network, Receiver, Transform},
     receivers::VOL_RECEIVER,
     time::ClockTick,
-    tracks::midi,
+    tracks::midi::{self, NoteExpression},
+    utils,
     wave::Wave,
+    Error,
 };
 use serde::{Deserialize, Serialize};
-use std::{fs::File, path::Path};
+use std::{borrow::Cow, path::Path};
 
+pub mod filter;
+pub mod mod_matrix;
 pub mod osc_panel;
 
+pub use filter::Filter;
+pub use mod_matrix::{ModDestination, ModSource, Modulation};
 pub use osc_panel::OscPanel;
 
-use super::MidiInstrument;
+use super::{InstrumentBuilder, MidiInstrument};
 
 const PITCH_RECEIVER: Receiver = Receiver::new(0.0, (-4800.0, 4800.0), Transform::Linear);
 
@@ -31,10 +37,23 @@ pub struct Synthesizer {
     pub(crate) lfo_2: GenId,
     pub(crate) pitch_receiver: Receiver,
     pub(crate) volume_receiver: Receiver,
+    #[serde(default)]
+    pub(crate) filter: Option<Box<Filter>>,
+    #[serde(default)]
+    pub(crate) mod_matrix: Vec<Modulation>,
 }
 
 impl Synthesizer {
-    fn play_freq(&self, note_on: ClockTick, note_off: ClockTick, freq: f32, velocity: f32) -> Wave {
+    /// `key` is the midi key from 0.0 to 1.0, independent of the tuning
+    fn play_freq(
+        &self,
+        note_on: ClockTick,
+        note_off: ClockTick,
+        freq: f32,
+        key: f32,
+        velocity: f32,
+        expression: Option<&NoteExpression>,
+    ) -> Wave {
         GENRATOR_MANAGER
             .write()
             .unwrap()
@@ -57,27 +76,147 @@ impl Synthesizer {
             .unwrap()
             .get_envelope(self.main_enevelope, note_on, sus_samples)
             .expect("non envelope envelope call");
+        let alt_envelope = if self.filter.is_some() || !self.mod_matrix.is_empty() {
+            GENRATOR_MANAGER
+                .read()
+                .unwrap()
+                .get_envelope(self.alt_enevelope, note_on, sus_samples)
+                .expect("non envelope envelope call")
+        } else {
+            Vec::new()
+        };
+        if !self.mod_matrix.is_empty() {
+            self.set_voice_sources(note_on, key, &envelope, &alt_envelope, expression);
+        }
         // TODO
-        let cent_offsets = self.pitch_receiver.get_vec(note_on, envelope.len());
+        let mut cent_offsets = self.pitch_receiver.get_vec(note_on, envelope.len());
+        if let Some(expression) = expression {
+            let bend = expression.bend_cents(note_on, envelope.len());
+            utils::add_elementwise(&mut cent_offsets, &bend);
+        }
 
         let mut wave = self
             .oscillators
             .play(freq, &cent_offsets, note_on, envelope.len());
+        if let Some(filter) = &self.filter {
+            filter.apply(&mut wave, note_on, freq, &alt_envelope);
+        }
         wave.scale_by_vec(self.volume_receiver.get_vec(note_on, envelope.len()));
         wave.scale_by_vec(envelope);
         self.effects.apply_to(&mut wave, note_on);
         wave
     }
 
+    /// makes the key, the envelopes and the mpe controllers of the note available to the modulation matrix
+    fn set_voice_sources(
+        &self,
+        note_on: ClockTick,
+        key: f32,
+        envelope: &[f32],
+        alt: &[f32],
+        expression: Option<&NoteExpression>,
+    ) {
+        let specific = |kind| GenId::Specific {
+            track_id: self.track_id,
+            kind,
+        };
+        let mut manager = GENRATOR_MANAGER.write().unwrap();
+        manager
+            .set_const(specific(Specific::Key), key.clamp(0.0, 1.0))
+            .expect("invalid key id in synthesizer");
+        manager
+            .set_rendered(specific(Specific::MainEnvelope), note_on, envelope.to_vec())
+            .expect("invalid envelope id in synthesizer");
+        manager
+            .set_rendered(specific(Specific::AltEnvelope), note_on, alt.to_vec())
+            .expect("invalid envelope id in synthesizer");
+
+        let expression = expression.cloned().unwrap_or_default();
+        let len = envelope.len();
+        for (kind, values) in [
+            (Specific::NoteBend, expression.bend_vec(note_on, len)),
+            (Specific::Timbre, expression.timbre_vec(note_on, len)),
+            (Specific::Pressure, expression.pressure_vec(note_on, len)),
+        ] {
+            manager
+                .set_rendered(specific(kind), note_on, values)
+                .expect("invalid expression id in synthesizer");
+        }
+    }
+
+    /// the synthesizer with the modulation matrix resolved into its receivers
+    fn patched(&self) -> Cow<'_, Self> {
+        if self.mod_matrix.is_empty() {
+            return Cow::Borrowed(self);
+        }
+        let mut synth = self.clone();
+        let mut destinations: Vec<ModDestination> = Vec::new();
+        for modulation in &self.mod_matrix {
+            if !destinations.contains(&modulation.destination) {
+                destinations.push(modulation.destination);
+            }
+        }
+        for destination in destinations {
+            let sources = self
+                .mod_matrix
+                .iter()
+                .filter(|modulation| modulation.destination == destination)
+                .map(|modulation| {
+                    (
+                        modulation.amount,
+                        modulation.network(self.track_id, (self.lfo_1, self.lfo_2)),
+                    )
+                })
+                .collect();
+            let receiver = match destination {
+                ModDestination::Volume => Some(&mut synth.volume_receiver),
+                ModDestination::Pitch => Some(&mut synth.pitch_receiver),
+                ModDestination::FilterCutoff
+                | ModDestination::FilterResonance
+                | ModDestination::FilterDrive
+                | ModDestination::FilterEnvelope => synth
+                    .filter
+                    .as_mut()
+                    .and_then(|filter| filter.receiver_mut(destination)),
+                _ => synth.oscillators.receiver_mut(destination),
+            };
+            if let Some(receiver) = receiver {
+                receiver.modulate(sources);
+            }
+        }
+        Cow::Owned(synth)
+    }
+
     pub fn play_note(&self, note: midi::Note) -> Wave {
-        self.play_freq(note.on, note.off, note.pitch.get_freq(), note.velocity)
+        match note.pitch.get_freq() {
+            Some(freq) => self.patched().play_freq(
+                note.on,
+                note.off,
+                freq,
+                note.pitch.get() as f32 / 127.0,
+                note.velocity,
+                note.expression.as_deref(),
+            ),
+            None => Wave::new(),
+        }
     }
 
     pub fn play_notes(&self, notes: &[midi::Note]) -> Wave {
+        let synth = self.patched();
         let mut wave = Wave::new();
         // TODO think about how to handle if notes only start at some timestamp
         for note in notes {
-            let sound = self.play_note(*note);
+            let Some(freq) = note.pitch.get_freq() else {
+                continue;
+            };
+            let sound = synth.play_freq(
+                note.on,
+                note.off,
+                freq,
+                note.pitch.get() as f32 / 127.0,
+                note.velocity,
+                note.expression.as_deref(),
+            );
             wave.add(&sound, TIME_MANAGER.read().unwrap().tick_to_sample(note.on));
         }
         wave
@@ -92,10 +231,13 @@ impl Synthesizer {
     pub fn play_test_chord(&self) -> Wave {
         let note_on = TIME_MANAGER.read().unwrap().abs_start();
         let note_off = TIME_MANAGER.read().unwrap().second_to_tick(6.0);
-        let mut wave = self.play_freq(note_on, note_off, 300.0, 0.7);
-        wave.add(&self.play_freq(note_on, note_off, 375.0, 0.7), 0);
-        wave.add(&self.play_freq(note_on, note_off, 450.0, 0.7), 0);
-        wave.add(&self.play_freq(note_on, note_off, 600.0, 0.7), 0);
+        let synth = self.patched();
+        let mut wave = Wave::new();
+        for freq in [300.0, 375.0, 450.0, 600.0] {
+            // the chord isn't tuned to keys, the closest equal tempered ones are used
+            let key = (69.0 + 12.0 * (freq / 440.0f32).log2()) / 127.0;
+            wave.add(&synth.play_freq(note_on, note_off, freq, key, 0.7, None), 0);
+        }
         wave
     }
 
@@ -106,6 +248,15 @@ impl Synthesizer {
     }
 
     pub fn extract(&self) -> SynthBuilder {
+        let mut instr_generator: GenSaveBuilder = GENRATOR_MANAGER
+            .read()
+            .unwrap()
+            .get_instr_save(self.track_id)
+            .expect("synthesizer had invalid generator save")
+            .into();
+        for oscillator in instr_generator.lfo_oscillators_mut() {
+            *oscillator = oscillator.extract();
+        }
         SynthBuilder {
             name: self.name.clone(),
             effects: self.effects.extract(),
@@ -122,12 +273,12 @@ impl Synthesizer {
             lfo_2: self.lfo_2.extract().expect("synthesizer had invalid GenId"),
             pitch_receiver: self.pitch_receiver.extract(),
             volume_receiver: self.volume_receiver.extract(),
-            instr_generator: GENRATOR_MANAGER
-                .read()
-                .unwrap()
-                .get_instr_save(self.track_id)
-                .expect("synthesizer had invalid generator save")
-                .into(),
+            filter: self
+                .filter
+                .as_ref()
+                .map(|filter| Box::new(filter.extract())),
+            mod_matrix: self.mod_matrix.clone(),
+            instr_generator,
         }
     }
 
@@ -138,10 +289,7 @@ impl Synthesizer {
 
 impl Synthesizer {
     pub fn save_to(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn std::error::Error>> {
-        let data = self.extract();
-        let file = File::create(path)?;
-        ron::ser::to_writer_pretty(file, &data, Default::default())?;
-        Ok(())
+        InstrumentBuilder::Synthesizer(self.extract()).save_to(path)
     }
 }
 
@@ -157,6 +305,11 @@ pub struct SynthBuilder {
     pub pitch_receiver: Receiver,
     pub volume_receiver: Receiver,
     pub instr_generator: GenSaveBuilder,
+    /// the cutoff is modulated by `alt_enevelope`
+    #[serde(default)]
+    pub filter: Option<Box<Filter>>,
+    #[serde(default)]
+    pub mod_matrix: Vec<Modulation>,
 }
 
 impl SynthBuilder {
@@ -206,13 +359,187 @@ impl SynthBuilder {
             pitch_receiver: PITCH_RECEIVER,
             volume_receiver: vol_receiver,
             instr_generator,
+            filter: None,
+            mod_matrix: Vec::new(),
         }
     }
 }
 
 impl SynthBuilder {
+    pub(crate) fn build(self, track_id: u8) -> Result<Synthesizer, Box<dyn std::error::Error>> {
+        let mut effects = self.effects;
+        effects.set_id(track_id);
+
+        let mut oscillators = self.oscillators;
+        oscillators.set_id(track_id);
+
+        let mut instr_generator = self.instr_generator;
+        for oscillator in oscillators
+            .oscillators_mut()
+            .iter_mut()
+            .chain(instr_generator.lfo_oscillators_mut())
+        {
+            oscillator.load_wavetable()?;
+        }
+
+        let mut main_enevelope = self.main_enevelope;
+        main_enevelope.set_id(track_id);
+
+        let mut alt_enevelope = self.alt_enevelope;
+        alt_enevelope.set_id(track_id);
+
+        let mut lfo_1 = self.lfo_1;
+        lfo_1.set_id(track_id);
+
+        let mut lfo_2 = self.lfo_2;
+        lfo_2.set_id(track_id);
+
+        let mut pitch_receiver = self.pitch_receiver;
+        pitch_receiver.set_id(track_id);
+
+        let mut volume_receiver = self.volume_receiver;
+        volume_receiver.set_id(track_id);
+
+        let mut filter = self.filter;
+        if let Some(filter) = &mut filter {
+            filter.set_id(track_id);
+        }
+
+        *GENRATOR_MANAGER
+            .write()
+            .unwrap()
+            .get_mut_instr_save(track_id)
+            .unwrap() = instr_generator.as_generator_save(track_id, TI::Instr);
+
+        Ok(Synthesizer {
+            name: self.name,
+            track_id,
+            effects,
+            oscillators,
+            main_enevelope,
+            alt_enevelope,
+            lfo_1,
+            lfo_2,
+            pitch_receiver,
+            volume_receiver,
+            filter,
+            mod_matrix: self.mod_matrix,
+        })
+    }
+
+    /// fails with `Error::Value` if the destination isn't part of the synthesizer,
+    /// the filter has to be set and the oscillator slots added before they are modulated
+    pub fn add_modulation(&mut self, modulation: Modulation) -> Result<(), Error> {
+        let exists = match modulation.destination {
+            ModDestination::Volume | ModDestination::Pitch => true,
+            ModDestination::FilterCutoff
+            | ModDestination::FilterResonance
+            | ModDestination::FilterDrive
+            | ModDestination::FilterEnvelope => self.filter.is_some(),
+            destination => self.oscillators.has_destination(destination),
+        };
+        if !exists {
+            return Err(Error::Value);
+        }
+        self.mod_matrix.push(modulation);
+        Ok(())
+    }
+
     pub fn from_path(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
-        let rdr = File::open(path)?;
-        Ok(ron::de::from_reader(rdr)?)
+        match InstrumentBuilder::load(path)? {
+            InstrumentBuilder::Synthesizer(synth) => Ok(synth),
+            _ => Err(Error::Type)?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::network::Curve;
+
+    #[test]
+    fn key_modulates_from_the_note() {
+        let track_id = 42;
+        GENRATOR_MANAGER
+            .write()
+            .unwrap()
+            .new_track(track_id)
+            .unwrap();
+        let mut builder = SynthBuilder::new("keyed");
+        builder
+            .add_modulation(
+                Modulation::new(ModSource::Key, ModDestination::Volume, -1.0, Curve::Linear)
+                    .unwrap(),
+            )
+            .unwrap();
+        let synth = builder.build(track_id).unwrap();
+
+        let low = synth.play_note(midi::note(20, 0, 1000, 1.0));
+        let key = GENRATOR_MANAGER
+            .read()
+            .unwrap()
+            .get_val(
+                GenId::Specific {
+                    track_id,
+                    kind: Specific::Key,
+                },
+                ClockTick::abs_zero(),
+            )
+            .unwrap();
+        assert_eq!(key, 20.0 / 127.0);
+
+        // the higher key turns the volume further down
+        let high = synth.play_note(midi::note(100, 0, 1000, 1.0));
+        let peak = |wave: &Wave| {
+            wave.right()
+                .iter()
+                .fold(0.0, |max: f32, x| max.max(x.abs()))
+        };
+        assert!(peak(&high) < peak(&low));
+    }
+
+    #[test]
+    fn modulations_need_their_destination() {
+        let mut builder = SynthBuilder::new("plain");
+        let modulation = |destination| {
+            Modulation::new(ModSource::Lfo1, destination, 0.5, Curve::Linear).unwrap()
+        };
+        assert!(builder
+            .add_modulation(modulation(ModDestination::FilterCutoff))
+            .is_err());
+        assert!(builder
+            .add_modulation(modulation(ModDestination::OscTilt(0)))
+            .is_err());
+        assert!(builder
+            .add_modulation(modulation(ModDestination::OscPitch(5)))
+            .is_err());
+        assert!(builder
+            .add_modulation(modulation(ModDestination::CrossModDepth(0)))
+            .is_err());
+        assert!(builder
+            .add_modulation(modulation(ModDestination::OscPitch(0)))
+            .is_ok());
+        assert_eq!(builder.mod_matrix.len(), 1);
+    }
+
+    #[test]
+    fn songs_keep_their_old_synthesizers() {
+        let track_id = 43;
+        GENRATOR_MANAGER
+            .write()
+            .unwrap()
+            .new_track(track_id)
+            .unwrap();
+        let synth = SynthBuilder::new("old").build(track_id).unwrap();
+        // synthesizers used to be saved without a filter and a modulation matrix
+        let text = ron::to_string(&synth)
+            .unwrap()
+            .replace(",filter:None", "")
+            .replace(",mod_matrix:[]", "");
+        assert!(!text.contains("mod_matrix"));
+        let loaded: Synthesizer = ron::from_str(&text).unwrap();
+        assert!(loaded.filter.is_none());
+        assert!(loaded.mod_matrix.is_empty());
     }
 }
@@ -0,0 +1,252 @@
+use std::f32::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    globals::SAMPLE_RATE,
+    network::{Receiver, Transform},
+    time::ClockTick,
+    utils,
+    wave::Wave,
+    Error,
+};
+
+use super::mod_matrix::ModDestination;
+
+/// in Hz
+const CUTOFF_RECEIVER: Receiver = Receiver::new(20000.0, (20.0, 20000.0), Transform::Linear);
+const RESONANCE_RECEIVER: Receiver = Receiver::new(0.0, (0.0, 1.0), Transform::Linear);
+const DRIVE_RECEIVER: Receiver = Receiver::new(0.0, (0.0, 24.0), Transform::Decibel);
+/// in cents at the peak of the envelope
+const ENVELOPE_RECEIVER: Receiver = Receiver::new(0.0, (-9600.0, 9600.0), Transform::Linear);
+
+/// the note at which key tracking leaves the cutoff unchanged, C4
+const KEY_TRACKING_CENTER: f32 = 261.6256;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FilterMode {
+    #[default]
+    LowPass,
+    HighPass,
+    BandPass,
+    Notch,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Slope {
+    #[default]
+    Db12,
+    /// two 12 dB stages in series
+    Db24,
+}
+
+/// state variable filter applied to every voice of a synthesizer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Filter {
+    pub mode: FilterMode,
+    pub slope: Slope,
+    pub cutoff: Receiver,
+    pub resonance: Receiver,
+    /// gain into a soft clipper before the filter, at 0 dB the clipper is left out
+    pub drive: Receiver,
+    /// how far the cutoff follows the note, 1.0 moves it by an octave per octave
+    pub key_tracking: f32,
+    /// how far the alt envelope moves the cutoff
+    pub envelope: Receiver,
+}
+
+impl Filter {
+    pub fn new(mode: FilterMode, slope: Slope, cutoff: f32, resonance: f32) -> Result<Self, Error> {
+        Ok(Self {
+            mode,
+            slope,
+            cutoff: CUTOFF_RECEIVER.csv(cutoff)?,
+            resonance: RESONANCE_RECEIVER.csv(resonance)?,
+            drive: DRIVE_RECEIVER,
+            key_tracking: 0.0,
+            envelope: ENVELOPE_RECEIVER,
+        })
+    }
+
+    pub fn set_drive(&mut self, drive_db: f32) -> Result<(), Error> {
+        self.drive = DRIVE_RECEIVER.csv(drive_db)?;
+        Ok(())
+    }
+
+    pub fn set_key_tracking(&mut self, key_tracking: f32) -> Result<(), Error> {
+        if !(0.0..=1.0).contains(&key_tracking) {
+            return Err(Error::Value);
+        }
+        self.key_tracking = key_tracking;
+        Ok(())
+    }
+
+    pub fn set_envelope_amount(&mut self, cents: f32) -> Result<(), Error> {
+        self.envelope = ENVELOPE_RECEIVER.csv(cents)?;
+        Ok(())
+    }
+
+    /// filters a voice playing at `freq`, `envelope` is the alt envelope of the note
+    pub fn apply(&self, wave: &mut Wave, note_on: ClockTick, freq: f32, envelope: &[f32]) {
+        let len = wave.len();
+        let cutoff = self.cutoff.get_vec(note_on, len);
+        let resonance = self.resonance.get_vec(note_on, len);
+        let drive = self.drive.get_vec(note_on, len);
+        let amount = self.envelope.get_vec(note_on, len);
+        let tracking = self.key_tracking * (freq / KEY_TRACKING_CENTER).log2();
+
+        let coefficients: Vec<Coefficients> = (0..len)
+            .map(|i| {
+                let env = envelope.get(i).copied().unwrap_or(0.0);
+                let cutoff = cutoff[i] * utils::fast_pow2(tracking + env * amount[i] / 1200.0);
+                Coefficients::new(cutoff, resonance[i])
+            })
+            .collect();
+
+        let mut right = wave.right().to_vec();
+        let mut left = wave.left().to_vec();
+        for channel in [&mut right, &mut left] {
+            let mut stages = vec![Svf::default(); self.stages()];
+            for (i, x) in channel.iter_mut().enumerate() {
+                let mut y = if drive[i] > 1.0 {
+                    (*x * drive[i]).tanh()
+                } else {
+                    *x
+                };
+                for stage in &mut stages {
+                    y = stage.process(y, &coefficients[i], self.mode);
+                }
+                *x = y;
+            }
+        }
+        *wave = Wave::from_vecs(right, left);
+    }
+
+    pub(crate) fn receiver_mut(&mut self, destination: ModDestination) -> Option<&mut Receiver> {
+        match destination {
+            ModDestination::FilterCutoff => Some(&mut self.cutoff),
+            ModDestination::FilterResonance => Some(&mut self.resonance),
+            ModDestination::FilterDrive => Some(&mut self.drive),
+            ModDestination::FilterEnvelope => Some(&mut self.envelope),
+            _ => None,
+        }
+    }
+
+    fn stages(&self) -> usize {
+        match self.slope {
+            Slope::Db12 => 1,
+            Slope::Db24 => 2,
+        }
+    }
+
+    pub fn extract(&self) -> Self {
+        Self {
+            mode: self.mode,
+            slope: self.slope,
+            cutoff: self.cutoff.extract(),
+            resonance: self.resonance.extract(),
+            drive: self.drive.extract(),
+            key_tracking: self.key_tracking,
+            envelope: self.envelope.extract(),
+        }
+    }
+
+    pub fn set_id(&mut self, track_id: u8) {
+        self.cutoff.set_id(track_id);
+        self.resonance.set_id(track_id);
+        self.drive.set_id(track_id);
+        self.envelope.set_id(track_id);
+    }
+}
+
+struct Coefficients {
+    k: f32,
+    a1: f32,
+    a2: f32,
+    a3: f32,
+}
+
+impl Coefficients {
+    fn new(cutoff: f32, resonance: f32) -> Self {
+        let cutoff = cutoff.clamp(20.0, 0.49 * SAMPLE_RATE as f32);
+        let g = (PI * cutoff / SAMPLE_RATE as f32).tan();
+        // damping, self oscillation is avoided by keeping it above zero
+        let k = 2.0 - 1.98 * resonance.clamp(0.0, 1.0);
+        let a1 = 1.0 / (1.0 + g * (g + k));
+        let a2 = g * a1;
+        Self {
+            k,
+            a1,
+            a2,
+            a3: g * a2,
+        }
+    }
+}
+
+/// trapezoidal integrated state variable filter after Andrew Simper
+#[derive(Debug, Clone, Default)]
+struct Svf {
+    ic1eq: f32,
+    ic2eq: f32,
+}
+
+impl Svf {
+    fn process(&mut self, x: f32, c: &Coefficients, mode: FilterMode) -> f32 {
+        let v3 = x - self.ic2eq;
+        let v1 = c.a1 * self.ic1eq + c.a2 * v3;
+        let v2 = self.ic2eq + c.a2 * self.ic1eq + c.a3 * v3;
+        self.ic1eq = 2.0 * v1 - self.ic1eq;
+        self.ic2eq = 2.0 * v2 - self.ic2eq;
+
+        let low = v2;
+        let high = x - c.k * v1 - v2;
+        match mode {
+            FilterMode::LowPass => low,
+            FilterMode::HighPass => high,
+            FilterMode::BandPass => v1,
+            FilterMode::Notch => low + high,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn low_pass_removes_highs() {
+        let len = SAMPLE_RATE / 10;
+        let tone = |freq: f32| {
+            Wave::from_vec(
+                (0..len)
+                    .map(|i| (2.0 * PI * freq * i as f32 / SAMPLE_RATE as f32).sin() * 0.5)
+                    .collect(),
+            )
+        };
+        let filter = Filter::new(FilterMode::LowPass, Slope::Db24, 500.0, 0.0).unwrap();
+        let mut low = tone(100.0);
+        let mut high = tone(8000.0);
+        filter.apply(&mut low, ClockTick::abs_zero(), 261.6256, &[]);
+        filter.apply(&mut high, ClockTick::abs_zero(), 261.6256, &[]);
+        assert!(low.rms() > 0.3);
+        assert!(high.rms() < 0.01);
+
+        let filter = Filter::new(FilterMode::HighPass, Slope::Db12, 500.0, 0.0).unwrap();
+        let mut low = tone(100.0);
+        filter.apply(&mut low, ClockTick::abs_zero(), 261.6256, &[]);
+        assert!(low.rms() < 0.05);
+    }
+
+    #[test]
+    fn drive_only_clips_when_turned_up() {
+        let mut filter = Filter::new(FilterMode::LowPass, Slope::Db12, 20000.0, 0.0).unwrap();
+        let mut clean = Wave::from_vec(vec![0.5; 1000]);
+        filter.apply(&mut clean, ClockTick::abs_zero(), 261.6256, &[]);
+        assert!((clean.right()[999] - 0.5).abs() < 1e-3);
+
+        filter.set_drive(12.0).unwrap();
+        let mut driven = Wave::from_vec(vec![0.5; 1000]);
+        filter.apply(&mut driven, ClockTick::abs_zero(), 261.6256, &[]);
+        assert!((driven.right()[999] - (0.5 * utils::db_to_factor(12.0)).tanh()).abs() < 1e-3);
+    }
+}
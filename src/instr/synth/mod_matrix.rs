@@ -0,0 +1,93 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    gens::{GenId, Specific},
+    network::{Curve, Network},
+    Error,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ModSource {
+    // per voice
+    MainEnvelope,
+    AltEnvelope,
+    Velocity,
+    Key,
+    /// mpe controllers, resting at their center when the note has none
+    NoteBend,
+    Timbre,
+    Pressure,
+    // per track
+    Lfo1,
+    Lfo2,
+    ModWheel,
+    PitchBend,
+}
+
+/// the parameters of a synthesizer a modulation can be routed to, oscillators are given by their slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ModDestination {
+    Volume,
+    Pitch,
+    OscWeight(usize),
+    OscModulation(usize),
+    OscPitch(usize),
+    OscDetune(usize),
+    OscSpread(usize),
+    /// only additive oscillators
+    OscTilt(usize),
+    OscBalance(usize),
+    CrossModDepth(usize),
+    FilterCutoff,
+    FilterResonance,
+    FilterDrive,
+    FilterEnvelope,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Modulation {
+    pub source: ModSource,
+    pub destination: ModDestination,
+    /// share of the destination's range covered by the source, from -1.0 to 1.0
+    pub amount: f32,
+    #[serde(default)]
+    pub curve: Curve,
+}
+
+impl Modulation {
+    pub fn new(
+        source: ModSource,
+        destination: ModDestination,
+        amount: f32,
+        curve: Curve,
+    ) -> Result<Self, Error> {
+        if !(-1.0..=1.0).contains(&amount) {
+            return Err(Error::Value);
+        }
+        Ok(Self {
+            source,
+            destination,
+            amount,
+            curve,
+        })
+    }
+
+    /// the weighted source, `lfos` are the bound ids of the synthesizer's lfos
+    pub(crate) fn network(&self, track_id: u8, lfos: (GenId, GenId)) -> Network {
+        let specific = |kind| GenId::Specific { track_id, kind };
+        let id = match self.source {
+            ModSource::MainEnvelope => specific(Specific::MainEnvelope),
+            ModSource::AltEnvelope => specific(Specific::AltEnvelope),
+            ModSource::Velocity => specific(Specific::Vel),
+            ModSource::Key => specific(Specific::Key),
+            ModSource::NoteBend => specific(Specific::NoteBend),
+            ModSource::Timbre => specific(Specific::Timbre),
+            ModSource::Pressure => specific(Specific::Pressure),
+            ModSource::Lfo1 => lfos.0,
+            ModSource::Lfo2 => lfos.1,
+            ModSource::ModWheel => specific(Specific::ModW),
+            ModSource::PitchBend => specific(Specific::Pitch),
+        };
+        Network::Curve(self.curve, Box::new(Network::Leaf(id)))
+    }
+}
@@ -1,21 +1,65 @@
-use std::vec;
+use std::{f32::consts::TAU, vec};
 
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    globals::TIME_MANAGER,
     network::{self, Receiver, Transform},
     receivers::VOL_RECEIVER,
     time::ClockTick,
-    utils,
-    utils::oscs::Oscillator,
+    utils::{
+        self,
+        oscs::{OscState, Oscillator},
+        Rng,
+    },
     wave::Wave,
     Error,
 };
 
-use super::PITCH_RECEIVER;
+use super::{mod_matrix::ModDestination, PITCH_RECEIVER};
 
 const MODULATION_RECEIVER: Receiver = Receiver::new(0.5, (0.0, 1.0), Transform::Linear);
 const PITCH_OFFSET_RECEIVER: Receiver = Receiver::new(0.0, (-4800.0, 4800.0), Transform::Linear);
+const DETUNE_RECEIVER: Receiver = Receiver::new(0.0, (0.0, 100.0), Transform::Linear);
+const SPREAD_RECEIVER: Receiver = Receiver::new(0.0, (0.0, 1.0), Transform::Linear);
+
+/// in dB per octave
+const TILT_RECEIVER: Receiver = Receiver::new(0.0, (-24.0, 24.0), Transform::Linear);
+const BALANCE_RECEIVER: Receiver = Receiver::new(0.0, (-1.0, 1.0), Transform::Linear);
+const CROSS_MOD_RECEIVER: Receiver = Receiver::new(0.5, (0.0, 1.0), Transform::Linear);
+
+pub const MAX_UNISON: u8 = 16;
+
+/// frequency deviation at full cross-modulation depth, relative to the carrier frequency
+const FM_DEPTH: f32 = 4.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CrossModKind {
+    /// restarts the cycle whenever the source starts a new one, the depth sets how far
+    HardSync,
+    /// multiplies with the source, the depth fades between dry and fully ring modulated
+    Ring,
+    /// linear through-zero frequency modulation
+    Fm,
+}
+
+/// modulation of an oscillator by the output of an oscillator in an earlier slot
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CrossMod {
+    pub kind: CrossModKind,
+    pub source: usize,
+    pub depth: Receiver,
+}
+
+impl CrossMod {
+    fn extract(&self) -> Self {
+        Self {
+            kind: self.kind,
+            source: self.source,
+            depth: self.depth.extract(),
+        }
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OscPanel {
@@ -23,6 +67,23 @@ pub struct OscPanel {
     modulation: Vec<Receiver>,
     weights: Vec<Receiver>,
     pitch_offsets: Vec<Receiver>,
+    /// voices per oscillator
+    #[serde(default)]
+    unison: Vec<u8>,
+    /// how far in cents the outermost unison voices are detuned
+    #[serde(default)]
+    detune: Vec<Receiver>,
+    /// how far the unison voices are panned apart
+    #[serde(default)]
+    spread: Vec<Receiver>,
+    #[serde(default)]
+    cross_mod: Vec<Option<CrossMod>>,
+    /// spectral tilt of additive oscillators
+    #[serde(default)]
+    tilt: Vec<Receiver>,
+    /// odd to even partials of additive oscillators
+    #[serde(default)]
+    balance: Vec<Receiver>,
 }
 
 impl OscPanel {
@@ -37,12 +98,18 @@ impl OscPanel {
             oscillators,
             weights: network::vec_or_none(weights, len, VOL_RECEIVER)?,
             modulation: network::vec_or_none(modulation, len, MODULATION_RECEIVER)?,
+            unison: vec![1; len],
+            detune: vec![DETUNE_RECEIVER; len],
+            spread: vec![SPREAD_RECEIVER; len],
+            cross_mod: vec![None; len],
+            tilt: vec![TILT_RECEIVER; len],
+            balance: vec![BALANCE_RECEIVER; len],
         })
     }
 
     pub fn extract(&self) -> Self {
         Self {
-            oscillators: self.oscillators.clone(),
+            oscillators: self.oscillators.iter().map(Oscillator::extract).collect(),
             modulation: self
                 .modulation
                 .iter()
@@ -58,9 +125,39 @@ impl OscPanel {
                 .iter()
                 .map(|receiver| receiver.extract())
                 .collect(),
+            unison: self.unison.clone(),
+            detune: self
+                .detune
+                .iter()
+                .map(|receiver| receiver.extract())
+                .collect(),
+            spread: self
+                .spread
+                .iter()
+                .map(|receiver| receiver.extract())
+                .collect(),
+            cross_mod: self
+                .cross_mod
+                .iter()
+                .map(|cross| cross.as_ref().map(CrossMod::extract))
+                .collect(),
+            tilt: self
+                .tilt
+                .iter()
+                .map(|receiver| receiver.extract())
+                .collect(),
+            balance: self
+                .balance
+                .iter()
+                .map(|receiver| receiver.extract())
+                .collect(),
         }
     }
 
+    pub(crate) fn oscillators_mut(&mut self) -> &mut [Oscillator] {
+        &mut self.oscillators
+    }
+
     pub fn set_id(&mut self, track_id: u8) {
         self.modulation
             .iter_mut()
@@ -71,6 +168,22 @@ impl OscPanel {
         self.pitch_offsets
             .iter_mut()
             .for_each(|receiver| receiver.set_id(track_id));
+        self.detune
+            .iter_mut()
+            .for_each(|receiver| receiver.set_id(track_id));
+        self.spread
+            .iter_mut()
+            .for_each(|receiver| receiver.set_id(track_id));
+        self.cross_mod
+            .iter_mut()
+            .flatten()
+            .for_each(|cross| cross.depth.set_id(track_id));
+        self.tilt
+            .iter_mut()
+            .for_each(|receiver| receiver.set_id(track_id));
+        self.balance
+            .iter_mut()
+            .for_each(|receiver| receiver.set_id(track_id));
     }
 }
 
@@ -81,45 +194,293 @@ impl Default for OscPanel {
             weights: vec![VOL_RECEIVER],
             pitch_offsets: vec![PITCH_OFFSET_RECEIVER],
             modulation: vec![MODULATION_RECEIVER],
+            unison: vec![1],
+            detune: vec![DETUNE_RECEIVER],
+            spread: vec![SPREAD_RECEIVER],
+            cross_mod: vec![None],
+            tilt: vec![TILT_RECEIVER],
+            balance: vec![BALANCE_RECEIVER],
         }
     }
 }
 
 impl OscPanel {
     pub fn play(&self, freq: f32, cent_offsets: &[f32], start: ClockTick, samples: usize) -> Wave {
-        let mut wave = vec![0.0; samples];
+        let mut right = vec![0.0; samples];
+        let mut left = vec![0.0; samples];
+        // every note gets its own noise and start phases
+        let seed = TIME_MANAGER.read().unwrap().tick_to_sample(start) as u64;
+        // unweighted output and the cycle starts of every voice of every slot,
+        // used as cross-modulation sources
+        let mut outputs: Vec<Vec<f32>> = Vec::with_capacity(self.oscillators.len());
+        let mut cycles: Vec<Vec<Vec<bool>>> = Vec::with_capacity(self.oscillators.len());
 
-        for (((osc, weigth), modulation), offset) in self
+        for (i, (((osc, weigth), modulation), offset)) in self
             .oscillators
             .iter()
             .zip(&self.weights)
             .zip(&self.modulation)
             .zip(&self.pitch_offsets)
+            .enumerate()
         {
-            // TODO
-            let freq: Vec<f32> = offset
-                .get_vec(start, samples)
-                .into_iter()
-                .zip(cent_offsets)
-                .map(|(x, y)| freq * utils::fast_pow2((x + y) / 1200.0))
-                .collect();
-
+            let offsets = offset.get_vec(start, samples);
             let modulation = modulation.get_vec(start, samples);
-            let new_wave: Vec<f32> = osc
-                .play(&freq, &modulation, samples)
-                .into_iter()
-                .zip(weigth.get_vec(start, samples))
-                .map(|(x, y)| x * y)
+            let weights = weigth.get_vec(start, samples);
+
+            let voices = self.unison.get(i).copied().unwrap_or(1).max(1);
+            let detune = match self.detune.get(i) {
+                Some(receiver) => receiver.get_vec(start, samples),
+                None => vec![0.0; samples],
+            };
+            let spread = match self.spread.get(i) {
+                Some(receiver) => receiver.get_vec(start, samples),
+                None => vec![0.0; samples],
+            };
+            let cross = self
+                .cross_mod
+                .get(i)
+                .and_then(|cross| cross.as_ref())
+                .filter(|cross| cross.source < i)
+                .map(|cross| {
+                    (
+                        cross.kind,
+                        &outputs[cross.source],
+                        &cycles[cross.source],
+                        cross.depth.get_vec(start, samples),
+                    )
+                });
+            let shape = match (osc, self.tilt.get(i), self.balance.get(i)) {
+                (Oscillator::Additive(_), Some(tilt), Some(balance)) => Some((
+                    tilt.get_vec(start, samples),
+                    balance.get_vec(start, samples),
+                )),
+                _ => None,
+            };
+            let center: Vec<f32> = (0..samples)
+                .map(|k| freq * utils::fast_pow2((offsets[k] + cent_offsets[k]) / 1200.0))
                 .collect();
 
-            utils::add_elementwise(&mut wave, &new_wave)
+            let gain = 1.0 / (voices as f32).sqrt();
+            let mut rng = Rng::new(seed << 4 | i as u64);
+            let mut mono = vec![0.0; samples];
+            let mut wraps = Vec::with_capacity(voices as usize);
+
+            for voice in 0..voices {
+                // from -1.0 to 1.0 across the voices
+                let position = if voices == 1 {
+                    0.0
+                } else {
+                    2.0 * voice as f32 / (voices - 1) as f32 - 1.0
+                };
+                let phase = if voices == 1 {
+                    0.0
+                } else {
+                    rng.next_f32() * TAU
+                };
+                let mut state = OscState::new(osc.clone(), phase, rng.next_u64());
+                let mut wrapped = vec![false; samples];
+
+                for k in 0..samples {
+                    let mut freq = center[k] * utils::fast_pow2(position * detune[k] / 1200.0);
+                    match &cross {
+                        Some((CrossModKind::Fm, source, _, depth)) => {
+                            freq *= 1.0 + FM_DEPTH * depth[k] * source[k]
+                        }
+                        // every voice follows a voice of the source, as rendered with its fm
+                        Some((CrossModKind::HardSync, _, resets, depth))
+                            if resets[voice as usize % resets.len()][k] =>
+                        {
+                            state.sync(depth[k])
+                        }
+                        _ => {}
+                    }
+                    if let Some((tilt, balance)) = &shape {
+                        state.set_shape(tilt[k], balance[k]);
+                    }
+                    let mut x = state.next(freq, modulation[k]) * gain;
+                    wrapped[k] = state.wrapped();
+                    if let Some((CrossModKind::Ring, source, _, depth)) = &cross {
+                        x *= 1.0 - depth[k] + depth[k] * source[k];
+                    }
+                    mono[k] += x;
+
+                    let x = x * weights[k];
+                    if position == 0.0 || spread[k] == 0.0 {
+                        right[k] += x;
+                        left[k] += x;
+                    } else {
+                        // equal power panning as in `Wave::pan`
+                        let angle = (position * spread[k] + 1.0) * std::f32::consts::FRAC_PI_4;
+                        right[k] += x * angle.sin() * std::f32::consts::SQRT_2;
+                        left[k] += x * angle.cos() * std::f32::consts::SQRT_2;
+                    }
+                }
+                wraps.push(wrapped);
+            }
+            outputs.push(mono);
+            cycles.push(wraps);
+        }
+        Wave::from_vecs(right, left)
+    }
+
+    /// lets the oscillator at `source` modulate the one at `index`, only earlier slots can be sources
+    pub fn set_cross_mod(
+        &mut self,
+        index: usize,
+        kind: CrossModKind,
+        source: usize,
+        depth: f32,
+    ) -> Result<(), Error> {
+        if index >= self.oscillators.len() || source >= index {
+            return Err(Error::Value);
+        }
+        self.cross_mod.resize(self.oscillators.len(), None);
+        self.cross_mod[index] = Some(CrossMod {
+            kind,
+            source,
+            depth: CROSS_MOD_RECEIVER.csv(depth)?,
+        });
+        Ok(())
+    }
+
+    pub fn remove_cross_mod(&mut self, index: usize) {
+        if let Some(cross) = self.cross_mod.get_mut(index) {
+            *cross = None;
+        }
+    }
+
+    /// spreads the oscillator at `index` over `voices` detuned and panned voices
+    pub fn set_unison(
+        &mut self,
+        index: usize,
+        voices: u8,
+        detune: f32,
+        spread: f32,
+    ) -> Result<(), Error> {
+        if index >= self.oscillators.len() || voices == 0 || voices > MAX_UNISON {
+            return Err(Error::Value);
+        }
+        let len = self.oscillators.len();
+        self.unison.resize(len, 1);
+        self.detune.resize(len, DETUNE_RECEIVER);
+        self.spread.resize(len, SPREAD_RECEIVER);
+        self.unison[index] = voices;
+        self.detune[index] = DETUNE_RECEIVER.csv(detune)?;
+        self.spread[index] = SPREAD_RECEIVER.csv(spread)?;
+        Ok(())
+    }
+
+    pub(crate) fn receiver_mut(&mut self, destination: ModDestination) -> Option<&mut Receiver> {
+        match destination {
+            ModDestination::OscWeight(i) => self.weights.get_mut(i),
+            ModDestination::OscModulation(i) => self.modulation.get_mut(i),
+            ModDestination::OscPitch(i) => self.pitch_offsets.get_mut(i),
+            ModDestination::OscDetune(i) => self.detune.get_mut(i),
+            ModDestination::OscSpread(i) => self.spread.get_mut(i),
+            ModDestination::OscTilt(i) => self.tilt.get_mut(i),
+            ModDestination::OscBalance(i) => self.balance.get_mut(i),
+            ModDestination::CrossModDepth(i) => self
+                .cross_mod
+                .get_mut(i)
+                .and_then(|cross| cross.as_mut())
+                .map(|cross| &mut cross.depth),
+            _ => None,
+        }
+    }
+
+    /// if modulating the destination changes the sound, tilt and balance only shape additive slots
+    pub(crate) fn has_destination(&self, destination: ModDestination) -> bool {
+        match destination {
+            ModDestination::OscTilt(i) | ModDestination::OscBalance(i) => {
+                matches!(self.oscillators.get(i), Some(Oscillator::Additive(_)))
+            }
+            ModDestination::CrossModDepth(i) => matches!(self.cross_mod.get(i), Some(Some(_))),
+            ModDestination::OscWeight(i)
+            | ModDestination::OscModulation(i)
+            | ModDestination::OscPitch(i)
+            | ModDestination::OscDetune(i)
+            | ModDestination::OscSpread(i) => i < self.oscillators.len(),
+            _ => false,
         }
-        Wave::from_vec(wave)
     }
 
     pub fn add_osc(&mut self, oscillator: Oscillator) {
         self.oscillators.push(oscillator);
         self.pitch_offsets.push(PITCH_RECEIVER);
         self.weights.push(VOL_RECEIVER);
+        self.modulation.push(MODULATION_RECEIVER);
+        self.unison.push(1);
+        self.detune.push(DETUNE_RECEIVER);
+        self.spread.push(SPREAD_RECEIVER);
+        self.cross_mod.resize(self.oscillators.len(), None);
+        self.tilt.resize(self.oscillators.len(), TILT_RECEIVER);
+        self.balance
+            .resize(self.oscillators.len(), BALANCE_RECEIVER);
+    }
+
+    /// shapes the spectrum of the additive oscillator at `index`
+    pub fn set_spectrum(&mut self, index: usize, tilt: f32, balance: f32) -> Result<(), Error> {
+        if !matches!(self.oscillators.get(index), Some(Oscillator::Additive(_))) {
+            return Err(Error::Type);
+        }
+        let len = self.oscillators.len();
+        self.tilt.resize(len, TILT_RECEIVER);
+        self.balance.resize(len, BALANCE_RECEIVER);
+        self.tilt[index] = TILT_RECEIVER.csv(tilt)?;
+        self.balance[index] = BALANCE_RECEIVER.csv(balance)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unison_is_stereo() {
+        let mut panel = OscPanel::from_oscs(vec![Oscillator::Saw], None, None).unwrap();
+        let cents = vec![0.0; 4410];
+        let mono = panel.play(220.0, &cents, ClockTick::abs_zero(), 4410);
+        assert_eq!(mono.right(), mono.left());
+
+        panel.set_unison(0, 7, 25.0, 1.0).unwrap();
+        let wide = panel.play(220.0, &cents, ClockTick::abs_zero(), 4410);
+        assert_eq!(wide.len(), 4410);
+        assert_ne!(wide.right(), wide.left());
+        assert!(panel.set_unison(0, MAX_UNISON + 1, 0.0, 0.0).is_err());
+    }
+
+    #[test]
+    fn cross_modulation() {
+        let cents = vec![0.0; 4410];
+        let mut panel =
+            OscPanel::from_oscs(vec![Oscillator::Sine, Oscillator::Saw], None, None).unwrap();
+        let dry = panel.play(220.0, &cents, ClockTick::abs_zero(), 4410);
+        assert!(panel.set_cross_mod(0, CrossModKind::Ring, 1, 1.0).is_err());
+
+        for kind in [CrossModKind::HardSync, CrossModKind::Ring, CrossModKind::Fm] {
+            panel.set_cross_mod(1, kind, 0, 1.0).unwrap();
+            let wet = panel.play(220.0, &cents, ClockTick::abs_zero(), 4410);
+            assert_ne!(dry.right(), wet.right(), "{kind:?} had no effect");
+        }
+        panel.remove_cross_mod(1);
+        let dry_again = panel.play(220.0, &cents, ClockTick::abs_zero(), 4410);
+        assert_eq!(dry.right(), dry_again.right());
+
+        // only the last slot is heard, it syncs to the rendered phase of the frequency modulated one
+        let mut panel = OscPanel::from_oscs(
+            vec![Oscillator::Sine, Oscillator::Saw, Oscillator::Sine],
+            Some(vec![0.0, 0.0, 1.0]),
+            None,
+        )
+        .unwrap();
+        panel
+            .set_cross_mod(2, CrossModKind::HardSync, 1, 1.0)
+            .unwrap();
+        panel.set_cross_mod(1, CrossModKind::Fm, 0, 0.0).unwrap();
+        let steady = panel.play(220.0, &cents, ClockTick::abs_zero(), 4410);
+        panel.set_cross_mod(1, CrossModKind::Fm, 0, 1.0).unwrap();
+        let modulated = panel.play(220.0, &cents, ClockTick::abs_zero(), 4410);
+        assert_ne!(steady.right(), modulated.right());
     }
 }
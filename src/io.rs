@@ -7,10 +7,14 @@ use std::{
     collections::{hash_map::Entry, HashMap},
     error::Error,
     fs,
+    ops::RangeInclusive,
     path::Path,
 };
 
 pub mod data;
+pub mod scala;
+pub mod sf2;
+pub mod sfz;
 
 pub fn read_wav(path: impl AsRef<Path>) -> Result<Wave, Box<dyn std::error::Error>> {
     let reader = hound::WavReader::open(path)?;
@@ -119,15 +123,7 @@ pub fn read_wav(path: impl AsRef<Path>) -> Result<Wave, Box<dyn std::error::Erro
                             })
                             .chunks(2)
                             .into_iter()
-                            .map(|mut chunk| {
-                                (
-                                    chunk.next().unwrap(),
-                                    match chunk.next() {
-                                        Some(s) => s,
-                                        None => 0.0,
-                                    },
-                                )
-                            })
+                            .map(|mut chunk| (chunk.next().unwrap(), chunk.next().unwrap_or(0.0)))
                             .unzip();
                         if err {
                             Err(crate::Error::WavRead)?
@@ -274,28 +270,30 @@ fn parse_midi_track(
             TrackEventKind::Meta(msg) => {
                 decode_meta_msg(msg, &mut data, time_decoder, current_ticks)?
             }
-            TrackEventKind::Midi {
-                channel: _,
-                message,
-            } => match message {
-                MidiMessage::NoteOn { key, vel } => {
-                    data.push_note_on(current_ticks, key.as_int(), vel.as_int())
-                }
-                MidiMessage::NoteOff { key, vel: _ } => {
-                    data.push_note_off(current_ticks, key.as_int())
-                }
-                MidiMessage::Controller { controller, value } => {
-                    data.push_cc(current_ticks, controller.as_int(), value.as_int())
-                }
-                MidiMessage::PitchBend { bend } => data.push_pitch_bend(current_ticks, bend),
-                MidiMessage::Aftertouch { key, vel } => {
-                    data.push_after_touch(current_ticks, key.as_int(), vel.as_int())
-                }
-                MidiMessage::ChannelAftertouch { vel } => {
-                    data.push_ch_after_touch(current_ticks, vel.as_int())
+            TrackEventKind::Midi { channel, message } => {
+                let channel = channel.as_int();
+                match message {
+                    MidiMessage::NoteOn { key, vel } => {
+                        data.push_note_on(current_ticks, channel, key.as_int(), vel.as_int())
+                    }
+                    MidiMessage::NoteOff { key, vel: _ } => {
+                        data.push_note_off(current_ticks, channel, key.as_int())
+                    }
+                    MidiMessage::Controller { controller, value } => {
+                        data.push_cc(current_ticks, channel, controller.as_int(), value.as_int())
+                    }
+                    MidiMessage::PitchBend { bend } => {
+                        data.push_pitch_bend(current_ticks, channel, bend)
+                    }
+                    MidiMessage::Aftertouch { key, vel } => {
+                        data.push_after_touch(current_ticks, key.as_int(), vel.as_int())
+                    }
+                    MidiMessage::ChannelAftertouch { vel } => {
+                        data.push_ch_after_touch(current_ticks, channel, vel.as_int())
+                    }
+                    MidiMessage::ProgramChange { program: _ } => (),
                 }
-                MidiMessage::ProgramChange { program: _ } => (),
-            },
+            }
             TrackEventKind::SysEx(_) => (),
             TrackEventKind::Escape(_) => (),
         }
@@ -316,11 +314,11 @@ fn decode_meta_msg(
                 data.change_number(val);
             }
         }
-        TrackName(name) => data
-            .change_name(&String::from_utf8(name.to_vec()).expect("recieved invalid track name")),
-        InstrumentName(name) => data.change_inst_name(
-            &String::from_utf8(name.to_vec()).expect("recieved invalid instrument name"),
-        ),
+        TrackName(name) => {
+            data.change_name(std::str::from_utf8(name).expect("recieved invalid track name"))
+        }
+        InstrumentName(name) => data
+            .change_inst_name(std::str::from_utf8(name).expect("recieved invalid instrument name")),
         Tempo(tempo) => time_decoder
             .mus_per_beat(current_ticks, tempo.as_int())
             .expect("failed to decode tempo msg"),
@@ -356,6 +354,7 @@ pub(crate) struct TimeDecoder {
     pub time_signatures: XYPairs<u32, MidiSig>,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone, Copy)]
 pub struct MidiSig {
     beats_per_bar: u8,
@@ -445,31 +444,160 @@ impl AlmostTrack {
         self.number = number
     }
 
-    pub fn push_note_on(&mut self, tick: u32, key: u8, vel: u8) {
-        self.note_on.push(NoteOn { tick, key, vel })
+    pub fn push_note_on(&mut self, tick: u32, channel: u8, key: u8, vel: u8) {
+        self.note_on.push(NoteOn {
+            tick,
+            channel,
+            key,
+            vel,
+        })
     }
 
-    pub fn push_note_off(&mut self, tick: u32, key: u8) {
-        self.note_off.push(NoteOff { tick, key })
+    pub fn push_note_off(&mut self, tick: u32, channel: u8, key: u8) {
+        self.note_off.push(NoteOff { tick, channel, key })
     }
 
-    pub fn push_cc(&mut self, tick: u32, control: u8, val: u8) {
-        self.cc.push(ControlChange { tick, control, val })
+    pub fn push_cc(&mut self, tick: u32, channel: u8, control: u8, val: u8) {
+        self.cc.push(ControlChange {
+            tick,
+            channel,
+            control,
+            val,
+        })
     }
 
-    pub fn push_pitch_bend(&mut self, tick: u32, val: midly::PitchBend) {
-        self.pitch_bend.push(PitchBend { tick, val })
+    pub fn push_pitch_bend(&mut self, tick: u32, channel: u8, val: midly::PitchBend) {
+        self.pitch_bend.push(PitchBend { tick, channel, val })
     }
 
     pub fn push_after_touch(&mut self, tick: u32, key: u8, vel: u8) {
         self.after_touch.push(AfterTouch { tick, key, vel })
     }
 
-    pub fn push_ch_after_touch(&mut self, tick: u32, vel: u8) {
-        self.ch_after_touch.push(ChAftertouch { tick, vel })
+    pub fn push_ch_after_touch(&mut self, tick: u32, channel: u8, vel: u8) {
+        self.ch_after_touch
+            .push(ChAftertouch { tick, channel, vel })
+    }
+}
+
+/// the member channels of an mpe zone, each of them playing a single note
+#[derive(Debug, Clone, PartialEq)]
+struct MpeZone {
+    members: RangeInclusive<u8>,
+    /// in semitones
+    bend_range: f32,
+}
+
+impl MpeZone {
+    /// the zone left with the members inside `free`, `None` if there are none
+    fn shrunk(self, free: RangeInclusive<u8>) -> Option<Self> {
+        let members = *self.members.start().max(free.start())..=*self.members.end().min(free.end());
+        (!members.is_empty()).then_some(Self { members, ..self })
+    }
+}
+
+impl AlmostTrack {
+    /// the lower and upper zone set up by mpe configuration messages, which are rpn 6 on the
+    /// first or last channel, a zone claiming the members of the other one shrinks it
+    fn mpe_zones(&self) -> Vec<MpeZone> {
+        // the parameter number selected on each channel
+        let mut rpn = [(0x7f, 0x7f); 16];
+        let mut lower: Option<MpeZone> = None;
+        let mut upper: Option<MpeZone> = None;
+        for cc in &self.cc {
+            let channel = cc.channel as usize;
+            match cc.control {
+                101 => rpn[channel].0 = cc.val,
+                100 => rpn[channel].1 = cc.val,
+                6 => match (rpn[channel], cc.channel) {
+                    ((0, 6), 0) if cc.val == 0 => lower = None,
+                    ((0, 6), 15) if cc.val == 0 => upper = None,
+                    ((0, 6), 0) => {
+                        let last = cc.val.min(15);
+                        lower = Some(MpeZone {
+                            members: 1..=last,
+                            bend_range: 48.0,
+                        });
+                        upper = upper.and_then(|zone| zone.shrunk(last + 1..=14));
+                    }
+                    ((0, 6), 15) => {
+                        let first = 15 - cc.val.min(15);
+                        upper = Some(MpeZone {
+                            members: first..=14,
+                            bend_range: 48.0,
+                        });
+                        lower = lower.and_then(|zone| zone.shrunk(1..=first.saturating_sub(1)));
+                    }
+                    ((0, 0), channel) => {
+                        for zone in [&mut lower, &mut upper].into_iter().flatten() {
+                            if zone.members.contains(&channel) {
+                                zone.bend_range = cc.val as f32;
+                            }
+                        }
+                    }
+                    _ => (),
+                },
+                _ => (),
+            }
+        }
+        lower.into_iter().chain(upper).collect()
+    }
+
+    /// the controllers of the channel while the note is held
+    fn expression(&self, channel: u8, on: u32, off: u32, bend_range: f32) -> midi::NoteExpression {
+        midi::NoteExpression {
+            bend: curve(
+                self.pitch_bend
+                    .iter()
+                    .filter(|p| p.channel == channel)
+                    .map(|p| (p.tick, p.val.as_f32() / 2.0 + 0.5)),
+                on,
+                off,
+            ),
+            bend_range,
+            timbre: curve(
+                self.cc
+                    .iter()
+                    .filter(|cc| cc.channel == channel && cc.control == 74)
+                    .map(|cc| (cc.tick, cc.val as f32 / 127.0)),
+                on,
+                off,
+            ),
+            pressure: curve(
+                self.ch_after_touch
+                    .iter()
+                    .filter(|p| p.channel == channel)
+                    .map(|p| (p.tick, p.vel as f32 / 127.0)),
+                on,
+                off,
+            ),
+        }
     }
 }
 
+/// the values from `on` to `off`, starting with the last one sent before the note
+fn curve(events: impl Iterator<Item = (u32, f32)>, on: u32, off: u32) -> XYPairs<ClockTick, f32> {
+    let mut start = None;
+    let mut points: Vec<(u32, f32)> = Vec::new();
+    for (tick, val) in events {
+        if tick <= on {
+            start = Some(val);
+        } else if tick <= off {
+            match points.last_mut() {
+                Some(last) if last.0 == tick => last.1 = val,
+                _ => points.push((tick, val)),
+            }
+        }
+    }
+    let (ticks, vals) = start
+        .map(|val| (on, val))
+        .into_iter()
+        .chain(points)
+        .map(|(tick, val)| (ClockTick::new(tick), val))
+        .unzip();
+    XYPairs::from_vecs(ticks, vals)
+}
+
 impl AlmostTrack {
     pub fn into_track(mut self) -> Result<MidiTrackBuilder, Box<dyn Error>> {
         let mut notes = Vec::new();
@@ -479,31 +607,40 @@ impl AlmostTrack {
             "different count of note on and note off events"
         );
         // TODO AfterTouch
-        for note_on in self.note_on.into_iter() {
+        let zones = self.mpe_zones();
+        let zone_of = |channel: u8| zones.iter().find(|zone| zone.members.contains(&channel));
+        let is_member = |channel: u8| zone_of(channel).is_some();
+        for note_on in std::mem::take(&mut self.note_on) {
             let mut index = None;
             for (i, note_off) in self.note_off.iter().enumerate() {
-                if note_on.key == note_off.key {
+                if note_on.key == note_off.key && note_on.channel == note_off.channel {
                     index = Some(i);
                     break;
                 }
             }
+            let off = self.note_off.remove(index.unwrap()).tick;
+            let expression = zone_of(note_on.channel).map(|zone| {
+                Box::new(self.expression(note_on.channel, note_on.tick, off, zone.bend_range))
+            });
             notes.push(midi::Note {
                 pitch: midi::Pitch::new(note_on.key).unwrap(),
                 on: ClockTick::new(note_on.tick),
-                off: ClockTick::new(self.note_off.remove(index.unwrap()).tick),
+                off: ClockTick::new(off),
                 velocity: note_on.vel as f32 / 127.0,
+                expression,
             })
         }
 
+        // the controllers of the member channels only belong to their notes
         let mut pitch_bend = XYPairs::new();
 
-        for p in self.pitch_bend {
+        for p in self.pitch_bend.iter().filter(|p| !is_member(p.channel)) {
             pitch_bend.push(ClockTick::new(p.tick), p.val.as_f32() / 2.0 + 0.5)?
         }
 
         let mut gen_data = HashMap::<u8, XYPairs<_, _>>::new();
 
-        for p in self.cc.into_iter() {
+        for p in self.cc.iter().filter(|p| !is_member(p.channel)) {
             let val = p.val as f32 / 127.0;
             let tick = ClockTick::new(p.tick);
             match gen_data.entry(p.control) {
@@ -518,7 +655,7 @@ impl AlmostTrack {
         }
 
         let mut ch_after_touch = XYPairs::new();
-        for p in self.ch_after_touch.into_iter() {
+        for p in self.ch_after_touch.iter().filter(|p| !is_member(p.channel)) {
             ch_after_touch.push(ClockTick::new(p.tick), p.vel as f32 / 127.0)?
         }
 
@@ -537,6 +674,7 @@ impl AlmostTrack {
 #[derive(Debug)]
 struct NoteOn {
     tick: u32,
+    channel: u8,
     key: u8,
     vel: u8,
 }
@@ -544,12 +682,14 @@ struct NoteOn {
 #[derive(Debug)]
 struct NoteOff {
     tick: u32,
+    channel: u8,
     key: u8,
 }
 
 #[derive(Debug)]
 struct ControlChange {
     tick: u32,
+    channel: u8,
     control: u8,
     val: u8,
 }
@@ -557,9 +697,11 @@ struct ControlChange {
 #[derive(Debug)]
 struct PitchBend {
     tick: u32,
+    channel: u8,
     val: midly::PitchBend,
 }
 
+#[allow(dead_code)]
 #[derive(Debug)]
 struct AfterTouch {
     tick: u32,
@@ -570,5 +712,69 @@ struct AfterTouch {
 #[derive(Debug)]
 struct ChAftertouch {
     tick: u32,
+    channel: u8,
     vel: u8,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mpe_notes_keep_their_channel() {
+        let mut track = AlmostTrack::new(0);
+        // mpe configuration message for a lower zone with three member channels
+        track.push_cc(0, 0, 101, 0);
+        track.push_cc(0, 0, 100, 6);
+        track.push_cc(0, 0, 6, 3);
+        track.push_pitch_bend(0, 1, midly::PitchBend::from_f32(0.5));
+        track.push_note_on(10, 1, 60, 100);
+        track.push_note_on(10, 2, 60, 100);
+        track.push_cc(20, 2, 74, 127);
+        track.push_ch_after_touch(30, 1, 64);
+        track.push_note_off(40, 2, 60);
+        track.push_note_off(50, 1, 60);
+        track.push_cc(50, 0, 1, 64);
+        assert_eq!(track.mpe_zones()[0].members, 1..=3);
+
+        let track = track.into_track().unwrap();
+        let first = track.notes[0].expression.as_ref().unwrap();
+        assert_eq!(first.bend_range, 48.0);
+        assert_eq!(first.bend.slices().1, &[0.75]);
+        assert_eq!(first.pressure.slices().0, &[ClockTick::new(30)]);
+        assert!(first.timbre.is_empty());
+
+        let second = &track.notes[1];
+        assert_eq!(second.off, ClockTick::new(40));
+        assert_eq!(
+            second.expression.as_ref().unwrap().timbre.slices().1,
+            &[1.0]
+        );
+
+        // the manager channel still controls the whole track
+        assert!(track.pitch_bend.is_empty());
+        assert!(track.gen_data.contains_key(&1));
+        assert!(!track.gen_data.contains_key(&74));
+    }
+
+    #[test]
+    fn lower_and_upper_mpe_zones() {
+        let mut track = AlmostTrack::new(0);
+        let configure = |track: &mut AlmostTrack, channel, members| {
+            track.push_cc(0, channel, 101, 0);
+            track.push_cc(0, channel, 100, 6);
+            track.push_cc(0, channel, 6, members);
+        };
+        configure(&mut track, 0, 7);
+        configure(&mut track, 15, 10);
+        let zones = track.mpe_zones();
+        assert_eq!(zones[0].members, 1..=4);
+        assert_eq!(zones[1].members, 5..=14);
+
+        // turning off the upper zone leaves the lower one
+        configure(&mut track, 15, 0);
+        let zones = track.mpe_zones();
+        assert_eq!(zones.len(), 1);
+        assert_eq!(zones[0].members, 1..=4);
+    }
+}
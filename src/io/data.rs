@@ -1,9 +1,10 @@
 use crate::{
+    effects::EffectPanel,
     gens::{
         point_defined::Interpolation, Constant, GenId, Generator, GeneratorManager, PointDefined,
         Specific, TI,
     },
-    globals::{GENRATOR_MANAGER, RESOURCE_MANAGER, TIME_MANAGER},
+    globals::{GENRATOR_MANAGER, RESOURCE_MANAGER, TIME_MANAGER, TUNING_MANAGER},
     instr::MidiInstrument,
     resources::ResourceManager,
     time::{ClockTick, TimeManager},
@@ -11,6 +12,7 @@ use crate::{
         midi::{self, MidiTrack},
         Track,
     },
+    tuning::TuningManager,
     utils::XYPairs,
     Error, Song,
 };
@@ -25,9 +27,13 @@ use std::{
 pub struct SongBuilder {
     name: String,
     tracks: HashMap<u8, Track>,
+    #[serde(default)]
+    master: EffectPanel,
     time_manager: TimeManager,
     generator_manager: GeneratorManager,
     resource_manager: ResourceManager,
+    #[serde(default)]
+    tuning_manager: TuningManager,
 }
 
 impl SongBuilder {
@@ -35,9 +41,11 @@ impl SongBuilder {
         Self {
             name: String::new(),
             tracks: HashMap::new(),
+            master: EffectPanel::EmptyLeaf,
             time_manager: TimeManager::default(),
             generator_manager: GeneratorManager::new(),
             resource_manager: ResourceManager::default(),
+            tuning_manager: TuningManager::default(),
         }
     }
 
@@ -150,9 +158,11 @@ impl From<&Song> for SongBuilder {
         Self {
             name: song.name.clone(),
             tracks: song.tracks.clone(),
+            master: song.master.clone(),
             time_manager: TIME_MANAGER.read().unwrap().clone(),
             generator_manager: GENRATOR_MANAGER.read().unwrap().clone(),
             resource_manager: RESOURCE_MANAGER.read().unwrap().extract(),
+            tuning_manager: song.get_tuning().clone(),
         }
     }
 }
@@ -163,10 +173,19 @@ impl TryFrom<SongBuilder> for Song {
         *GENRATOR_MANAGER.write().unwrap() = data.generator_manager;
         *TIME_MANAGER.write().unwrap() = data.time_manager;
         *RESOURCE_MANAGER.write().unwrap() = data.resource_manager;
-        RESOURCE_MANAGER.write().unwrap().init()?;
+        *TUNING_MANAGER.write().unwrap() = data.tuning_manager.clone();
+        // samples that can't be loaded are rendered as silence and reported then
+        if let Err(err) = RESOURCE_MANAGER.write().unwrap().init() {
+            match err.downcast_ref::<Error>() {
+                Some(Error::Sample(_)) => (),
+                _ => return Err(err),
+            }
+        }
         Ok(Self {
             name: data.name,
             tracks: data.tracks,
+            master: data.master,
+            tuning: data.tuning_manager,
         })
     }
 }
@@ -192,4 +211,3 @@ pub struct MidiTrackBuilder {
     pub(super) pitch_bend: XYPairs<ClockTick, f32>,
     pub(super) _ch_after_touch: XYPairs<ClockTick, f32>,
 }
-
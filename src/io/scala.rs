@@ -0,0 +1,157 @@
+use std::{fs, path::Path};
+
+use crate::{
+    tuning::{KeyboardMap, Scale, Tuning},
+    Error,
+};
+
+/// a tuning from a scala scale file and an optional keyboard mapping
+pub fn parse_scala_files(
+    scl: impl AsRef<Path>,
+    kbm: Option<impl AsRef<Path>>,
+) -> Result<Tuning, Box<dyn std::error::Error>> {
+    let scale = parse_scl(&fs::read_to_string(scl)?)?;
+    let keyboard = match kbm {
+        Some(kbm) => parse_kbm(&fs::read_to_string(kbm)?)?,
+        None => KeyboardMap::default(),
+    };
+    Ok(Tuning::new(scale, keyboard)?)
+}
+
+/// lines starting with `!` are comments, the rest is the description, the number of steps and the steps
+pub fn parse_scl(text: &str) -> Result<Scale, Error> {
+    let mut lines = lines(text);
+    let description = lines.next().ok_or(Error::Parse)?.trim();
+    let len: usize = first_token(lines.next())?
+        .parse()
+        .map_err(|_| Error::Parse)?;
+    let steps = lines
+        .take(len)
+        .map(|line| parse_step(first_token(Some(line))?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if steps.len() != len {
+        return Err(Error::Parse);
+    }
+    Scale::new(description, steps)
+}
+
+/// a step is in cents if it has a period, otherwise it's a ratio
+fn parse_step(token: &str) -> Result<f32, Error> {
+    if token.contains('.') {
+        return token.parse().map_err(|_| Error::Parse);
+    }
+    let (num, den) = token.split_once('/').unwrap_or((token, "1"));
+    let num: f32 = num.parse().map_err(|_| Error::Parse)?;
+    let den: f32 = den.parse().map_err(|_| Error::Parse)?;
+    if num <= 0.0 || den <= 0.0 {
+        return Err(Error::Parse);
+    }
+    Ok(1200.0 * (num / den).log2())
+}
+
+/// the fields of a keyboard mapping in the order scala writes them, `x` marks unmapped keys
+pub fn parse_kbm(text: &str) -> Result<KeyboardMap, Error> {
+    let mut fields = lines(text)
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| first_token(Some(line)));
+    let mut next = || fields.next().unwrap_or(Err(Error::Parse));
+    let key = |token: &str| match token.parse::<u8>() {
+        Ok(key) if key < 0x80 => Ok(key),
+        _ => Err(Error::Parse),
+    };
+
+    let size: usize = next()?.parse().map_err(|_| Error::Parse)?;
+    let first_key = key(next()?)?;
+    let last_key = key(next()?)?;
+    let middle_key = key(next()?)?;
+    let reference_key = key(next()?)?;
+    let reference_freq: f32 = next()?.parse().map_err(|_| Error::Parse)?;
+    let octave_degree = next()?.parse().map_err(|_| Error::Parse)?;
+    let mut mapping = Vec::with_capacity(size);
+    // missing entries at the end are unmapped
+    for _ in 0..size {
+        mapping.push(match next() {
+            Ok("x") | Err(_) => None,
+            Ok(degree) => Some(degree.parse().map_err(|_| Error::Parse)?),
+        });
+    }
+    Ok(KeyboardMap {
+        first_key,
+        last_key,
+        middle_key,
+        reference_key,
+        reference_freq,
+        octave_degree,
+        mapping,
+    })
+}
+
+fn lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines().filter(|line| !line.starts_with('!'))
+}
+
+fn first_token(line: Option<&str>) -> Result<&str, Error> {
+    line.and_then(|line| line.split_whitespace().next())
+        .ok_or(Error::Parse)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SCL: &str = "! just.scl
+!
+Just major
+ 7
+!
+ 9/8
+ 5/4
+ 4/3
+ 3/2
+ 5/3
+ 15/8
+ 2/1
+";
+
+    const KBM: &str = "! white keys only
+12
+0
+127
+60
+69
+440.0
+7
+! mapping
+0
+x
+1
+x
+2
+3
+x
+4
+x
+5
+x
+6
+";
+
+    #[test]
+    fn just_major_on_white_keys() {
+        let scale = parse_scl(SCL).unwrap();
+        assert_eq!(scale.description, "Just major");
+        assert_eq!(scale.len(), 7);
+        assert!((scale.degree_cents(4) - 701.955).abs() < 1e-2);
+        assert!((scale.degree_cents(7) - 1200.0).abs() < 1e-3);
+
+        let tuning = Tuning::new(scale, parse_kbm(KBM).unwrap()).unwrap();
+        assert_eq!(tuning.freq(69), Some(440.0));
+        // C4 is a major sixth below A4
+        assert!((tuning.freq(60).unwrap() - 264.0).abs() < 1e-2);
+        assert!((tuning.freq(72).unwrap() - 528.0).abs() < 1e-2);
+        assert_eq!(tuning.freq(61), None);
+
+        assert!(parse_scl("cents\n2\n100.0\n").is_err());
+        assert!(parse_kbm("12\n0\n127\n60\n69\n").is_err());
+    }
+}
@@ -0,0 +1,608 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    gens::Envelope,
+    globals::SAMPLE_RATE,
+    instr::sampler::{LoopMode, SamplerBuilder, Zone},
+    resources::{SampleSource, Sf2Sample},
+    wave::Wave,
+    Error,
+};
+
+// generator operators from the SoundFont 2.04 specification
+const START_OFFSET: u16 = 0;
+const END_OFFSET: u16 = 1;
+const START_LOOP_OFFSET: u16 = 2;
+const END_LOOP_OFFSET: u16 = 3;
+const START_COARSE_OFFSET: u16 = 4;
+const END_COARSE_OFFSET: u16 = 12;
+const PAN: u16 = 17;
+const ATTACK_VOL_ENV: u16 = 34;
+const DECAY_VOL_ENV: u16 = 36;
+const SUSTAIN_VOL_ENV: u16 = 37;
+const RELEASE_VOL_ENV: u16 = 38;
+const INSTRUMENT: u16 = 41;
+const KEY_RANGE: u16 = 43;
+const VEL_RANGE: u16 = 44;
+const START_LOOP_COARSE_OFFSET: u16 = 45;
+const INITIAL_ATTENUATION: u16 = 48;
+const END_LOOP_COARSE_OFFSET: u16 = 50;
+const COARSE_TUNE: u16 = 51;
+const FINE_TUNE: u16 = 52;
+const SAMPLE_ID: u16 = 53;
+const SAMPLE_MODES: u16 = 54;
+const OVERRIDING_ROOT_KEY: u16 = 58;
+
+const DEFAULT_ENV_TIME: i16 = -12000;
+const ROM_SAMPLE: u16 = 0x8000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sf2Preset {
+    pub name: String,
+    pub bank: u16,
+    pub program: u16,
+}
+
+#[derive(Debug)]
+struct PresetHeader {
+    preset: Sf2Preset,
+    bag: u16,
+}
+
+#[derive(Debug)]
+struct InstrumentHeader {
+    bag: u16,
+}
+
+#[derive(Debug)]
+struct SampleHeader {
+    start: u32,
+    end: u32,
+    start_loop: u32,
+    end_loop: u32,
+    sample_rate: u32,
+    original_pitch: u8,
+    pitch_correction: i8,
+    sample_type: u16,
+}
+
+type Generators = HashMap<u16, [u8; 2]>;
+type Chunk<'a> = ([u8; 4], &'a [u8]);
+
+/// the preset and instrument tables of a SoundFont 2 file, the sample data is read on demand
+#[derive(Debug)]
+pub struct Sf2 {
+    path: PathBuf,
+    presets: Vec<PresetHeader>,
+    preset_bags: Vec<u16>,
+    preset_gens: Vec<(u16, [u8; 2])>,
+    instruments: Vec<InstrumentHeader>,
+    instrument_bags: Vec<u16>,
+    instrument_gens: Vec<(u16, [u8; 2])>,
+    samples: Vec<SampleHeader>,
+}
+
+impl Sf2 {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref();
+        let data = fs::read(path)?;
+        let pdta = riff_lists(&data)?
+            .into_iter()
+            .find(|(kind, _)| kind == b"pdta")
+            .ok_or(Error::Parse)?
+            .1;
+
+        let mut sf2 = Self {
+            path: path.to_path_buf(),
+            presets: Vec::new(),
+            preset_bags: Vec::new(),
+            preset_gens: Vec::new(),
+            instruments: Vec::new(),
+            instrument_bags: Vec::new(),
+            instrument_gens: Vec::new(),
+            samples: Vec::new(),
+        };
+        for (id, body) in chunks(pdta)? {
+            match &id {
+                b"phdr" => {
+                    sf2.presets = body
+                        .chunks_exact(38)
+                        .map(|r| PresetHeader {
+                            preset: Sf2Preset {
+                                name: name(&r[0..20]),
+                                program: u16_at(r, 20),
+                                bank: u16_at(r, 22),
+                            },
+                            bag: u16_at(r, 24),
+                        })
+                        .collect()
+                }
+                b"pbag" => sf2.preset_bags = body.chunks_exact(4).map(|r| u16_at(r, 0)).collect(),
+                b"pgen" => sf2.preset_gens = generators(body),
+                b"inst" => {
+                    sf2.instruments = body
+                        .chunks_exact(22)
+                        .map(|r| InstrumentHeader { bag: u16_at(r, 20) })
+                        .collect()
+                }
+                b"ibag" => {
+                    sf2.instrument_bags = body.chunks_exact(4).map(|r| u16_at(r, 0)).collect()
+                }
+                b"igen" => sf2.instrument_gens = generators(body),
+                b"shdr" => {
+                    sf2.samples = body
+                        .chunks_exact(46)
+                        .map(|r| SampleHeader {
+                            start: u32_at(r, 20),
+                            end: u32_at(r, 24),
+                            start_loop: u32_at(r, 28),
+                            end_loop: u32_at(r, 32),
+                            sample_rate: u32_at(r, 36),
+                            original_pitch: r[40],
+                            pitch_correction: r[41] as i8,
+                            sample_type: u16_at(r, 44),
+                        })
+                        .collect()
+                }
+                _ => (),
+            }
+        }
+        // every table ends with a terminal record
+        if sf2.presets.len() < 2 || sf2.instruments.len() < 2 || sf2.samples.is_empty() {
+            Err(Error::Parse)?
+        }
+        // the terminal record is the only one without a sample rate
+        let last = sf2.samples.len() - 1;
+        if sf2.samples[..last]
+            .iter()
+            .any(|sample| sample.sample_rate == 0)
+        {
+            Err(Error::Parse)?
+        }
+        Ok(sf2)
+    }
+
+    pub fn presets(&self) -> Vec<Sf2Preset> {
+        self.presets[..self.presets.len() - 1]
+            .iter()
+            .map(|header| header.preset.clone())
+            .collect()
+    }
+
+    /// builds a sampler from the preset with the given bank and program number
+    pub fn instrument(&self, bank: u16, program: u16) -> Result<SamplerBuilder, Error> {
+        let index = self.presets[..self.presets.len() - 1]
+            .iter()
+            .position(|header| header.preset.bank == bank && header.preset.program == program)
+            .ok_or(Error::Existence)?;
+        let mut builder = SamplerBuilder::new(&self.presets[index].preset.name);
+
+        let preset_zones = zones(
+            &self.preset_bags,
+            &self.preset_gens,
+            self.presets[index].bag,
+            self.presets[index + 1].bag,
+        )?;
+        let (preset_global, preset_zones) = split_global(preset_zones, INSTRUMENT);
+
+        for preset_zone in preset_zones {
+            let preset_gens = merge(&preset_global, &preset_zone);
+            let instrument = get_u16(&preset_gens, INSTRUMENT).ok_or(Error::Parse)? as usize;
+            if instrument + 1 >= self.instruments.len() {
+                return Err(Error::Parse);
+            }
+            let instrument_zones = zones(
+                &self.instrument_bags,
+                &self.instrument_gens,
+                self.instruments[instrument].bag,
+                self.instruments[instrument + 1].bag,
+            )?;
+            let (instrument_global, instrument_zones) = split_global(instrument_zones, SAMPLE_ID);
+
+            for instrument_zone in instrument_zones {
+                let gens = merge(&instrument_global, &instrument_zone);
+                if let Some(zone) = self.build_zone(&gens, &preset_gens)? {
+                    builder.add_zone(zone);
+                }
+            }
+        }
+        Ok(builder)
+    }
+
+    fn build_zone(
+        &self,
+        gens: &Generators,
+        preset_gens: &Generators,
+    ) -> Result<Option<Zone<SampleSource>>, Error> {
+        // zones without a sample don't play anything
+        let Some(sample_id) = get_u16(gens, SAMPLE_ID) else {
+            return Ok(None);
+        };
+        let header = self.samples.get(sample_id as usize).ok_or(Error::Parse)?;
+        if header.sample_type & ROM_SAMPLE != 0 || header.end <= header.start {
+            return Ok(None);
+        }
+
+        let (lo_key, hi_key) = intersect(
+            get_range(gens, KEY_RANGE),
+            get_range(preset_gens, KEY_RANGE),
+        );
+        let (lo_vel, hi_vel) = intersect(
+            get_range(gens, VEL_RANGE),
+            get_range(preset_gens, VEL_RANGE),
+        );
+        if lo_key > hi_key || lo_vel > hi_vel {
+            return Ok(None);
+        }
+
+        let offset = |fine: u16, coarse: u16| {
+            get_i16(gens, fine).unwrap_or(0) as i64
+                + 32768 * get_i16(gens, coarse).unwrap_or(0) as i64
+        };
+        // offsets can't move the sample out of its own data
+        let clamp = |point: u32, offset: i64| {
+            (point as i64 + offset).clamp(header.start as i64, header.end as i64) as u32
+        };
+        let start = clamp(header.start, offset(START_OFFSET, START_COARSE_OFFSET));
+        let end = clamp(header.end, offset(END_OFFSET, END_COARSE_OFFSET));
+        if end <= start {
+            return Ok(None);
+        }
+        let sample = Sf2Sample {
+            path: self.path.clone(),
+            start,
+            end,
+            sample_rate: header.sample_rate,
+        };
+
+        let root = match get_i16(gens, OVERRIDING_ROOT_KEY) {
+            Some(key) if (0..128).contains(&key) => key as u8,
+            _ if header.original_pitch < 128 => header.original_pitch,
+            _ => 60,
+        };
+        let mut zone = Zone::new(SampleSource::Sf2(sample), lo_key, hi_key, root);
+        zone.lo_vel = lo_vel as f32 / 127.0;
+        zone.hi_vel = hi_vel as f32 / 127.0;
+
+        // preset generators are offsets to the instrument generators
+        let sum = |op: u16, default: i16| {
+            get_i16(gens, op).unwrap_or(default) as f32
+                + get_i16(preset_gens, op).unwrap_or(0) as f32
+        };
+        zone.tune =
+            sum(COARSE_TUNE, 0) * 100.0 + sum(FINE_TUNE, 0) + header.pitch_correction as f32;
+        zone.gain = -sum(INITIAL_ATTENUATION, 0) / 10.0;
+        zone.pan = (sum(PAN, 0) / 500.0).clamp(-1.0, 1.0);
+
+        // loop points are stored in samples at the rate of the file
+        let rate = SAMPLE_RATE as f64 / header.sample_rate as f64;
+        let loop_start = header.start_loop as i64
+            + offset(START_LOOP_OFFSET, START_LOOP_COARSE_OFFSET)
+            - start as i64;
+        let loop_end =
+            header.end_loop as i64 + offset(END_LOOP_OFFSET, END_LOOP_COARSE_OFFSET) - start as i64;
+        if loop_start >= 0 && loop_end > loop_start {
+            let start = (loop_start as f64 * rate) as usize;
+            let end = (loop_end as f64 * rate) as usize;
+            zone.loop_mode = match get_i16(gens, SAMPLE_MODES).unwrap_or(0) {
+                1 => LoopMode::Continuous { start, end },
+                3 => LoopMode::Sustain { start, end },
+                _ => LoopMode::NoLoop,
+            };
+        }
+
+        let seconds = |op: u16| timecents_to_seconds(sum(op, DEFAULT_ENV_TIME)).clamp(0.0, 25.0);
+        let sustain = 10.0_f32.powf(-sum(SUSTAIN_VOL_ENV, 0).max(0.0) / 200.0);
+        zone.envelope = Some(Envelope::new_adsr(
+            seconds(ATTACK_VOL_ENV).max(0.001),
+            seconds(DECAY_VOL_ENV),
+            sustain.clamp(0.0, 1.0),
+            seconds(RELEASE_VOL_ENV),
+        )?);
+        Ok(Some(zone))
+    }
+}
+
+/// reads the sample data and converts it to the sample rate used by the crate
+pub fn read_sample(sample: &Sf2Sample) -> Result<Wave, Box<dyn std::error::Error>> {
+    let mut file = File::open(&sample.path)?;
+    let offset = find_smpl(&mut file)?;
+    file.seek(SeekFrom::Start(offset + sample.start as u64 * 2))?;
+    let mut bytes = vec![0; (sample.end - sample.start) as usize * 2];
+    file.read_exact(&mut bytes)?;
+    let vec = bytes
+        .chunks_exact(2)
+        .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / i16::MAX as f32)
+        .collect();
+    let wave = Wave::from_vec(vec);
+    if sample.sample_rate as usize == SAMPLE_RATE {
+        Ok(wave)
+    } else {
+        Ok(wave.resample(sample.sample_rate as f32 / SAMPLE_RATE as f32))
+    }
+}
+
+/// finds the start of the 16 bit sample data without reading the whole file
+fn find_smpl(file: &mut File) -> Result<u64, Box<dyn std::error::Error>> {
+    let mut header = [0; 12];
+    file.read_exact(&mut header)?;
+    if &header[0..4] != b"RIFF" || &header[8..12] != b"sfbk" {
+        Err(Error::Parse)?
+    }
+    let mut pos = 12;
+    let end = 8 + u32_at(&header, 4) as u64;
+    while pos + 8 <= end {
+        let mut chunk = [0; 12];
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut chunk)?;
+        let size = u32_at(&chunk, 4) as u64;
+        if &chunk[0..4] == b"LIST" && &chunk[8..12] == b"sdta" {
+            let mut sub_pos = pos + 12;
+            while sub_pos + 8 <= pos + 8 + size {
+                let mut sub = [0; 8];
+                file.seek(SeekFrom::Start(sub_pos))?;
+                file.read_exact(&mut sub)?;
+                if &sub[0..4] == b"smpl" {
+                    return Ok(sub_pos + 8);
+                }
+                sub_pos += 8 + (u32_at(&sub, 4) as u64).div_ceil(2) * 2;
+            }
+        }
+        pos += 8 + size.div_ceil(2) * 2;
+    }
+    Err(Error::Parse)?
+}
+
+fn timecents_to_seconds(timecents: f32) -> f32 {
+    2.0_f32.powf(timecents / 1200.0)
+}
+
+/// the LIST chunks of the sfbk RIFF form by their type
+fn riff_lists(data: &[u8]) -> Result<Vec<Chunk<'_>>, Error> {
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"sfbk" {
+        return Err(Error::Parse);
+    }
+    let end = (8 + u32_at(data, 4) as usize).min(data.len());
+    Ok(chunks(&data[12..end])?
+        .into_iter()
+        .filter(|(id, body)| id == b"LIST" && body.len() >= 4)
+        .map(|(_, body)| ([body[0], body[1], body[2], body[3]], &body[4..]))
+        .collect())
+}
+
+fn chunks(mut data: &[u8]) -> Result<Vec<Chunk<'_>>, Error> {
+    let mut out = Vec::new();
+    while data.len() >= 8 {
+        let id = [data[0], data[1], data[2], data[3]];
+        let size = u32_at(data, 4) as usize;
+        let body = data.get(8..8 + size).ok_or(Error::Parse)?;
+        out.push((id, body));
+        data = data.get(8 + size.div_ceil(2) * 2..).unwrap_or(&[]);
+    }
+    Ok(out)
+}
+
+fn generators(body: &[u8]) -> Vec<(u16, [u8; 2])> {
+    body.chunks_exact(4)
+        .map(|r| (u16_at(r, 0), [r[2], r[3]]))
+        .collect()
+}
+
+/// the generators of the zones in bags `start..end`
+fn zones(
+    bags: &[u16],
+    gens: &[(u16, [u8; 2])],
+    start: u16,
+    end: u16,
+) -> Result<Vec<Generators>, Error> {
+    let mut out = Vec::new();
+    for bag in start as usize..end as usize {
+        let first = *bags.get(bag).ok_or(Error::Parse)? as usize;
+        let last = *bags.get(bag + 1).ok_or(Error::Parse)? as usize;
+        out.push(
+            gens.get(first..last)
+                .ok_or(Error::Parse)?
+                .iter()
+                .copied()
+                .collect(),
+        );
+    }
+    Ok(out)
+}
+
+/// a first zone without the terminal generator is the global zone
+fn split_global(mut zones: Vec<Generators>, terminal: u16) -> (Generators, Vec<Generators>) {
+    match zones.first() {
+        Some(zone) if !zone.contains_key(&terminal) => {
+            let global = zones.remove(0);
+            (global, zones)
+        }
+        _ => (Generators::new(), zones),
+    }
+}
+
+fn merge(global: &Generators, local: &Generators) -> Generators {
+    let mut out = global.clone();
+    out.extend(local.iter().map(|(op, amount)| (*op, *amount)));
+    out
+}
+
+fn intersect(a: (u8, u8), b: (u8, u8)) -> (u8, u8) {
+    (a.0.max(b.0), a.1.min(b.1))
+}
+
+fn get_i16(gens: &Generators, op: u16) -> Option<i16> {
+    gens.get(&op).map(|amount| i16::from_le_bytes(*amount))
+}
+
+fn get_u16(gens: &Generators, op: u16) -> Option<u16> {
+    gens.get(&op).map(|amount| u16::from_le_bytes(*amount))
+}
+
+fn get_range(gens: &Generators, op: u16) -> (u8, u8) {
+    gens.get(&op)
+        .map(|amount| (amount[0], amount[1]))
+        .unwrap_or((0, 127))
+}
+
+fn name(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes.split(|b| *b == 0).next().unwrap_or(&[]))
+        .trim()
+        .to_string()
+}
+
+fn u16_at(bytes: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([bytes[pos], bytes[pos + 1]])
+}
+
+fn u32_at(bytes: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut out = id.to_vec();
+        out.extend((body.len() as u32).to_le_bytes());
+        out.extend(body);
+        out
+    }
+
+    fn list(kind: &[u8; 4], chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut body = kind.to_vec();
+        body.extend(chunks.concat());
+        chunk(b"LIST", &body)
+    }
+
+    fn record(name: &str, len: usize, fields: &[u8]) -> Vec<u8> {
+        let mut out = name.as_bytes().to_vec();
+        out.resize(20, 0);
+        out.extend(fields);
+        out.resize(len, 0);
+        out
+    }
+
+    fn gen(op: u16, amount: [u8; 2]) -> Vec<u8> {
+        let mut out = op.to_le_bytes().to_vec();
+        out.extend(amount);
+        out
+    }
+
+    fn bags(starts: &[u16]) -> Vec<u8> {
+        starts
+            .iter()
+            .flat_map(|start| [start.to_le_bytes(), [0, 0]].concat())
+            .collect()
+    }
+
+    /// a preset with one instrument, whose zones are one without a sample and two sharing one sample,
+    /// the sample starts at `start` in the sample data
+    fn sf2_file(start: u32, sample_rate: u32) -> Vec<u8> {
+        let smpl: Vec<u8> = (0..100_i16).flat_map(|i| (i * 100).to_le_bytes()).collect();
+
+        let mut sample_fields = Vec::new();
+        for value in [start, 100, 10, 90, sample_rate] {
+            sample_fields.extend(value.to_le_bytes());
+        }
+        sample_fields.extend([60, 0, 0, 0, 1, 0]);
+        let shdr = [record("sine", 46, &sample_fields), record("EOS", 46, &[])].concat();
+
+        let igen = [
+            gen(KEY_RANGE, [0, 59]),
+            gen(SAMPLE_ID, [0, 0]),
+            gen(KEY_RANGE, [60, 64]),
+            gen(KEY_RANGE, [60, 127]),
+            gen(START_OFFSET, (-50_i16).to_le_bytes()),
+            gen(END_OFFSET, 1000_i16.to_le_bytes()),
+            gen(SAMPLE_ID, [0, 0]),
+            gen(0, [0, 0]),
+        ]
+        .concat();
+        let pdta = list(
+            b"pdta",
+            &[
+                chunk(
+                    b"phdr",
+                    &[
+                        record("Sine", 38, &[0, 0, 0, 0, 0, 0]),
+                        record("EOP", 38, &[0, 0, 0, 0, 1, 0]),
+                    ]
+                    .concat(),
+                ),
+                chunk(b"pbag", &bags(&[0, 1])),
+                chunk(b"pgen", &[gen(INSTRUMENT, [0, 0]), gen(0, [0, 0])].concat()),
+                chunk(
+                    b"inst",
+                    &[record("Sine", 22, &[0, 0]), record("EOI", 22, &[3, 0])].concat(),
+                ),
+                chunk(b"ibag", &bags(&[0, 2, 3, 7])),
+                chunk(b"igen", &igen),
+                chunk(b"shdr", &shdr),
+            ],
+        );
+        let sdta = list(b"sdta", &[chunk(b"smpl", &smpl)]);
+
+        let mut body = b"sfbk".to_vec();
+        body.extend(sdta);
+        body.extend(pdta);
+        chunk(b"RIFF", &body)
+    }
+
+    #[test]
+    fn preset_zones() {
+        let path = std::env::temp_dir().join("song_sf2_test.sf2");
+        fs::write(&path, sf2_file(0, SAMPLE_RATE as u32)).unwrap();
+        let sf2 = Sf2::open(&path).unwrap();
+        assert_eq!(
+            sf2.presets(),
+            vec![Sf2Preset {
+                name: "Sine".to_string(),
+                bank: 0,
+                program: 0
+            }]
+        );
+        assert!(matches!(sf2.instrument(1, 0), Err(Error::Existence)));
+
+        let zones = sf2.instrument(0, 0).unwrap().zones;
+        assert_eq!(zones.len(), 2);
+        assert_eq!(
+            (zones[0].lo_key, zones[0].hi_key, zones[0].root),
+            (0, 59, 60)
+        );
+        assert_eq!((zones[1].lo_key, zones[1].hi_key), (60, 127));
+        let SampleSource::Sf2(sample) = &zones[1].sample else {
+            panic!("zone doesn't play a soundfont sample");
+        };
+        // the offsets are clamped to the sample
+        assert_eq!((sample.start, sample.end), (0, 100));
+
+        let wave = read_sample(sample).unwrap();
+        assert_eq!(wave.len(), 100);
+        assert!((wave.right()[1] - 100.0 / i16::MAX as f32).abs() < 1e-6);
+    }
+
+    #[test]
+    fn offsets_stay_in_their_sample() {
+        let path = std::env::temp_dir().join("song_sf2_offset_test.sf2");
+        fs::write(&path, sf2_file(20, SAMPLE_RATE as u32)).unwrap();
+        let zones = Sf2::open(&path).unwrap().instrument(0, 0).unwrap().zones;
+        let SampleSource::Sf2(sample) = &zones[1].sample else {
+            panic!("zone doesn't play a soundfont sample");
+        };
+        // the negative start offset stops at the start of the sample
+        assert_eq!((sample.start, sample.end), (20, 100));
+        let wave = read_sample(sample).unwrap();
+        assert!((wave.right()[0] - 2000.0 / i16::MAX as f32).abs() < 1e-6);
+        assert_eq!(wave.resample(0.0).len(), wave.len());
+
+        fs::write(&path, sf2_file(0, 0)).unwrap();
+        assert!(Sf2::open(&path).is_err());
+    }
+}
@@ -0,0 +1,504 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    gens::Envelope,
+    instr::sampler::{LoopMode, SamplerBuilder, Zone},
+    resources::SampleSource,
+    Error,
+};
+
+/// an opcode or header which has no equivalent in the sampler, a region without a sample is reported
+/// with an empty `sample` opcode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unsupported {
+    pub header: String,
+    pub opcode: String,
+    pub value: String,
+}
+
+impl Display for Unsupported {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "unsupported sfz opcode in <{}>: {}={}",
+            self.header, self.opcode, self.value
+        )
+    }
+}
+
+pub fn parse_sfz_file(
+    path: impl AsRef<Path>,
+) -> Result<(SamplerBuilder, Vec<Unsupported>), Box<dyn std::error::Error>> {
+    let path = path.as_ref();
+    let text = fs::read_to_string(path)?;
+    let name = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    parse_sfz(&text, &name, dir)
+}
+
+/// converts sfz text into a sampler, sample paths are taken relative to `dir`
+pub fn parse_sfz(
+    text: &str,
+    name: &str,
+    dir: &Path,
+) -> Result<(SamplerBuilder, Vec<Unsupported>), Box<dyn std::error::Error>> {
+    let mut builder = SamplerBuilder::new(name);
+    let mut unsupported = Vec::new();
+
+    let mut default_path = PathBuf::new();
+    let mut global = HashMap::new();
+    let mut master = HashMap::new();
+    let mut group = HashMap::new();
+    let mut region: Option<HashMap<String, String>> = None;
+    let mut header = String::new();
+
+    for token in tokenize(&strip_comments(text)) {
+        match token {
+            Token::Header(name) => {
+                if let Some(opcodes) = region.take() {
+                    if let Some(zone) = build_zone(
+                        &[
+                            ("global", &global),
+                            ("master", &master),
+                            ("group", &group),
+                            ("region", &opcodes),
+                        ],
+                        dir,
+                        &default_path,
+                        &mut unsupported,
+                    )? {
+                        builder.add_zone(zone);
+                    }
+                }
+                match name.as_str() {
+                    "global" => {
+                        global.clear();
+                        master.clear();
+                        group.clear();
+                    }
+                    "master" => {
+                        master.clear();
+                        group.clear();
+                    }
+                    "group" => group.clear(),
+                    "region" => region = Some(HashMap::new()),
+                    "control" => (),
+                    _ => unsupported.push(Unsupported {
+                        header: name.clone(),
+                        opcode: String::new(),
+                        value: String::new(),
+                    }),
+                }
+                header = name;
+            }
+            Token::Opcode(opcode, value) => match header.as_str() {
+                "control" => match opcode.as_str() {
+                    "default_path" => default_path = PathBuf::from(value.replace('\\', "/")),
+                    _ => unsupported.push(Unsupported {
+                        header: header.clone(),
+                        opcode,
+                        value,
+                    }),
+                },
+                "global" => {
+                    global.insert(opcode, value);
+                }
+                "master" => {
+                    master.insert(opcode, value);
+                }
+                "group" => {
+                    group.insert(opcode, value);
+                }
+                "region" => {
+                    if let Some(opcodes) = &mut region {
+                        opcodes.insert(opcode, value);
+                    }
+                }
+                // opcodes of unsupported headers were already reported with the header
+                _ => (),
+            },
+            Token::Directive(directive) => unsupported.push(Unsupported {
+                header: header.clone(),
+                opcode: directive,
+                value: String::new(),
+            }),
+        }
+    }
+    if let Some(opcodes) = region.take() {
+        if let Some(zone) = build_zone(
+            &[
+                ("global", &global),
+                ("master", &master),
+                ("group", &group),
+                ("region", &opcodes),
+            ],
+            dir,
+            &default_path,
+            &mut unsupported,
+        )? {
+            builder.add_zone(zone);
+        }
+    }
+    // opcodes set on outer levels are reported once and not for every region
+    let mut reported = Vec::with_capacity(unsupported.len());
+    for entry in unsupported {
+        if !reported.contains(&entry) {
+            reported.push(entry)
+        }
+    }
+    Ok((builder, reported))
+}
+
+/// applies the opcodes of the levels from the outermost to the innermost, so inner levels override outer ones,
+/// a region without a sample is skipped and reported
+fn build_zone(
+    levels: &[(&str, &HashMap<String, String>)],
+    dir: &Path,
+    default_path: &Path,
+    unsupported: &mut Vec<Unsupported>,
+) -> Result<Option<Zone<SampleSource>>, Error> {
+    let Some(sample) = levels
+        .iter()
+        .rev()
+        .find_map(|(_, level)| level.get("sample"))
+    else {
+        unsupported.push(Unsupported {
+            header: "region".to_string(),
+            opcode: "sample".to_string(),
+            value: String::new(),
+        });
+        return Ok(None);
+    };
+    let mut zone = Zone::new(
+        SampleSource::Wav(dir.join(default_path).join(sample.replace('\\', "/"))),
+        0,
+        127,
+        60,
+    );
+    let mut loop_mode = None;
+    let mut loop_start = None;
+    let mut loop_end = None;
+    let mut has_envelope = false;
+    let (mut attack, mut decay, mut sustain, mut release): (f32, f32, f32, f32) =
+        (0.001, 0.0, 1.0, 0.001);
+    let (mut tune, mut transpose) = (0.0, 0.0);
+
+    for (header, level) in levels {
+        // key sets the whole range, so lokey, hikey and pitch_keycenter of the same level can narrow it
+        let mut opcodes: Vec<(&str, &str)> = level
+            .iter()
+            .map(|(opcode, value)| (opcode.as_str(), value.as_str()))
+            .collect();
+        opcodes.sort_by_key(|(opcode, _)| (*opcode != "key", *opcode));
+        for (opcode, value) in opcodes {
+            match opcode {
+                "sample" => (),
+                "key" => {
+                    let key = parse_key(value)?;
+                    zone.lo_key = key;
+                    zone.hi_key = key;
+                    zone.root = key;
+                }
+                "lokey" => zone.lo_key = parse_key(value)?,
+                "hikey" => zone.hi_key = parse_key(value)?,
+                "pitch_keycenter" => zone.root = parse_key(value)?,
+                "lovel" => zone.lo_vel = parse::<f32>(value)? / 127.0,
+                "hivel" => zone.hi_vel = parse::<f32>(value)? / 127.0,
+                "seq_length" => zone.seq_length = parse(value)?,
+                "seq_position" => zone.seq_position = parse(value)?,
+                "loop_mode" | "loopmode" => loop_mode = Some((*header, value)),
+                "loop_start" | "loopstart" => loop_start = Some(parse::<usize>(value)?),
+                "loop_end" | "loopend" => loop_end = Some(parse::<usize>(value)?),
+                "ampeg_attack" => {
+                    attack = parse(value)?;
+                    has_envelope = true;
+                }
+                "ampeg_decay" => {
+                    decay = parse(value)?;
+                    has_envelope = true;
+                }
+                "ampeg_sustain" => {
+                    sustain = parse::<f32>(value)? / 100.0;
+                    has_envelope = true;
+                }
+                "ampeg_release" => {
+                    release = parse(value)?;
+                    has_envelope = true;
+                }
+                "volume" => zone.gain = parse(value)?,
+                "pan" => zone.pan = parse::<f32>(value)? / 100.0,
+                "tune" => tune = parse::<f32>(value)?,
+                "transpose" => transpose = parse::<f32>(value)?,
+                _ => unsupported.push(Unsupported {
+                    header: header.to_string(),
+                    opcode: opcode.to_string(),
+                    value: value.to_string(),
+                }),
+            }
+        }
+    }
+    zone.tune = tune + transpose * 100.0;
+
+    zone.loop_mode = match (loop_mode, loop_start, loop_end) {
+        (Some((_, "one_shot")), _, _) => LoopMode::OneShot,
+        (Some((_, "loop_continuous")), Some(start), Some(end)) => LoopMode::Continuous {
+            start,
+            end: end + 1,
+        },
+        (Some((_, "loop_sustain")), Some(start), Some(end)) => LoopMode::Sustain {
+            start,
+            end: end + 1,
+        },
+        (None | Some((_, "no_loop")), _, _) => LoopMode::NoLoop,
+        (Some((header, mode)), _, _) => {
+            unsupported.push(Unsupported {
+                header: header.to_string(),
+                opcode: "loop_mode".to_string(),
+                value: mode.to_string(),
+            });
+            LoopMode::NoLoop
+        }
+    };
+
+    if has_envelope {
+        zone.envelope = Some(Envelope::new_adsr(
+            attack.max(0.001),
+            decay,
+            sustain.clamp(0.0, 1.0),
+            release,
+        )?);
+    }
+    Ok(Some(zone))
+}
+
+fn parse<T: std::str::FromStr>(value: &str) -> Result<T, Error> {
+    value.trim().parse().map_err(|_| Error::Parse)
+}
+
+/// parses midi numbers and note names like c4, f#3 or eb2 where c4 is 60
+fn parse_key(value: &str) -> Result<u8, Error> {
+    let value = value.trim().to_lowercase();
+    if let Ok(key) = value.parse::<u8>() {
+        return if key < 0x80 {
+            Ok(key)
+        } else {
+            Err(Error::Parse)
+        };
+    }
+    let mut chars = value.chars();
+    let mut key: i32 = match chars.next() {
+        Some('c') => 0,
+        Some('d') => 2,
+        Some('e') => 4,
+        Some('f') => 5,
+        Some('g') => 7,
+        Some('a') => 9,
+        Some('b') => 11,
+        _ => return Err(Error::Parse),
+    };
+    let rest = chars.as_str();
+    let octave = if let Some(rest) = rest.strip_prefix('#') {
+        key += 1;
+        rest
+    } else if let Some(rest) = rest.strip_prefix('b') {
+        key -= 1;
+        rest
+    } else {
+        rest
+    };
+    key += (parse::<i32>(octave)? + 1) * 12;
+    u8::try_from(key)
+        .ok()
+        .filter(|key| *key < 0x80)
+        .ok_or(Error::Parse)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Token {
+    Header(String),
+    Opcode(String, String),
+    Directive(String),
+}
+
+fn strip_comments(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("//") {
+            rest = after.find('\n').map_or("", |end| &after[end..]);
+        } else if let Some(after) = rest.strip_prefix("/*") {
+            rest = after.find("*/").map_or("", |end| &after[end + 2..]);
+        } else {
+            let c = rest.chars().next().unwrap();
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    out
+}
+
+fn is_opcode_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// checks if `text` starts with an opcode name followed by '='
+fn starts_opcode(text: &str) -> bool {
+    let name_len = text.chars().take_while(|c| is_opcode_char(*c)).count();
+    name_len > 0 && text[name_len..].starts_with('=')
+}
+
+fn tokenize(text: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    for line in text.lines() {
+        let mut rest = line.trim_start();
+        while !rest.is_empty() {
+            if let Some(after) = rest.strip_prefix('<') {
+                let end = after.find('>').unwrap_or(after.len());
+                tokens.push(Token::Header(after[..end].trim().to_string()));
+                rest = after.get(end + 1..).unwrap_or("");
+            } else if rest.starts_with('#') {
+                tokens.push(Token::Directive(rest.trim().to_string()));
+                rest = "";
+            } else if starts_opcode(rest) {
+                let eq = rest.find('=').unwrap();
+                let opcode = rest[..eq].to_string();
+                let value = &rest[eq + 1..];
+                // values may contain spaces, they end where the next opcode or header starts
+                let mut end = value.len();
+                for (i, c) in value.char_indices() {
+                    if c == '<' || (c.is_whitespace() && starts_opcode(value[i..].trim_start())) {
+                        end = i;
+                        break;
+                    }
+                }
+                tokens.push(Token::Opcode(opcode, value[..end].trim().to_string()));
+                rest = &value[end..];
+            } else {
+                // skip garbage up to the next whitespace
+                let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                rest = &rest[end..];
+            }
+            rest = rest.trim_start();
+        }
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SFZ: &str = "
+        // test instrument
+        <control> default_path=samples\\piano/
+        <global> ampeg_release=0.5 volume=-3
+        <group> lovel=0 hivel=63 seq_length=2
+        <region> sample=soft c4.wav lokey=c4 hikey=d#4 pitch_keycenter=c#4 seq_position=1
+        <region> sample=soft c4 rr.wav lokey=60 hikey=63 pitch_keycenter=61 seq_position=2
+        <group> lovel=64 tune=-12 /* loud */
+        <region> sample=loud.wav key=72 loop_mode=loop_sustain loop_start=10 loop_end=99 pan=50
+        <region> sample=fx.wav amp_velcurve_1=0.5 loop_mode=one_shot
+        <curve> curve_index=1
+    ";
+
+    #[test]
+    fn sfz_regions() {
+        let (builder, unsupported) = parse_sfz(SFZ, "piano", Path::new("sfz")).unwrap();
+        let zones = builder.zones;
+        assert_eq!(zones.len(), 4);
+
+        assert_eq!(
+            zones[0].sample,
+            SampleSource::Wav(PathBuf::from("sfz/samples/piano/soft c4.wav"))
+        );
+        assert_eq!(
+            (zones[0].lo_key, zones[0].hi_key, zones[0].root),
+            (60, 63, 61)
+        );
+        assert_eq!((zones[0].seq_length, zones[0].seq_position), (2, 1));
+        assert_eq!(zones[0].gain, -3.0);
+        assert!(zones[0].envelope.is_some());
+        assert_eq!(
+            zones[1].sample,
+            SampleSource::Wav(PathBuf::from("sfz/samples/piano/soft c4 rr.wav"))
+        );
+
+        assert_eq!(
+            (zones[2].lo_key, zones[2].hi_key, zones[2].root),
+            (72, 72, 72)
+        );
+        assert_eq!(zones[2].tune, -12.0);
+        assert_eq!(zones[2].pan, 0.5);
+        assert_eq!(
+            zones[2].loop_mode,
+            LoopMode::Sustain {
+                start: 10,
+                end: 100
+            }
+        );
+        assert_eq!(zones[3].loop_mode, LoopMode::OneShot);
+
+        assert_eq!(
+            unsupported
+                .iter()
+                .map(|u| u.opcode.as_str())
+                .collect::<Vec<_>>(),
+            vec!["amp_velcurve_1", ""]
+        );
+        assert_eq!(unsupported[1].header, "curve");
+    }
+
+    #[test]
+    fn inner_levels_win() {
+        let sfz = "
+            <global> lokey=30 hikey=40 tune=-12 loop_mode=loop_forever
+            <group> key=50 amp_veltrack=0
+            <region> sample=a.wav tune=5
+            <region> lokey=45
+            <region> sample=b.wav key=55 lokey=52
+        ";
+        let (builder, unsupported) = parse_sfz(sfz, "levels", Path::new("")).unwrap();
+        let zones = builder.zones;
+        assert_eq!(zones.len(), 2);
+        assert_eq!(
+            (zones[0].lo_key, zones[0].hi_key, zones[0].root),
+            (50, 50, 50)
+        );
+        assert_eq!(zones[0].tune, 5.0);
+        assert_eq!(
+            (zones[1].lo_key, zones[1].hi_key, zones[1].root),
+            (52, 55, 55)
+        );
+
+        let reported: Vec<(&str, &str)> = unsupported
+            .iter()
+            .map(|u| (u.header.as_str(), u.opcode.as_str()))
+            .collect();
+        assert_eq!(
+            reported,
+            vec![
+                ("group", "amp_veltrack"),
+                ("global", "loop_mode"),
+                ("region", "sample")
+            ]
+        );
+    }
+
+    #[test]
+    fn note_names() {
+        assert_eq!(parse_key("c4").unwrap(), 60);
+        assert_eq!(parse_key("C#4").unwrap(), 61);
+        assert_eq!(parse_key("eb-1").unwrap(), 3);
+        assert_eq!(parse_key("127").unwrap(), 127);
+        assert!(parse_key("g9").is_ok());
+        assert!(parse_key("a9").is_err());
+    }
+}
@@ -1,11 +1,21 @@
 #![warn(missing_debug_implementations)]
 
-use cpal::Stream;
+use analysis::ClipEvent;
+use effects::EffectPanel;
+use globals::{RENDER_WARNINGS, TIME_MANAGER, TUNING_MANAGER};
 use io::data::SongBuilder;
-use std::{collections::HashMap, convert::TryInto, fs::File, path::Path, u8};
+use resources::{SampleId, WavetableId};
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
 use tracks::{MidiTrack, Track};
+use tuning::{Tuning, TuningManager};
 use wave::Wave;
 
+pub mod analysis;
 pub mod effects;
 pub mod error;
 pub mod gens;
@@ -17,6 +27,7 @@ pub mod receivers;
 pub mod resources;
 pub mod time;
 pub mod tracks;
+pub mod tuning;
 pub mod utils;
 pub mod wave;
 
@@ -26,6 +37,38 @@ pub use error::Error;
 pub struct Song {
     name: String,
     tracks: HashMap<u8, Track>,
+    master: EffectPanel,
+    tuning: TuningManager,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RenderReport {
+    /// places where the output of the master bus exceeds 0 dBFS
+    pub clipping: Vec<ClipEvent>,
+    /// problems that were worked around while rendering
+    pub warnings: Vec<RenderWarning>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RenderWarning {
+    /// a drum kit has no pad for the note, `replacement` is the pitch of the pad played instead
+    UnmappedNote {
+        instrument: String,
+        pitch: u8,
+        replacement: Option<u8>,
+    },
+    /// the sample wasn't loaded and was rendered as silence
+    MissingSample { id: SampleId, path: Option<PathBuf> },
+    /// the wavetable wasn't loaded and was rendered as silence
+    MissingWavetable { id: WavetableId },
+}
+
+/// records a warning for the report of the next render, repeated warnings are only kept once
+pub(crate) fn warn(warning: RenderWarning) {
+    let mut warnings = RENDER_WARNINGS.write().unwrap();
+    if !warnings.contains(&warning) {
+        warnings.push(warning);
+    }
 }
 
 impl Song {
@@ -36,15 +79,113 @@ impl Song {
         Self {
             name: name.to_string(),
             tracks: HashMap::new(),
+            master: EffectPanel::EmptyLeaf,
+            tuning: TuningManager::default(),
         }
     }
 
     pub fn get_wave(&self) -> Wave {
+        self.render().0
+    }
+
+    pub fn render(&self) -> (Wave, RenderReport) {
+        self.use_tuning();
         let mut wave = Wave::new();
         for track in self.tracks.values() {
             wave.add(&track.play(), 0);
         }
-        wave
+        self.master
+            .apply_to(&mut wave, TIME_MANAGER.read().unwrap().abs_start());
+        let report = RenderReport {
+            clipping: analysis::clipping(&wave),
+            warnings: std::mem::take(&mut *RENDER_WARNINGS.write().unwrap()),
+        };
+        (wave, report)
+    }
+
+    /// renders the song through the master bus and writes it without normalizing
+    pub fn render_to(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<RenderReport, Box<dyn std::error::Error>> {
+        let (wave, report) = self.render();
+        wave.write_wav(path)?;
+        Ok(report)
+    }
+
+    pub fn get_master(&self) -> &EffectPanel {
+        &self.master
+    }
+
+    pub fn set_master(&mut self, master: EffectPanel) {
+        self.master = master
+    }
+
+    /// the tuning every track without its own tuning plays in
+    pub fn set_tuning(&mut self, tuning: Tuning) {
+        self.tuning.set_song_tuning(tuning)
+    }
+
+    /// `None` makes the track follow the song's tuning
+    pub fn set_track_tuning(&mut self, track_id: u8, tuning: Option<Tuning>) {
+        self.tuning.set_track_tuning(track_id, tuning)
+    }
+
+    pub fn get_tuning(&self) -> &TuningManager {
+        &self.tuning
+    }
+
+    /// makes the tuning of the song the one notes are played in
+    fn use_tuning(&self) {
+        *TUNING_MANAGER.write().unwrap() = self.tuning.clone();
+    }
+
+    /// renders every track to its own wav file in `dir`, all stems start at tick 0 and have the same length,
+    /// the stems are taken before the master bus and sum up to its input, the mix is written after it
+    pub fn export_stems(
+        &self,
+        dir: impl AsRef<Path>,
+        with_mix: bool,
+    ) -> Result<RenderReport, Box<dyn std::error::Error>> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        self.use_tuning();
+
+        let mut track_ids: Vec<&u8> = self.tracks.keys().collect();
+        track_ids.sort();
+        let mut stems: Vec<(String, Wave)> = track_ids
+            .into_iter()
+            .map(|id| {
+                let track = &self.tracks[id];
+                let name = match utils::file_name_safe(track.get_name()) {
+                    name if name.is_empty() => format!("{:02}_track", id),
+                    name => format!("{:02}_{}", id, name),
+                };
+                (name, track.play())
+            })
+            .collect();
+
+        let len = stems.iter().map(|(_, wave)| wave.len()).max().unwrap_or(0);
+        let mut mix = Wave::zeros(len);
+        for (name, stem) in stems.iter_mut() {
+            stem.resize(len, 0.0);
+            mix.add(stem, 0);
+            stem.write_wav(dir.join(format!("{}.wav", name)))?;
+        }
+
+        self.master
+            .apply_to(&mut mix, TIME_MANAGER.read().unwrap().abs_start());
+        if with_mix {
+            let name = match utils::file_name_safe(&self.name) {
+                name if name.is_empty() => "mix".to_string(),
+                name => name,
+            };
+            mix.write_wav(dir.join(format!("{}.wav", name)))?;
+        }
+        Ok(RenderReport {
+            clipping: analysis::clipping(&mix),
+            warnings: std::mem::take(&mut *RENDER_WARNINGS.write().unwrap()),
+        })
     }
 
     pub fn mut_midi_tracks(&mut self) -> Vec<&mut MidiTrack> {
@@ -87,3 +228,53 @@ impl Song {
 //         let host = cpal::
 //     }
 // }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{instr::drum_machine::DrumMachineBuilder, tracks::midi::note};
+
+    #[test]
+    fn stems_share_their_length() {
+        let mut song = Song::new("demo song");
+        for (id, name, key) in [(1, "kick", 36), (2, "crash cymbal", 49)] {
+            let mut track = MidiTrack::new(id);
+            track.set_name(name.to_string());
+            track.add_drum_machine(DrumMachineBuilder::default());
+            track.add_notes(vec![note(key, 0, 100, 0.8)]);
+            song.tracks.insert(id, Track::Midi(track));
+        }
+
+        let dir = std::env::temp_dir().join("song_stems_test");
+        song.export_stems(&dir, true).unwrap();
+        let lengths: Vec<usize> = ["01_kick", "02_crash_cymbal", "demo_song"]
+            .iter()
+            .map(|name| {
+                io::read_wav(dir.join(format!("{}.wav", name)))
+                    .unwrap()
+                    .len()
+            })
+            .collect();
+        assert!(lengths[0] > 0);
+        assert!(lengths.iter().all(|len| *len == lengths[0]));
+    }
+    #[test]
+    fn tunings_belong_to_the_song() {
+        let mut song = Song::new("tuned song");
+        let tuning = Tuning::new(
+            tuning::Scale::equal(19, 1200.0).unwrap(),
+            tuning::KeyboardMap::default(),
+        )
+        .unwrap();
+        song.set_tuning(tuning.clone());
+        song.set_track_tuning(3, Some(Tuning::default()));
+
+        assert_eq!(song.get_tuning().get(0), &tuning);
+        assert_eq!(song.get_tuning().get(3), &Tuning::default());
+        assert_eq!(TUNING_MANAGER.read().unwrap().get(0), &Tuning::default());
+        assert_eq!(
+            Song::new("other song").get_tuning().get(0),
+            &Tuning::default()
+        );
+    }
+}
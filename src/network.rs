@@ -8,6 +8,34 @@ pub enum Network {
     WeightedAverage(Vec<(f32, Network)>),
     WeightedProduct(Vec<(f32, Network)>),
     Inverted(Box<Network>),
+    Const(f32),
+    /// weighted sum without normalization, clamped to `0.0..=1.0`
+    Sum(Vec<(f32, Network)>),
+    Curve(Curve, Box<Network>),
+}
+
+/// shapes a value in `0.0..=1.0`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Curve {
+    #[default]
+    Linear,
+    Exponential,
+    Logarithmic,
+    SCurve,
+    /// maps to `-1.0..=1.0`, so sources like lfos or pitch bend modulate in both directions
+    Bipolar,
+}
+
+impl Curve {
+    pub fn apply(&self, x: f32) -> f32 {
+        match self {
+            Curve::Linear => x,
+            Curve::Exponential => x * x,
+            Curve::Logarithmic => x.max(0.0).sqrt(),
+            Curve::SCurve => x * x * (3.0 - 2.0 * x),
+            Curve::Bipolar => 2.0 * x - 1.0,
+        }
+    }
 }
 
 impl Network {
@@ -32,7 +60,15 @@ impl Network {
                 }
                 Ok(out)
             }
-            Network::Inverted(net) => net.get_ids(),
+            Network::Sum(vec) => {
+                let mut out = Vec::new();
+                for (_, net) in vec {
+                    out.append(&mut net.get_ids()?)
+                }
+                Ok(out)
+            }
+            Network::Inverted(net) | Network::Curve(_, net) => net.get_ids(),
+            Network::Const(_) => Ok(Vec::new()),
         }
     }
 
@@ -67,7 +103,20 @@ impl Network {
                     None
                 }
             }
+            Network::Sum(vec) => {
+                let new_vec: Vec<_> = vec
+                    .iter()
+                    .filter_map(|(w, net)| Some((*w, net.extract()?)))
+                    .collect();
+                if !new_vec.is_empty() {
+                    Some(Self::Sum(new_vec))
+                } else {
+                    None
+                }
+            }
             Network::Inverted(net) => net.extract(),
+            Network::Const(val) => Some(Self::Const(*val)),
+            Network::Curve(curve, net) => Some(Self::Curve(*curve, Box::new(net.extract()?))),
         }
     }
 
@@ -80,7 +129,9 @@ impl Network {
             Network::WeightedProduct(vec) => {
                 vec.iter_mut().for_each(|(_, net)| net.set_id(track_id))
             }
-            Network::Inverted(net) => net.set_id(track_id),
+            Network::Sum(vec) => vec.iter_mut().for_each(|(_, net)| net.set_id(track_id)),
+            Network::Inverted(net) | Network::Curve(_, net) => net.set_id(track_id),
+            Network::Const(_) => (),
         }
     }
 }
@@ -111,7 +162,14 @@ impl Network {
                 }
                 out.powf(1.0 / sum)
             }
-            Network::Inverted(net) => net.get_val(time) * -1.0 + 1.0,
+            Network::Inverted(net) => 1.0 - net.get_val(time),
+            Network::Const(val) => *val,
+            Network::Sum(vec) => vec
+                .iter()
+                .map(|(weight, net)| weight * net.get_val(time))
+                .sum::<f32>()
+                .clamp(0.0, 1.0),
+            Network::Curve(curve, net) => curve.apply(net.get_val(time)),
         }
     }
 
@@ -153,7 +211,22 @@ impl Network {
             Network::Inverted(net) => net
                 .get_vec(start, samples)
                 .into_iter()
-                .map(|x| x * -1.0 + 1.0)
+                .map(|x| 1.0 - x)
+                .collect(),
+            Network::Const(val) => vec![*val; samples],
+            Network::Sum(vec) => {
+                let mut out = vec![0.0; samples];
+                for (weight, net) in vec {
+                    for (o, x) in out.iter_mut().zip(net.get_vec(start, samples)) {
+                        *o += weight * x
+                    }
+                }
+                out.into_iter().map(|x| x.clamp(0.0, 1.0)).collect()
+            }
+            Network::Curve(curve, net) => net
+                .get_vec(start, samples)
+                .into_iter()
+                .map(|x| curve.apply(x))
                 .collect(),
         }
     }
@@ -162,12 +235,24 @@ impl Network {
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Transform {
     Linear,
+    /// the range is given in dB, the receiver puts out the corresponding factor
+    Decibel,
 }
 
 impl Transform {
     pub fn get_fn(&self, range: (f32, f32)) -> Box<dyn Fn(f32) -> f32> {
         match self {
             Transform::Linear => Box::new(move |x: f32| x * (range.1 - range.0) + range.0),
+            Transform::Decibel => {
+                Box::new(move |x: f32| utils::db_to_factor(x * (range.1 - range.0) + range.0))
+            }
+        }
+    }
+
+    pub fn get_value(&self, value: f32) -> f32 {
+        match self {
+            Transform::Linear => value,
+            Transform::Decibel => utils::db_to_factor(value),
         }
     }
 }
@@ -196,21 +281,28 @@ impl Receiver {
         parent_id: Option<GenId>,
     ) -> Result<(), Error> {
         match parent_id {
-            Some(id) => {
-                if network.get_ids()?.contains(&id) {
-                    Err(Error::Loop)
-                } else {
-                    self.network = Some(network);
-                    Ok(())
-                }
-            }
-            None => {
+            Some(id) if network.get_ids()?.contains(&id) => Err(Error::Loop),
+            _ => {
                 self.network = Some(network);
                 Ok(())
             }
         }
     }
 
+    /// adds the weighted sources on top of the current value or network
+    pub fn modulate(&mut self, sources: Vec<(f32, Network)>) {
+        if sources.is_empty() {
+            return;
+        }
+        let base = match self.network.take() {
+            Some(net) => net,
+            None => Network::Const((self.value - self.range.0) / (self.range.1 - self.range.0)),
+        };
+        let mut sum = vec![(1.0, base)];
+        sum.extend(sources);
+        self.network = Some(Network::Sum(sum));
+    }
+
     pub fn delete_network(&mut self) {
         self.network = None
     }
@@ -295,7 +387,7 @@ fn in_range(val: f32, range: (f32, f32)) -> bool {
 impl Receiver {
     pub fn get_vec(&self, start: ClockTick, samples: usize) -> Vec<f32> {
         match &self.network {
-            None => vec![self.value; samples],
+            None => vec![self.transform.get_value(self.value); samples],
             Some(net) => net
                 .get_vec(start, samples)
                 .into_iter()
@@ -306,7 +398,7 @@ impl Receiver {
 
     pub fn get_val(&self, time: ClockTick) -> f32 {
         match &self.network {
-            None => self.value,
+            None => self.transform.get_value(self.value),
             Some(net) => self.transform.get_fn(self.range)(net.get_val(time)),
         }
     }
@@ -325,3 +417,22 @@ pub fn vec_or_none(
     }
     Ok(out)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn modulation_adds_to_value() {
+        let mut receiver = Receiver::new(0.5, (0.0, 2.0), Transform::Linear);
+        receiver.modulate(vec![(0.25, Network::Const(1.0))]);
+        assert_eq!(receiver.get_val(ClockTick::abs_zero()), 1.0);
+
+        receiver.modulate(vec![(
+            0.5,
+            Network::Curve(Curve::Bipolar, Box::new(Network::Const(0.0))),
+        )]);
+        assert_eq!(receiver.get_val(ClockTick::abs_zero()), 0.0);
+        assert_eq!(receiver.get_vec(ClockTick::abs_zero(), 3), vec![0.0; 3]);
+    }
+}
@@ -1,3 +1,5 @@
 use crate::network::{Receiver, Transform};
 
 pub const VOL_RECEIVER: Receiver = Receiver::new(1.0, (0.0, 5.0), Transform::Linear);
+
+pub const DB_VOL_RECEIVER: Receiver = Receiver::new(0.0, (-60.0, 12.0), Transform::Decibel);
@@ -1,25 +1,79 @@
 use std::{
     collections::{hash_map::Entry, HashMap},
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
 use serde::{Deserialize, Serialize};
 
-use crate::{io, wave::Wave, Error};
+use crate::{io, utils::wavetable::Wavetable, wave::Wave, Error, RenderWarning};
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct ResourceManager {
     #[serde(skip)]
     samples: HashMap<SampleId, Wave>,
     sample_path: HashMap<SampleId, PathBuf>,
+    #[serde(default)]
+    sf2_samples: HashMap<SampleId, Sf2Sample>,
+    #[serde(skip)]
+    wavetables: HashMap<WavetableId, Arc<Wavetable>>,
+    #[serde(default)]
+    wavetable_sources: HashMap<WavetableId, WavetableSource>,
 }
 
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub struct SampleId(u32);
 
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WavetableId(u32);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum WavetableSource {
+    /// consecutive single cycle frames of `frame_size` samples
+    Wav { path: PathBuf, frame_size: usize },
+    /// the amplitudes of the harmonics of every frame
+    Harmonics(Vec<Vec<f32>>),
+}
+
+impl WavetableSource {
+    fn load(&self) -> Result<Wavetable, Box<dyn std::error::Error>> {
+        match self {
+            WavetableSource::Wav { path, frame_size } => {
+                if *frame_size == 0 {
+                    Err(Error::Value)?
+                }
+                let wave = io::read_wav(path)?;
+                let frames = wave
+                    .right()
+                    .chunks_exact(*frame_size)
+                    .map(|frame| frame.to_vec())
+                    .collect();
+                Ok(Wavetable::from_frames(frames)?)
+            }
+            WavetableSource::Harmonics(frames) => Ok(Wavetable::from_harmonics(frames)?),
+        }
+    }
+}
+
+/// where the audio data of a sample comes from
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum SampleSource {
+    Wav(PathBuf),
+    Sf2(Sf2Sample),
+}
+
+/// a sample stored in the sample data of a SoundFont 2 file, start and end are in sample points
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Sf2Sample {
+    pub path: PathBuf,
+    pub start: u32,
+    pub end: u32,
+    pub sample_rate: u32,
+}
+
 impl ResourceManager {
     pub fn init(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let erroring: Vec<(SampleId, PathBuf)> = self
+        let mut erroring: Vec<(SampleId, PathBuf)> = self
             .sample_path
             .iter()
             .map(|(id, path)| -> Result<(), (&SampleId, &PathBuf)> {
@@ -34,6 +88,20 @@ impl ResourceManager {
             .map(|x| x.unwrap_err())
             .map(|(id, path)| (*id, path.clone()))
             .collect();
+        for (id, sample) in self.sf2_samples.iter() {
+            match io::sf2::read_sample(sample) {
+                Ok(wave) => {
+                    self.samples.insert(*id, wave);
+                }
+                Err(_) => erroring.push((*id, sample.path.clone())),
+            }
+        }
+        // missing wavetables are reported when they are played
+        for (id, source) in self.wavetable_sources.iter() {
+            if let Ok(table) = source.load() {
+                self.wavetables.insert(*id, Arc::new(table));
+            }
+        }
         if !erroring.is_empty() {
             Err(Error::Sample(erroring))?
         }
@@ -44,25 +112,45 @@ impl ResourceManager {
         Self {
             samples: Default::default(),
             sample_path: self.sample_path.clone(),
+            sf2_samples: self.sf2_samples.clone(),
+            wavetables: Default::default(),
+            wavetable_sources: self.wavetable_sources.clone(),
         }
     }
 }
 impl ResourceManager {
+    /// a sample which isn't loaded is returned as silence and reported as a render warning
     pub fn get_sample(&self, id: SampleId) -> Wave {
-        self.samples
-            .get(&id)
-            .expect("resource manager wasn't initialised correctly")
-            .clone()
+        match self.samples.get(&id) {
+            Some(wave) => wave.clone(),
+            None => {
+                let path = match self.get_source(id) {
+                    Ok(SampleSource::Wav(path)) => Some(path),
+                    Ok(SampleSource::Sf2(sample)) => Some(sample.path),
+                    Err(_) => None,
+                };
+                crate::warn(RenderWarning::MissingSample { id, path });
+                Wave::new()
+            }
+        }
     }
 
     pub fn add_sample(
         &mut self,
         path: impl AsRef<Path> + Clone,
     ) -> Result<SampleId, Box<dyn std::error::Error>> {
+        if let Some((id, _)) = self
+            .sample_path
+            .iter()
+            .find(|(_, known)| known.as_path() == path.as_ref())
+        {
+            return Ok(*id);
+        }
         for index in 0..u32::MAX {
             let id = SampleId(index);
             match self.sample_path.entry(id) {
                 Entry::Occupied(_) => continue,
+                Entry::Vacant(_) if self.sf2_samples.contains_key(&id) => continue,
                 Entry::Vacant(e) => {
                     let mut buf = PathBuf::new();
                     buf.push(path.clone());
@@ -75,6 +163,41 @@ impl ResourceManager {
         Err(Error::Overflow)?
     }
 
+    pub fn add_source(
+        &mut self,
+        source: &SampleSource,
+    ) -> Result<SampleId, Box<dyn std::error::Error>> {
+        match source {
+            SampleSource::Wav(path) => self.add_sample(path),
+            SampleSource::Sf2(sample) => {
+                if let Some((id, _)) = self.sf2_samples.iter().find(|(_, known)| *known == sample) {
+                    return Ok(*id);
+                }
+                let wave = io::sf2::read_sample(sample)?;
+                for index in 0..u32::MAX {
+                    let id = SampleId(index);
+                    if self.sample_path.contains_key(&id) || self.sf2_samples.contains_key(&id) {
+                        continue;
+                    }
+                    self.sf2_samples.insert(id, sample.clone());
+                    self.samples.insert(id, wave);
+                    return Ok(id);
+                }
+                Err(Error::Overflow)?
+            }
+        }
+    }
+
+    pub fn get_source(&self, id: SampleId) -> Result<SampleSource, Error> {
+        if let Some(path) = self.sample_path.get(&id) {
+            return Ok(SampleSource::Wav(path.clone()));
+        }
+        match self.sf2_samples.get(&id) {
+            Some(sample) => Ok(SampleSource::Sf2(sample.clone())),
+            None => Err(Error::Existence),
+        }
+    }
+
     pub fn get_path(&self, id: SampleId) -> Result<PathBuf, Error> {
         match self.sample_path.get(&id) {
             Some(path) => Ok(path.clone()),
@@ -82,3 +205,44 @@ impl ResourceManager {
         }
     }
 }
+
+impl ResourceManager {
+    pub fn add_wavetable(
+        &mut self,
+        source: WavetableSource,
+    ) -> Result<WavetableId, Box<dyn std::error::Error>> {
+        if let Some((id, _)) = self
+            .wavetable_sources
+            .iter()
+            .find(|(_, known)| **known == source)
+        {
+            return Ok(*id);
+        }
+        let table = source.load()?;
+        for index in 0..u32::MAX {
+            let id = WavetableId(index);
+            if let Entry::Vacant(e) = self.wavetable_sources.entry(id) {
+                e.insert(source);
+                self.wavetables.insert(id, Arc::new(table));
+                return Ok(id);
+            }
+        }
+        Err(Error::Overflow)?
+    }
+
+    pub fn get_wavetable_source(&self, id: WavetableId) -> Result<WavetableSource, Error> {
+        match self.wavetable_sources.get(&id) {
+            Some(source) => Ok(source.clone()),
+            None => Err(Error::Existence),
+        }
+    }
+
+    /// a wavetable which isn't loaded is reported as a render warning
+    pub fn get_wavetable(&self, id: WavetableId) -> Option<Arc<Wavetable>> {
+        let table = self.wavetables.get(&id).cloned();
+        if table.is_none() {
+            crate::warn(RenderWarning::MissingWavetable { id });
+        }
+        table
+    }
+}
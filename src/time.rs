@@ -1,4 +1,5 @@
 use derive_more::{Add, Sub};
+use midly::Timing;
 use serde::{Deserialize, Serialize};
 
 use crate::{
@@ -6,9 +7,18 @@ use crate::{
     utils::{self, XYPairs},
 };
 
+/// used when the midi file doesn't give a resolution
+const DEFAULT_TICKS_PER_BEAT: u32 = 480;
+
+fn default_ticks_per_beat() -> u32 {
+    DEFAULT_TICKS_PER_BEAT
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TimeManager {
     s_per_tick: XYPairs<ClockTick, f32>,
+    #[serde(default = "default_ticks_per_beat")]
+    ticks_per_beat: u32,
 }
 
 // signatures: XYPairs<ClockTick, Signature>, // stamp musical interpretation conversion
@@ -30,6 +40,7 @@ impl Default for TimeManager {
     fn default() -> Self {
         Self {
             s_per_tick: XYPairs::from_point(ClockTick::abs_zero(), 0.00001),
+            ticks_per_beat: DEFAULT_TICKS_PER_BEAT,
         } // TODO better value
     }
 }
@@ -76,6 +87,14 @@ impl TimeManager {
         self.second_to_tick(self.tick_to_second(tick) + seconds)
     }
 
+    pub fn ticks_per_beat(&self) -> u32 {
+        self.ticks_per_beat
+    }
+
+    pub fn beats_to_ticks(&self, beats: f32) -> f32 {
+        beats * self.ticks_per_beat as f32
+    }
+
     pub fn abs_start(&self) -> ClockTick {
         ClockTick(0)
     }
@@ -113,6 +132,10 @@ impl From<TimeDecoder> for TimeManager {
                 .clone()
                 .map_keys(ClockTick::new)
                 .map_values(|y| decoder.convert_mus_beat_to_s_tick(&y)),
+            ticks_per_beat: match decoder.midi_timeing {
+                Timing::Metrical(tpb) => tpb.as_int() as u32,
+                Timing::Timecode(_, _) => DEFAULT_TICKS_PER_BEAT,
+            },
         }
     }
 }
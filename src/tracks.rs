@@ -2,7 +2,9 @@ use crate::wave::Wave;
 use serde::{Deserialize, Serialize};
 
 pub mod midi;
+pub mod processors;
 pub use midi::MidiTrack;
+pub use processors::NoteProcessor;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Track {
@@ -1,18 +1,20 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
 
 use serde::{Deserialize, Serialize};
 
 use crate::{
     effects::EffectPanel,
-    gens::TI,
-    globals::{GENRATOR_MANAGER, RESOURCE_MANAGER, TIME_MANAGER},
+    gens::{point_defined::Interpolation, PointDefined},
+    globals::{TIME_MANAGER, TUNING_MANAGER},
     instr::{
-        drums::{Drums, DrumsBuilder},
-        synth::SynthBuilder,
-        MidiInstrument, Synthesizer,
+        drum_machine::DrumMachineBuilder, drums::DrumsBuilder, fm::FmSynthBuilder,
+        granular::GranularBuilder, layered::LayeredBuilder, pluck::PluckedStringBuilder,
+        sampler::SamplerBuilder, synth::SynthBuilder, InstrumentBuilder, MidiInstrument,
     },
-    resources::SampleId,
     time,
+    tracks::NoteProcessor,
+    tuning::ActiveTrack,
+    utils::{self, XYPairs},
     wave::Wave,
 };
 
@@ -37,17 +39,144 @@ impl Pitch {
         Self { value }
     }
 
-    pub fn get_freq(&self) -> f32 {
-        440.0 * 2.0_f32.powf((self.value as f32 - 69.0) / 12.0)
+    /// the frequency in the tuning of the track that is being played,
+    /// `None` if the tuning leaves the key unmapped
+    pub fn get_freq(&self) -> Option<f32> {
+        TUNING_MANAGER.read().unwrap().freq(self.value)
     }
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Note {
     pub pitch: Pitch,
     pub on: time::ClockTick,
     pub off: time::ClockTick,
     pub velocity: f32,
+    /// the controllers of the note's own channel, for notes of an mpe zone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expression: Option<Box<NoteExpression>>,
+}
+
+impl Note {
+    pub fn new(pitch: Pitch, on: time::ClockTick, off: time::ClockTick, velocity: f32) -> Self {
+        Self {
+            pitch,
+            on,
+            off,
+            velocity,
+            expression: None,
+        }
+    }
+
+    /// moves the note and its expression so it starts at `on`, keeping its length
+    pub fn moved_to(self, on: time::ClockTick) -> Self {
+        let offset = on.f32() as i64 - self.on.f32() as i64;
+        let mut expression = self.expression;
+        if let Some(expression) = &mut expression {
+            expression.shift(offset);
+        }
+        Self {
+            on,
+            off: on + (self.off - self.on),
+            expression,
+            ..self
+        }
+    }
+
+    /// the factors the frequency is bent by from the note on, 1.0 without expression
+    pub fn bend_factors(&self, samples: usize) -> Vec<f32> {
+        match &self.expression {
+            Some(expression) => {
+                let mut bend = expression.bend_cents(self.on, samples);
+                utils::cents_to_factor(&mut bend);
+                bend
+            }
+            None => vec![1.0; samples],
+        }
+    }
+}
+
+/// a note without expression, for tests
+#[cfg(test)]
+pub(crate) fn note(key: u8, on: u32, off: u32, velocity: f32) -> Note {
+    Note::new(
+        Pitch::new(key).unwrap(),
+        time::ClockTick::new(on),
+        time::ClockTick::new(off),
+        velocity,
+    )
+}
+
+/// per note controllers of an mpe voice, missing curves stay at their resting value
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct NoteExpression {
+    /// from 0.0 to 1.0, resting at 0.5
+    pub bend: XYPairs<time::ClockTick, f32>,
+    /// in semitones at full bend
+    pub bend_range: f32,
+    /// cc 74, resting at 0.5
+    pub timbre: XYPairs<time::ClockTick, f32>,
+    /// channel pressure, resting at 0.0
+    pub pressure: XYPairs<time::ClockTick, f32>,
+}
+
+impl NoteExpression {
+    pub fn bend_vec(&self, note_on: time::ClockTick, samples: usize) -> Vec<f32> {
+        curve_vec(&self.bend, 0.5, note_on, samples)
+    }
+
+    /// the bend as an offset in cents
+    pub fn bend_cents(&self, note_on: time::ClockTick, samples: usize) -> Vec<f32> {
+        let mut bend = self.bend_vec(note_on, samples);
+        for x in &mut bend {
+            *x = (*x - 0.5) * 2.0 * self.bend_range * 100.0;
+        }
+        bend
+    }
+
+    pub fn timbre_vec(&self, note_on: time::ClockTick, samples: usize) -> Vec<f32> {
+        curve_vec(&self.timbre, 0.5, note_on, samples)
+    }
+
+    pub fn pressure_vec(&self, note_on: time::ClockTick, samples: usize) -> Vec<f32> {
+        curve_vec(&self.pressure, 0.0, note_on, samples)
+    }
+
+    /// moves the curves by `offset` ticks, points moved before tick 0 are merged into it
+    pub fn shift(&mut self, offset: i64) {
+        for curve in [&mut self.bend, &mut self.timbre, &mut self.pressure] {
+            let (xs, ys) = curve.slices();
+            let mut points: Vec<(time::ClockTick, f32)> = Vec::with_capacity(xs.len());
+            for (x, y) in xs.iter().zip(ys) {
+                let x = time::ClockTick::new((x.f32() as i64 + offset).max(0) as u32);
+                match points.last_mut() {
+                    Some(last) if last.0 == x => last.1 = *y,
+                    _ => points.push((x, *y)),
+                }
+            }
+            let (xs, ys) = points.into_iter().unzip();
+            *curve = XYPairs::from_vecs(xs, ys);
+        }
+    }
+}
+
+fn curve_vec(
+    curve: &XYPairs<time::ClockTick, f32>,
+    rest: f32,
+    note_on: time::ClockTick,
+    samples: usize,
+) -> Vec<f32> {
+    if curve.is_empty() {
+        return vec![rest; samples];
+    }
+    let mut curve = curve.clone();
+    // the controller rests until its first change after the note on
+    if curve.slices().0[0] > note_on {
+        curve
+            .push(note_on, rest)
+            .expect("note on is before the first point");
+    }
+    PointDefined::from_xy_pairs(curve, Interpolation::Step).get_vec(note_on, samples)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -57,8 +186,13 @@ pub struct MidiTrack {
     // after_touch_id: Option<GenId>,
     pub instrument: MidiInstrument,
     gain: f32,
+    #[serde(default)]
+    gain_db: f32,
     effects: EffectPanel,
     notes: Vec<Note>,
+    /// applied in order before the notes reach the instrument
+    #[serde(default)]
+    processors: Vec<NoteProcessor>,
 }
 
 impl MidiTrack {
@@ -68,91 +202,112 @@ impl MidiTrack {
             track_id,
             instrument: MidiInstrument::empty(),
             gain: 1.0,
+            gain_db: 0.0,
             effects: EffectPanel::EmptyLeaf,
             notes: Vec::new(),
+            processors: Vec::new(),
         }
     }
 
     pub fn play(&self) -> Wave {
-        let mut wave = self.instrument.play_notes(&self.notes);
+        let mut wave = {
+            let _tuning = ActiveTrack::new(self.track_id);
+            self.instrument.play_notes(&self.processed_notes())
+        };
         self.effects
             .apply_to(&mut wave, TIME_MANAGER.read().unwrap().abs_start());
-        wave.scale(self.gain);
+        wave.scale(self.gain * utils::db_to_factor(self.gain_db));
         wave
     }
 
-    pub fn get_name(&self) -> &str {
-        &self.name
+    /// the notes as the instrument plays them
+    pub fn processed_notes(&self) -> Cow<'_, [Note]> {
+        if self.processors.is_empty() {
+            return Cow::Borrowed(&self.notes);
+        }
+        let mut notes = self.notes.clone();
+        for processor in &self.processors {
+            notes = processor.process(notes);
+        }
+        Cow::Owned(notes)
     }
 
-    pub fn add_synth(&mut self, data: SynthBuilder) {
-        let mut effects = data.effects;
-        effects.set_id(self.track_id);
+    pub fn add_processor(&mut self, processor: NoteProcessor) {
+        self.processors.push(processor)
+    }
 
-        let mut oscillators = data.oscillators;
-        oscillators.set_id(self.track_id);
+    pub fn get_processors(&self) -> &[NoteProcessor] {
+        &self.processors
+    }
 
-        let mut main_enevelope = data.main_enevelope;
-        main_enevelope.set_id(self.track_id);
+    pub fn set_processors(&mut self, processors: Vec<NoteProcessor>) {
+        self.processors = processors
+    }
 
-        let mut alt_enevelope = data.alt_enevelope;
-        alt_enevelope.set_id(self.track_id);
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
 
-        let mut lfo_1 = data.lfo_1;
-        lfo_1.set_id(self.track_id);
+    pub fn get_gain_db(&self) -> f32 {
+        self.gain_db
+    }
 
-        let mut lfo_2 = data.lfo_2;
-        lfo_2.set_id(self.track_id);
+    pub fn set_gain_db(&mut self, gain_db: f32) {
+        self.gain_db = gain_db
+    }
 
-        let mut pitch_receiver = data.pitch_receiver;
-        pitch_receiver.set_id(self.track_id);
+    pub fn add_synth(&mut self, data: SynthBuilder) -> Result<(), Box<dyn std::error::Error>> {
+        self.instrument = data.build(self.track_id)?.wrap_midi();
+        Ok(())
+    }
 
-        let mut volume_receiver = data.volume_receiver;
-        volume_receiver.set_id(self.track_id);
+    pub fn add_drums(&mut self, drums: DrumsBuilder) -> Result<(), Box<dyn std::error::Error>> {
+        self.instrument = MidiInstrument::Drums(Box::new(drums.build(self.track_id)?));
+        Ok(())
+    }
 
-        *GENRATOR_MANAGER
-            .write()
-            .unwrap()
-            .get_mut_instr_save(self.track_id)
-            .unwrap() = data
-            .instr_generator
-            .as_generator_save(self.track_id, TI::Instr);
-
-        let synth = Synthesizer {
-            name: data.name,
-            track_id: self.track_id,
-            effects,
-            oscillators,
-            main_enevelope,
-            alt_enevelope,
-            lfo_1,
-            lfo_2,
-            pitch_receiver,
-            volume_receiver,
-        };
+    pub fn add_fm(&mut self, fm: FmSynthBuilder) -> Result<(), Box<dyn std::error::Error>> {
+        self.instrument = fm.build(self.track_id)?.wrap_midi();
+        Ok(())
+    }
 
-        self.instrument = synth.wrap_midi();
+    pub fn add_drum_machine(&mut self, drums: DrumMachineBuilder) {
+        self.instrument = drums.build(self.track_id).wrap_midi();
     }
 
-    pub fn add_drums(&mut self, drums: DrumsBuilder) -> Result<(), Box<dyn std::error::Error>> {
-        let mut effects = drums.effects;
-        effects.set_id(self.track_id);
+    pub fn add_plucked_string(&mut self, string: PluckedStringBuilder) {
+        self.instrument = string.build(self.track_id).wrap_midi();
+    }
 
-        let mut volume = drums.volume;
-        volume.set_id(self.track_id);
+    pub fn add_granular(
+        &mut self,
+        granular: GranularBuilder,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.instrument = granular.build(self.track_id)?.wrap_midi();
+        Ok(())
+    }
 
-        let mut samples = HashMap::<Pitch, SampleId>::new();
-        for (pitch, path) in drums.samples {
-            samples.insert(pitch, RESOURCE_MANAGER.write().unwrap().add_sample(path)?);
-        }
+    pub fn add_layered(
+        &mut self,
+        layered: LayeredBuilder,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.instrument = layered.build(self.track_id)?.wrap_midi();
+        Ok(())
+    }
 
-        let drums = Drums {
-            name: drums.name,
-            effects,
-            volume,
-            samples,
-        };
-        self.instrument = MidiInstrument::Drums(Box::new(drums));
+    pub fn add_sampler(
+        &mut self,
+        sampler: SamplerBuilder,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.instrument = sampler.build(self.track_id)?.wrap_midi();
+        Ok(())
+    }
+
+    pub fn add_instrument(
+        &mut self,
+        instrument: InstrumentBuilder,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.instrument = instrument.build(self.track_id)?;
         Ok(())
     }
 }
@@ -175,3 +330,24 @@ impl MidiTrack {
         self.name = name
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn controllers_rest_until_their_first_change() {
+        let expression = NoteExpression {
+            bend: XYPairs::from_point(time::ClockTick::new(960), 1.0),
+            bend_range: 2.0,
+            ..Default::default()
+        };
+        let change = TIME_MANAGER
+            .read()
+            .unwrap()
+            .tick_to_sample(time::ClockTick::new(960));
+        let bend = expression.bend_vec(time::ClockTick::new(0), change + 10);
+        assert_eq!(bend[0], 0.5);
+        assert_eq!(bend[change + 5], 1.0);
+    }
+}
@@ -0,0 +1,36 @@
+use serde::{Deserialize, Serialize};
+
+use super::midi::Note;
+
+pub mod arpeggiator;
+pub mod transform;
+
+pub use arpeggiator::{ArpPattern, Arpeggiator};
+pub use transform::{Humanize, NoteLength, Quantize, ScaleTranspose, Velocity};
+
+/// turns the notes of a track into the notes its instrument plays, the notes of the track stay unchanged
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum NoteProcessor {
+    Arpeggiator(Arpeggiator),
+    /// in semitones
+    Transpose(i8),
+    ScaleTranspose(ScaleTranspose),
+    Quantize(Quantize),
+    Humanize(Humanize),
+    Velocity(Velocity),
+    Length(NoteLength),
+}
+
+impl NoteProcessor {
+    pub fn process(&self, notes: Vec<Note>) -> Vec<Note> {
+        match self {
+            NoteProcessor::Arpeggiator(arp) => arp.process(notes),
+            NoteProcessor::Transpose(semitones) => transform::transpose(notes, *semitones),
+            NoteProcessor::ScaleTranspose(transpose) => transpose.process(notes),
+            NoteProcessor::Quantize(quantize) => quantize.process(notes),
+            NoteProcessor::Humanize(humanize) => humanize.process(notes),
+            NoteProcessor::Velocity(velocity) => velocity.process(notes),
+            NoteProcessor::Length(length) => length.process(notes),
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    globals::TIME_MANAGER,
+    time::ClockTick,
+    tracks::midi::{Note, Pitch},
+    utils::Rng,
+    Error,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ArpPattern {
+    #[default]
+    Up,
+    Down,
+    /// up and back down without repeating the highest and lowest note
+    UpDown,
+    Random,
+    /// in the order the keys were pressed
+    AsPlayed,
+}
+
+/// plays the held notes one after another on a grid synced to the beat
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Arpeggiator {
+    pub pattern: ArpPattern,
+    /// octaves the pattern spans, 1 only plays the held notes
+    pub octaves: u8,
+    /// steps per beat
+    pub rate: f32,
+    /// share of a step a note is held for
+    pub gate: f32,
+    /// a released chord keeps playing until the next one, the last one ends with its notes
+    pub latch: bool,
+    /// of the random pattern
+    pub seed: u64,
+}
+
+impl Arpeggiator {
+    pub fn new(pattern: ArpPattern, rate: f32) -> Result<Self, Error> {
+        if rate <= 0.0 {
+            return Err(Error::Value);
+        }
+        Ok(Self {
+            pattern,
+            octaves: 1,
+            rate,
+            gate: 0.5,
+            latch: false,
+            seed: 0,
+        })
+    }
+
+    pub fn set_octaves(&mut self, octaves: u8) -> Result<(), Error> {
+        if octaves == 0 {
+            return Err(Error::Value);
+        }
+        self.octaves = octaves;
+        Ok(())
+    }
+
+    pub fn set_gate(&mut self, gate: f32) -> Result<(), Error> {
+        if gate <= 0.0 || gate > 1.0 {
+            return Err(Error::Value);
+        }
+        self.gate = gate;
+        Ok(())
+    }
+
+    /// the fields are public, so an arpeggiator with settings the setters would reject
+    /// plays the notes unchanged instead of stepping forever
+    pub fn process(&self, mut notes: Vec<Note>) -> Vec<Note> {
+        let Some(end) = notes.iter().map(|note| note.off).max() else {
+            return notes;
+        };
+        if !(self.rate > 0.0 && self.gate > 0.0 && self.gate <= 1.0) || self.octaves == 0 {
+            return notes;
+        }
+        let step = TIME_MANAGER.read().unwrap().beats_to_ticks(1.0 / self.rate) as f64;
+        // steps shorter than a tick don't fit on the grid
+        if step < 1.0 {
+            return notes;
+        }
+        notes.sort_by_key(|note| (note.on, note.pitch.get()));
+        let length = ((step * self.gate as f64) as u32).max(1);
+
+        let mut rng = Rng::new(self.seed);
+        let mut out = Vec::new();
+        let mut latched: Vec<&Note> = Vec::new();
+        let mut position = 0;
+        let mut k = (notes[0].on.f32() as f64 / step).ceil() as u64;
+        loop {
+            let tick = ClockTick::new((k as f64 * step).round() as u32);
+            if tick >= end {
+                break;
+            }
+            k += 1;
+            let held: Vec<&Note> = notes
+                .iter()
+                .filter(|note| note.on <= tick && tick < note.off)
+                .collect();
+            let chord = match (held.is_empty(), self.latch) {
+                (false, _) => {
+                    latched = held;
+                    &latched
+                }
+                (true, true) => &latched,
+                (true, false) => {
+                    // the pattern starts over with the next chord
+                    position = 0;
+                    continue;
+                }
+            };
+            let sequence = self.sequence(chord);
+            if sequence.is_empty() {
+                continue;
+            }
+            let (note, pitch) = match self.pattern {
+                ArpPattern::Random => sequence[rng.next_u64() as usize % sequence.len()],
+                _ => sequence[position % sequence.len()],
+            };
+            position += 1;
+            out.push(Note {
+                pitch,
+                off: tick + ClockTick::new(length),
+                ..note.clone().moved_to(tick)
+            });
+        }
+        out
+    }
+
+    /// one pass of the pattern over the chord
+    fn sequence<'a>(&self, chord: &[&'a Note]) -> Vec<(&'a Note, Pitch)> {
+        let mut keys: Vec<&Note> = chord.to_vec();
+        if self.pattern != ArpPattern::AsPlayed {
+            keys.sort_by_key(|note| note.pitch.get());
+        }
+        let mut sequence: Vec<(&Note, Pitch)> = (0..self.octaves)
+            .flat_map(|octave| {
+                keys.iter().filter_map(move |note| {
+                    Some((
+                        *note,
+                        Pitch::new(
+                            u8::try_from(note.pitch.get() as u16 + 12 * octave as u16).ok()?,
+                        )?,
+                    ))
+                })
+            })
+            .collect();
+        match self.pattern {
+            ArpPattern::Down => sequence.reverse(),
+            ArpPattern::UpDown if sequence.len() > 2 => {
+                let down: Vec<_> = sequence[1..sequence.len() - 1]
+                    .iter()
+                    .rev()
+                    .copied()
+                    .collect();
+                sequence.extend(down);
+            }
+            _ => (),
+        }
+        sequence
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tracks::midi::note;
+
+    #[test]
+    fn invalid_settings_play_the_notes() {
+        let chord = vec![note(64, 0, 960, 0.8), note(60, 0, 960, 0.8)];
+        let mut arp = Arpeggiator::new(ArpPattern::Up, 4.0).unwrap();
+        arp.rate = 0.0;
+        assert_eq!(arp.process(chord.clone()).len(), 2);
+        arp.rate = f32::INFINITY;
+        assert_eq!(arp.process(chord.clone()).len(), 2);
+        arp.rate = 4.0;
+        arp.gate = 0.0;
+        assert_eq!(arp.process(chord.clone()).len(), 2);
+        arp.gate = 0.5;
+        arp.octaves = 0;
+        assert_eq!(arp.process(chord).len(), 2);
+    }
+
+    #[test]
+    fn octaves_above_the_keyboard_are_left_out() {
+        let chord = vec![note(60, 0, 960, 0.8)];
+        let mut arp = Arpeggiator::new(ArpPattern::Up, 4.0).unwrap();
+        arp.set_octaves(30).unwrap();
+        let keys: Vec<u8> = arp
+            .sequence(&chord.iter().collect::<Vec<_>>())
+            .iter()
+            .map(|(_, pitch)| pitch.get())
+            .collect();
+        assert_eq!(keys, vec![60, 72, 84, 96, 108, 120]);
+        assert!(!arp.process(chord).is_empty());
+    }
+
+    #[test]
+    fn up_down_over_two_octaves() {
+        let tpb = TIME_MANAGER.read().unwrap().ticks_per_beat();
+        let chord = vec![note(64, 0, tpb * 2, 0.8), note(60, 0, tpb * 2, 0.8)];
+
+        let mut arp = Arpeggiator::new(ArpPattern::UpDown, 4.0).unwrap();
+        arp.set_octaves(2).unwrap();
+        let notes = arp.process(chord.clone());
+        let keys: Vec<u8> = notes.iter().map(|note| note.pitch.get()).collect();
+        assert_eq!(keys, vec![60, 64, 72, 76, 72, 64, 60, 64]);
+        assert_eq!(notes[1].on, ClockTick::new(tpb / 4));
+        assert_eq!(notes[1].off, ClockTick::new(tpb / 4 + tpb / 8));
+
+        // the released chord keeps playing until the next one
+        let mut arp = Arpeggiator::new(ArpPattern::Down, 1.0).unwrap();
+        arp.latch = true;
+        let mut notes = chord;
+        notes.push(note(50, tpb * 4, tpb * 5, 0.8));
+        let keys: Vec<u8> = arp
+            .process(notes)
+            .iter()
+            .map(|note| note.pitch.get())
+            .collect();
+        assert_eq!(keys, vec![64, 60, 64, 60, 50]);
+    }
+}
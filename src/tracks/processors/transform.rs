@@ -0,0 +1,360 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    globals::TIME_MANAGER,
+    network::Curve,
+    time::ClockTick,
+    tracks::midi::{Note, Pitch},
+    utils::Rng,
+    Error,
+};
+
+pub const MAJOR: [u8; 7] = [0, 2, 4, 5, 7, 9, 11];
+pub const MINOR: [u8; 7] = [0, 2, 3, 5, 7, 8, 10];
+
+/// moves the notes by semitones, notes moved out of the midi range are dropped
+pub fn transpose(notes: Vec<Note>, semitones: i8) -> Vec<Note> {
+    notes
+        .into_iter()
+        .filter_map(|note| {
+            Some(Note {
+                pitch: offset(note.pitch, semitones as i32)?,
+                ..note
+            })
+        })
+        .collect()
+}
+
+fn offset(pitch: Pitch, semitones: i32) -> Option<Pitch> {
+    Pitch::new(u8::try_from(pitch.get() as i32 + semitones).ok()?)
+}
+
+/// moves the notes by degrees of a scale, notes outside of the scale keep their distance to the degree below
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScaleTranspose {
+    /// pitch class of the first degree, 0 is C
+    pub root: u8,
+    /// semitones of the degrees above the root, ascending and below 12
+    pub scale: Vec<u8>,
+    pub degrees: i32,
+}
+
+impl ScaleTranspose {
+    pub fn new(root: u8, scale: &[u8], degrees: i32) -> Result<Self, Error> {
+        if root >= 12
+            || scale.first() != Some(&0)
+            || scale.windows(2).any(|pair| pair[0] >= pair[1])
+            || scale.last().is_none_or(|last| *last >= 12)
+        {
+            return Err(Error::Value);
+        }
+        Ok(Self {
+            root,
+            scale: scale.to_vec(),
+            degrees,
+        })
+    }
+
+    pub fn process(&self, notes: Vec<Note>) -> Vec<Note> {
+        notes
+            .into_iter()
+            .filter_map(|note| {
+                Some(Note {
+                    pitch: self.transpose(note.pitch)?,
+                    ..note
+                })
+            })
+            .collect()
+    }
+
+    fn transpose(&self, pitch: Pitch) -> Option<Pitch> {
+        let len = self.scale.len() as i32;
+        let from_root = pitch.get() as i32 - self.root as i32;
+        let class = from_root.rem_euclid(12) as u8;
+        let index = self.scale.iter().rposition(|step| *step <= class)? as i32;
+        let degree = index + self.degrees;
+        let step = |degree: i32| {
+            12 * degree.div_euclid(len) + self.scale[degree.rem_euclid(len) as usize] as i32
+        };
+        offset(pitch, step(degree) - step(index))
+    }
+}
+
+/// pulls the notes towards a grid, keeping their length
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Quantize {
+    /// in beats
+    pub grid: f32,
+    /// how far the notes are moved towards the grid, 1.0 puts them on it
+    pub strength: f32,
+    /// delays every second grid point, 1.0 by half a grid
+    pub swing: f32,
+}
+
+impl Quantize {
+    pub fn new(grid: f32, strength: f32, swing: f32) -> Result<Self, Error> {
+        let quantize = Self {
+            grid,
+            strength,
+            swing,
+        };
+        if !quantize.is_valid() {
+            return Err(Error::Value);
+        }
+        Ok(quantize)
+    }
+
+    fn is_valid(&self) -> bool {
+        self.grid > 0.0
+            && self.grid.is_finite()
+            && (0.0..=1.0).contains(&self.strength)
+            && (0.0..=1.0).contains(&self.swing)
+    }
+
+    pub fn process(&self, notes: Vec<Note>) -> Vec<Note> {
+        if !self.is_valid() {
+            return notes;
+        }
+        let grid = TIME_MANAGER.read().unwrap().beats_to_ticks(self.grid);
+        notes
+            .into_iter()
+            .map(|note| {
+                let on = note.on.f32();
+                let index = (on / grid).round();
+                let mut target = index * grid;
+                if index as u64 % 2 == 1 {
+                    target += self.swing * grid / 2.0;
+                }
+                let on = (on + (target - on) * self.strength).round().max(0.0) as u32;
+                note.moved_to(ClockTick::new(on))
+            })
+            .collect()
+    }
+}
+
+/// randomly moves the notes and changes their velocity, the same seed gives the same result
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Humanize {
+    /// largest offset in beats
+    pub timing: f32,
+    /// largest change of the velocity
+    pub velocity: f32,
+    pub seed: u64,
+}
+
+impl Humanize {
+    pub fn new(timing: f32, velocity: f32, seed: u64) -> Result<Self, Error> {
+        if timing < 0.0 || !(0.0..=1.0).contains(&velocity) {
+            return Err(Error::Value);
+        }
+        Ok(Self {
+            timing,
+            velocity,
+            seed,
+        })
+    }
+
+    pub fn process(&self, notes: Vec<Note>) -> Vec<Note> {
+        let timing = TIME_MANAGER.read().unwrap().beats_to_ticks(self.timing);
+        let mut rng = Rng::new(self.seed);
+        notes
+            .into_iter()
+            .map(|note| {
+                let on = (note.on.f32() + rng.noise() * timing).round().max(0.0) as u32;
+                let velocity = (note.velocity + rng.noise() * self.velocity).clamp(0.0, 1.0);
+                Note {
+                    velocity,
+                    ..note.moved_to(ClockTick::new(on))
+                }
+            })
+            .collect()
+    }
+}
+
+/// shapes the velocities, then pulls them towards their average
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Velocity {
+    pub curve: Curve,
+    /// 1.0 gives every note the average velocity
+    pub compression: f32,
+}
+
+impl Velocity {
+    pub fn new(curve: Curve, compression: f32) -> Result<Self, Error> {
+        if !(0.0..=1.0).contains(&compression) {
+            return Err(Error::Value);
+        }
+        Ok(Self { curve, compression })
+    }
+
+    pub fn process(&self, mut notes: Vec<Note>) -> Vec<Note> {
+        for note in &mut notes {
+            note.velocity = self.curve.apply(note.velocity).clamp(0.0, 1.0);
+        }
+        let average = notes.iter().map(|note| note.velocity).sum::<f32>() / notes.len() as f32;
+        for note in &mut notes {
+            note.velocity += (average - note.velocity) * self.compression;
+        }
+        notes
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum NoteLength {
+    /// every note lasts until the next one starts
+    Legato,
+    /// keeps a share of every note's length
+    Staccato(f32),
+}
+
+impl NoteLength {
+    /// `share` has to be above 0.0 and at most 1.0
+    pub fn staccato(share: f32) -> Result<Self, Error> {
+        if !(share > 0.0 && share <= 1.0) {
+            return Err(Error::Value);
+        }
+        Ok(NoteLength::Staccato(share))
+    }
+
+    pub fn process(&self, mut notes: Vec<Note>) -> Vec<Note> {
+        match self {
+            NoteLength::Legato => {
+                let mut onsets: Vec<ClockTick> = notes.iter().map(|note| note.on).collect();
+                onsets.sort();
+                onsets.dedup();
+                for note in &mut notes {
+                    let next = onsets.partition_point(|on| *on <= note.on);
+                    if let Some(next) = onsets.get(next) {
+                        note.off = *next;
+                    }
+                }
+            }
+            NoteLength::Staccato(share) if *share > 0.0 && *share <= 1.0 => {
+                for note in &mut notes {
+                    let length = ((note.off - note.on).f32() * share).round().max(1.0);
+                    note.off = note.on + ClockTick::new(length as u32);
+                }
+            }
+            NoteLength::Staccato(_) => (),
+        }
+        notes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        tracks::midi::{note, NoteExpression},
+        utils::XYPairs,
+    };
+
+    fn notes() -> Vec<Note> {
+        let tpb = TIME_MANAGER.read().unwrap().ticks_per_beat();
+        vec![
+            note(60, 10, tpb, 0.2),
+            note(64, tpb / 2 - 10, tpb, 1.0),
+            note(66, tpb * 3 / 2, tpb * 2, 0.6),
+        ]
+    }
+
+    fn keys(notes: &[Note]) -> Vec<u8> {
+        notes.iter().map(|note| note.pitch.get()).collect()
+    }
+
+    fn ons(notes: &[Note]) -> Vec<ClockTick> {
+        notes.iter().map(|note| note.on).collect()
+    }
+
+    #[test]
+    fn transpose_drops_notes_out_of_range() {
+        assert_eq!(keys(&transpose(notes(), 62)), vec![122, 126]);
+    }
+
+    #[test]
+    fn scale_transpose() {
+        // a third up in c major, the f sharp keeps its distance to f
+        let thirds = ScaleTranspose::new(0, &MAJOR, 2).unwrap();
+        assert_eq!(keys(&thirds.process(notes())), vec![64, 67, 70]);
+        assert!(ScaleTranspose::new(0, &[2, 4], 1).is_err());
+    }
+
+    #[test]
+    fn quantize_with_swing() {
+        let tpb = TIME_MANAGER.read().unwrap().ticks_per_beat();
+        let mut notes = notes();
+        notes[0].expression = Some(Box::new(NoteExpression {
+            pressure: XYPairs::from_vecs(
+                vec![ClockTick::new(10), ClockTick::new(20)],
+                vec![0.5, 1.0],
+            ),
+            ..Default::default()
+        }));
+        let quantized = Quantize::new(0.5, 1.0, 0.5).unwrap().process(notes);
+        assert_eq!(
+            ons(&quantized),
+            vec![
+                ClockTick::new(0),
+                ClockTick::new(tpb / 2 + tpb / 8),
+                ClockTick::new(tpb * 3 / 2 + tpb / 8)
+            ]
+        );
+        assert_eq!(quantized[0].off, ClockTick::new(tpb - 10));
+        // the expression moves with the note
+        let pressure = quantized[0]
+            .expression
+            .as_ref()
+            .unwrap()
+            .pressure
+            .slices()
+            .0;
+        assert_eq!(pressure, &[ClockTick::new(0), ClockTick::new(10)]);
+    }
+
+    #[test]
+    fn humanize_is_seeded() {
+        let humanize = Humanize::new(0.1, 0.1, 3).unwrap();
+        assert_eq!(
+            ons(&humanize.process(notes())),
+            ons(&humanize.process(notes()))
+        );
+    }
+
+    #[test]
+    fn velocity_compression() {
+        let velocity = Velocity::new(Curve::Linear, 1.0).unwrap();
+        assert!(velocity
+            .process(notes())
+            .iter()
+            .all(|note| (note.velocity - 0.6).abs() < 1e-6));
+    }
+
+    #[test]
+    fn note_lengths() {
+        let tpb = TIME_MANAGER.read().unwrap().ticks_per_beat();
+        let legato = NoteLength::Legato.process(notes());
+        assert_eq!(legato[0].off, ClockTick::new(tpb / 2 - 10));
+        assert_eq!(legato[2].off, ClockTick::new(tpb * 2));
+        let staccato = NoteLength::staccato(0.5).unwrap().process(notes());
+        assert_eq!(staccato[2].off, ClockTick::new(tpb * 7 / 4));
+    }
+
+    #[test]
+    fn invalid_settings_keep_the_notes() {
+        assert!(Quantize::new(0.0, 1.0, 0.0).is_err());
+        assert!(Quantize::new(f32::NAN, 1.0, 0.0).is_err());
+        assert!(NoteLength::staccato(0.0).is_err());
+        assert!(NoteLength::staccato(1.5).is_err());
+
+        let mut quantize = Quantize::new(0.5, 1.0, 0.0).unwrap();
+        quantize.grid = 0.0;
+        assert_eq!(ons(&quantize.process(notes())), ons(&notes()));
+        for share in [0.0, -1.0, f32::NAN] {
+            let kept = NoteLength::Staccato(share).process(notes());
+            assert!(kept
+                .iter()
+                .zip(notes())
+                .all(|(kept, note)| kept.off == note.off));
+        }
+    }
+}
@@ -0,0 +1,331 @@
+use std::{collections::HashMap, sync::PoisonError};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{globals::TUNING_MANAGER, Error};
+
+/// the steps of a scale in cents above its first degree, the last step is the period
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "ScaleBuilder")]
+pub struct Scale {
+    pub description: String,
+    pub(crate) steps: Vec<f32>,
+}
+
+/// a scale as it is saved, checked by `Scale::new` when loaded
+#[derive(Deserialize)]
+struct ScaleBuilder {
+    description: String,
+    steps: Vec<f32>,
+}
+
+impl TryFrom<ScaleBuilder> for Scale {
+    type Error = Error;
+
+    fn try_from(value: ScaleBuilder) -> Result<Self, Self::Error> {
+        Self::new(&value.description, value.steps)
+    }
+}
+
+impl Scale {
+    pub fn new(description: &str, steps: Vec<f32>) -> Result<Self, Error> {
+        match steps.last() {
+            Some(period) if *period > 0.0 => Ok(Self {
+                description: description.to_string(),
+                steps,
+            }),
+            _ => Err(Error::Value),
+        }
+    }
+
+    /// `steps` equal divisions of `period` cents
+    pub fn equal(steps: usize, period: f32) -> Result<Self, Error> {
+        Self::new(
+            &format!("{} equal divisions of {} cents", steps, period),
+            (1..=steps)
+                .map(|i| period * i as f32 / steps as f32)
+                .collect(),
+        )
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    pub fn period(&self) -> f32 {
+        self.steps[self.steps.len() - 1]
+    }
+
+    /// cents of a degree above the first one, degrees past the period repeat the scale
+    pub fn degree_cents(&self, degree: i32) -> f32 {
+        let len = self.steps.len() as i32;
+        let step = degree.rem_euclid(len) as usize;
+        let cents = if step == 0 { 0.0 } else { self.steps[step - 1] };
+        cents + degree.div_euclid(len) as f32 * self.period()
+    }
+}
+
+/// which midi keys play which scale degree, like a scala keyboard mapping
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeyboardMap {
+    pub first_key: u8,
+    pub last_key: u8,
+    /// the key playing the first degree of the scale
+    pub middle_key: u8,
+    pub reference_key: u8,
+    /// in Hz
+    pub reference_freq: f32,
+    /// the degree one repetition of the mapping spans
+    pub octave_degree: usize,
+    /// the degrees of the keys from `middle_key` onwards, `None` keys are not played,
+    /// an empty mapping plays the degrees in order
+    pub mapping: Vec<Option<usize>>,
+}
+
+impl KeyboardMap {
+    /// plays the degrees in order, starting at `middle_key`
+    pub fn linear(middle_key: u8, reference_key: u8, reference_freq: f32) -> Self {
+        Self {
+            first_key: 0,
+            last_key: 127,
+            middle_key,
+            reference_key,
+            reference_freq,
+            octave_degree: 0,
+            mapping: Vec::new(),
+        }
+    }
+
+    /// cents of the key above the middle key
+    fn cents(&self, scale: &Scale, key: u8) -> Option<f32> {
+        if key < self.first_key || key > self.last_key {
+            return None;
+        }
+        let offset = key as i32 - self.middle_key as i32;
+        if self.mapping.is_empty() {
+            return Some(scale.degree_cents(offset));
+        }
+        let len = self.mapping.len() as i32;
+        let degree = self.mapping[offset.rem_euclid(len) as usize]?;
+        Some(
+            scale.degree_cents(degree as i32)
+                + offset.div_euclid(len) as f32 * scale.degree_cents(self.octave_degree as i32),
+        )
+    }
+}
+
+impl Default for KeyboardMap {
+    /// C4 at the frequency it has in 12-TET with A4 at 440Hz
+    fn default() -> Self {
+        Self::linear(60, 60, 261.62558)
+    }
+}
+
+/// frequencies of the midi keys
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "TuningBuilder")]
+pub struct Tuning {
+    scale: Scale,
+    keyboard: KeyboardMap,
+}
+
+/// a tuning as it is saved, checked by `Tuning::new` when loaded
+#[derive(Deserialize)]
+struct TuningBuilder {
+    scale: Scale,
+    keyboard: KeyboardMap,
+}
+
+impl TryFrom<TuningBuilder> for Tuning {
+    type Error = Error;
+
+    fn try_from(value: TuningBuilder) -> Result<Self, Self::Error> {
+        Self::new(value.scale, value.keyboard)
+    }
+}
+
+impl Tuning {
+    pub fn new(scale: Scale, keyboard: KeyboardMap) -> Result<Self, Error> {
+        if scale.is_empty()
+            || keyboard.reference_freq <= 0.0
+            || keyboard.cents(&scale, keyboard.reference_key).is_none()
+        {
+            return Err(Error::Value);
+        }
+        Ok(Self { scale, keyboard })
+    }
+
+    /// the key at which the tuning is anchored, which has to be mapped
+    pub fn set_reference(&mut self, key: u8, freq: f32) -> Result<(), Error> {
+        if freq <= 0.0 || self.keyboard.cents(&self.scale, key).is_none() {
+            return Err(Error::Value);
+        }
+        self.keyboard.reference_key = key;
+        self.keyboard.reference_freq = freq;
+        Ok(())
+    }
+
+    pub fn get_scale(&self) -> &Scale {
+        &self.scale
+    }
+
+    pub fn get_keyboard(&self) -> &KeyboardMap {
+        &self.keyboard
+    }
+
+    /// `None` for keys the keyboard map leaves unmapped
+    pub fn freq(&self, key: u8) -> Option<f32> {
+        let cents = self.keyboard.cents(&self.scale, key)?;
+        let reference = self
+            .keyboard
+            .cents(&self.scale, self.keyboard.reference_key)
+            .unwrap_or(0.0);
+        Some(self.keyboard.reference_freq * 2.0_f32.powf((cents - reference) / 1200.0))
+    }
+}
+
+impl Default for Tuning {
+    /// 12-TET with A4 at 440Hz
+    fn default() -> Self {
+        Self {
+            scale: Scale::equal(12, 1200.0).unwrap(),
+            keyboard: KeyboardMap::default(),
+        }
+    }
+}
+
+/// the tuning of the song and of the tracks which override it
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TuningManager {
+    song: Tuning,
+    tracks: HashMap<u8, Tuning>,
+    /// the track that is being played
+    #[serde(skip)]
+    active_track: Option<u8>,
+}
+
+impl TuningManager {
+    pub fn set_song_tuning(&mut self, tuning: Tuning) {
+        self.song = tuning
+    }
+
+    /// `None` makes the track follow the song's tuning again
+    pub fn set_track_tuning(&mut self, track_id: u8, tuning: Option<Tuning>) {
+        match tuning {
+            Some(tuning) => self.tracks.insert(track_id, tuning),
+            None => self.tracks.remove(&track_id),
+        };
+    }
+
+    pub fn get(&self, track_id: u8) -> &Tuning {
+        self.tracks.get(&track_id).unwrap_or(&self.song)
+    }
+
+    /// the frequency of the key on the track that is being played
+    pub fn freq(&self, key: u8) -> Option<f32> {
+        match self.active_track {
+            Some(track_id) => self.get(track_id).freq(key),
+            None => self.song.freq(key),
+        }
+    }
+
+    pub(crate) fn set_active_track(&mut self, track_id: Option<u8>) {
+        self.active_track = track_id
+    }
+}
+
+/// keys are played in the tuning of the track while it lives,
+/// when it is dropped the track that was active before is again, even after a panic
+#[derive(Debug)]
+pub(crate) struct ActiveTrack {
+    previous: Option<u8>,
+}
+
+impl ActiveTrack {
+    pub(crate) fn new(track_id: u8) -> Self {
+        let mut manager = TUNING_MANAGER.write().unwrap();
+        let previous = manager.active_track.replace(track_id);
+        Self { previous }
+    }
+}
+
+impl Drop for ActiveTrack {
+    fn drop(&mut self) {
+        TUNING_MANAGER
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .set_active_track(self.previous)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils;
+
+    #[test]
+    fn default_is_twelve_tet() {
+        let tuning = Tuning::default();
+        for key in 0..128 {
+            let freq = tuning.freq(key).unwrap();
+            assert!((freq / utils::pitch_to_freq(key) - 1.0).abs() < 1e-4);
+        }
+
+        let mut manager = TuningManager::default();
+        let mut keyboard = KeyboardMap::linear(60, 69, 432.0);
+        keyboard.first_key = 21;
+        manager.set_track_tuning(
+            3,
+            Some(Tuning::new(Scale::equal(12, 1200.0).unwrap(), keyboard).unwrap()),
+        );
+        manager.set_active_track(Some(3));
+        assert_eq!(manager.freq(69), Some(432.0));
+        assert_eq!(manager.freq(20), None);
+        manager.set_active_track(Some(2));
+        assert!((manager.freq(69).unwrap() - 440.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn active_tracks_are_reset() {
+        let active = || TUNING_MANAGER.read().unwrap().active_track;
+        {
+            let _outer = ActiveTrack::new(200);
+            {
+                let _inner = ActiveTrack::new(201);
+                assert_eq!(active(), Some(201));
+            }
+            assert_eq!(active(), Some(200));
+        }
+        assert_eq!(active(), None);
+
+        let result = std::panic::catch_unwind(|| {
+            let _track = ActiveTrack::new(202);
+            panic!("render failed");
+        });
+        assert!(result.is_err());
+        assert_eq!(active(), None);
+    }
+
+    #[test]
+    fn loading_checks_the_tuning() {
+        let tuning = Tuning::default();
+        let text = ron::to_string(&tuning).unwrap();
+        assert_eq!(ron::from_str::<Tuning>(&text).unwrap(), tuning);
+
+        let empty = text.replacen(&ron::to_string(&tuning.scale.steps).unwrap(), "[]", 1);
+        assert!(ron::from_str::<Tuning>(&empty).is_err());
+        let unmapped = ron::to_string(&Tuning {
+            scale: tuning.scale.clone(),
+            keyboard: KeyboardMap {
+                first_key: 61,
+                ..KeyboardMap::default()
+            },
+        })
+        .unwrap();
+        assert!(ron::from_str::<Tuning>(&unmapped).is_err());
+    }
+}
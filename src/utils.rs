@@ -1,6 +1,7 @@
 use std::{
     io,
     ops::{AddAssign, MulAssign},
+    path::{Path, PathBuf},
 };
 
 use serde::{Deserialize, Serialize};
@@ -8,6 +9,7 @@ use serde::{Deserialize, Serialize};
 use crate::{globals::SAMPLE_RATE, Error};
 
 pub mod oscs;
+pub mod wavetable;
 
 #[inline(always)]
 pub fn seconds_to_samples(seconds: f32) -> usize {
@@ -48,6 +50,22 @@ pub fn mul_elementwise<T: MulAssign + Copy>(v1: &mut Vec<T>, v2: &[T]) {
     }
 }
 
+/// frequency of a midi key in 12-TET with A4 at 440Hz
+#[inline(always)]
+pub fn pitch_to_freq(key: u8) -> f32 {
+    440.0 * 2.0_f32.powf((key as f32 - 69.0) / 12.0)
+}
+
+#[inline(always)]
+pub fn db_to_factor(db: f32) -> f32 {
+    10.0_f32.powf(db / 20.0)
+}
+
+#[inline(always)]
+pub fn factor_to_db(factor: f32) -> f32 {
+    20.0 * factor.log10()
+}
+
 #[inline(always)] // TODO is this needed
 pub fn cents_to_factor(cents: &mut [f32]) {
     cents.iter_mut().for_each(|x| *x = fast_pow2(*x / 1200.0))
@@ -72,8 +90,8 @@ pub fn fast_pow2(x: f32) -> f32 {
 
 #[cfg(test)]
 mod test {
-    use std::ptr::read_volatile;
     use super::fast_pow2;
+    use std::ptr::read_volatile;
 
     #[test]
     fn pow2_test() {
@@ -124,6 +142,19 @@ pub fn max_abs_f32(vec: &[f32]) -> f32 {
     f32::max(f32::abs(max), f32::abs(min))
 }
 
+pub fn file_name_safe(name: &str) -> String {
+    name.trim()
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
 pub fn user_input(prompt: &str) -> String {
     println!("{}", prompt);
 
@@ -300,3 +331,56 @@ impl<K: Copy, V: Copy> Pair<K, V> {
         self.1
     }
 }
+
+/// `path` relative to the directory `base`, kept as it is if the two don't share a root
+pub fn relative_path(path: &Path, base: &Path) -> PathBuf {
+    let (Ok(abs_path), Ok(abs_base)) = (std::path::absolute(path), std::path::absolute(base))
+    else {
+        return path.to_path_buf();
+    };
+    let path_parts: Vec<_> = abs_path.components().collect();
+    let base_parts: Vec<_> = abs_base.components().collect();
+    let common = path_parts
+        .iter()
+        .zip(&base_parts)
+        .take_while(|(a, b)| a == b)
+        .count();
+    if common == 0 {
+        return path.to_path_buf();
+    }
+    let mut out = PathBuf::new();
+    for _ in common..base_parts.len() {
+        out.push("..");
+    }
+    for part in &path_parts[common..] {
+        out.push(part);
+    }
+    out
+}
+
+/// xorshift64* generator, seeded so renders stay reproducible
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// uniform in `[0.0, 1.0)`
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// uniform in `[-1.0, 1.0)`
+    pub fn noise(&mut self) -> f32 {
+        self.next_f32() * 2.0 - 1.0
+    }
+}
@@ -1,31 +1,83 @@
 use serde::{Deserialize, Serialize};
 
-use crate::globals::SAMPLE_RATE;
+use crate::{
+    globals::{RESOURCE_MANAGER, SAMPLE_RATE},
+    resources::{WavetableId, WavetableSource},
+    utils::{self, wavetable::Wavetable, Rng},
+};
 use std::{
     f32::consts::{PI, TAU},
     fmt::Debug,
+    sync::Arc,
 };
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub enum Oscillator {
+    #[default]
     Sine,
     ModSquare,
     ModSaw,
+    /// the modulation scans through the frames of the table
+    Wavetable(WavetableId),
+    /// a wavetable oscillator as it is saved, it becomes a `Wavetable` when the instrument is built
+    WavetableSource(WavetableSource),
+    /// band limited with PolyBLEP when played
+    Saw,
+    Square,
+    Triangle,
+    /// square with the modulation as the pulse width
+    Pulse,
+    WhiteNoise,
+    /// -3 dB per octave
+    PinkNoise,
+    /// -6 dB per octave
+    BrownNoise,
+    /// sum of sines, partials above the nyquist frequency are left out when played
+    Additive(Vec<Partial>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Partial {
+    /// frequency as a multiple of the played frequency
+    pub ratio: f32,
+    pub amplitude: f32,
+    /// in radians
+    #[serde(default)]
+    pub phase: f32,
 }
 
-impl Default for Oscillator {
-    fn default() -> Self {
-        Self::Sine
+impl Partial {
+    pub fn new(ratio: f32, amplitude: f32, phase: f32) -> Self {
+        Self {
+            ratio,
+            amplitude,
+            phase,
+        }
+    }
+
+    /// partials at the integer multiples of the fundamental, starting with it
+    pub fn harmonics(amplitudes: &[f32]) -> Vec<Self> {
+        amplitudes
+            .iter()
+            .enumerate()
+            .map(|(i, amplitude)| Self::new((i + 1) as f32, *amplitude, 0.0))
+            .collect()
+    }
+
+    /// odd partials are the ones nearest to the odd harmonics, the fundamental is odd
+    fn is_odd(&self) -> bool {
+        self.ratio.round() as i64 % 2 == 1
     }
 }
 
 impl Oscillator {
+    /// naive waveform without state, noise is always white and wavetables are silent here
     #[inline(always)] // TODO Performance
     pub fn get_sample(&self, phase: f32, modulation: f32) -> f32 {
         use Oscillator::*;
         match self {
             Sine => phase.sin(), // TODO should I use a aproximation for (0, Tau)?
-            ModSquare => {
+            ModSquare | Pulse => {
                 if phase < modulation * TAU {
                     1.0
                 } else {
@@ -39,7 +91,65 @@ impl Oscillator {
                     (phase - (modulation + 1.0) * PI) / (modulation - 1.0) / PI
                 }
             }
+            // the table is only looked up once per note by `OscState`
+            Wavetable(_) | WavetableSource(_) => 0.0,
+            Saw => phase / PI - 1.0,
+            Square => {
+                if phase < PI {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Triangle => 1.0 - 2.0 * (phase / PI - 1.0).abs(),
+            WhiteNoise | PinkNoise | BrownNoise => Rng::new(phase.to_bits() as u64).noise(),
+            Additive(partials) => {
+                partials
+                    .iter()
+                    .map(|p| p.amplitude * (p.ratio * phase + p.phase).sin())
+                    .sum::<f32>()
+                    / total_amplitude(partials)
+            }
+        }
+    }
+
+    /// a single sample without the history of a running oscillator, so saw and square aren't
+    /// band limited and the noise is white, drawn from `seed`
+    pub fn get_sample_seeded(&self, phase: f32, modulation: f32, freq: f32, seed: u64) -> f32 {
+        use Oscillator::*;
+        match self {
+            WhiteNoise | PinkNoise | BrownNoise => Rng::new(seed).noise(),
+            Wavetable(id) => match RESOURCE_MANAGER.read().unwrap().get_wavetable(*id) {
+                Some(table) => table.get_sample(phase, modulation, freq),
+                None => 0.0,
+            },
+            _ => self.get_sample(phase, modulation),
+        }
+    }
+
+    /// a wavetable oscillator holding the source of its table instead of the id
+    pub(crate) fn extract(&self) -> Self {
+        match self {
+            Oscillator::Wavetable(id) => {
+                match RESOURCE_MANAGER.read().unwrap().get_wavetable_source(*id) {
+                    Ok(source) => Oscillator::WavetableSource(source),
+                    Err(_) => self.clone(),
+                }
+            }
+            _ => self.clone(),
+        }
+    }
+
+    /// adds the table of a saved wavetable oscillator to the resource manager
+    pub(crate) fn load_wavetable(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Oscillator::WavetableSource(source) = self {
+            let id = RESOURCE_MANAGER
+                .write()
+                .unwrap()
+                .add_wavetable(source.clone())?;
+            *self = Oscillator::Wavetable(id);
         }
+        Ok(())
     }
 
     pub fn play(&self, freq: &[f32], modulation: &[f32], samples: usize) -> Vec<f32> {
@@ -52,6 +162,18 @@ impl Oscillator {
         modulation: &[f32],
         samples: usize,
         phase_shift: f32,
+    ) -> Vec<f32> {
+        self.play_seeded(freq, modulation, samples, phase_shift, 0)
+    }
+
+    /// the seed only changes the output of the noise oscillators
+    pub fn play_seeded(
+        &self,
+        freq: &[f32],
+        modulation: &[f32],
+        samples: usize,
+        phase_shift: f32,
+        seed: u64,
     ) -> Vec<f32> {
         debug_assert_eq!(
             freq.len(),
@@ -63,13 +185,303 @@ impl Oscillator {
             samples,
             "modulation.len() doesn't match the requested samples"
         );
-        let mut out = Vec::with_capacity(samples);
-        let mut phase = phase_shift;
-        for i in 0..samples {
-            phase += TAU * freq[i] / (SAMPLE_RATE as f32);
-            phase %= TAU;
-            out.push(self.get_sample(phase, modulation[i]))
+        let mut state = OscState::new(self.clone(), phase_shift, seed);
+        (0..samples)
+            .map(|i| state.next(freq[i], modulation[i]))
+            .collect()
+    }
+}
+
+/// a running oscillator, band limiting and colored noise depend on the previous samples
+#[derive(Debug, Clone)]
+pub struct OscState {
+    oscillator: Oscillator,
+    phase: f32,
+    /// if the last sample started a new cycle
+    wrapped: bool,
+    rng: Rng,
+    /// filter states of the pink noise
+    pink: [f32; 3],
+    brown: f32,
+    triangle: f32,
+    table: Option<Arc<Wavetable>>,
+    additive: Option<AdditiveState>,
+}
+
+/// phases and weights of the partials of an additive oscillator
+#[derive(Debug, Clone)]
+struct AdditiveState {
+    phases: Vec<f32>,
+    weights: Vec<f32>,
+    /// the tilt and balance the weights were computed for
+    shape: (f32, f32),
+}
+
+impl OscState {
+    pub fn new(oscillator: Oscillator, phase: f32, seed: u64) -> Self {
+        let table = match oscillator {
+            // looked up once, as the band limited level depends on the frequency
+            Oscillator::Wavetable(id) => RESOURCE_MANAGER.read().unwrap().get_wavetable(id),
+            _ => None,
+        };
+        let additive = match &oscillator {
+            Oscillator::Additive(partials) => Some(AdditiveState {
+                phases: partials.iter().map(|p| p.ratio * phase + p.phase).collect(),
+                weights: spectrum(partials, 0.0, 0.0),
+                shape: (0.0, 0.0),
+            }),
+            _ => None,
+        };
+        Self {
+            oscillator,
+            phase,
+            wrapped: false,
+            rng: Rng::new(seed),
+            pink: [0.0; 3],
+            brown: 0.0,
+            triangle: Oscillator::Triangle.get_sample(phase.rem_euclid(TAU), 0.0),
+            table,
+            additive,
         }
-        out
+    }
+
+    /// `tilt` in dB per octave and `balance` from only odd at -1.0 to only even partials at 1.0,
+    /// only changes the sound of additive oscillators
+    pub fn set_shape(&mut self, tilt: f32, balance: f32) {
+        if let (Oscillator::Additive(partials), Some(state)) =
+            (&self.oscillator, &mut self.additive)
+        {
+            if state.shape != (tilt, balance) {
+                state.weights = spectrum(partials, tilt, balance);
+                state.shape = (tilt, balance);
+            }
+        }
+    }
+
+    /// pulls the phase back towards the start of the cycle, fully for an `amount` of 1.0
+    pub fn sync(&mut self, amount: f32) {
+        self.phase *= 1.0 - amount.clamp(0.0, 1.0);
+        if let (Oscillator::Additive(partials), Some(state)) =
+            (&self.oscillator, &mut self.additive)
+        {
+            for (partial, phase) in partials.iter().zip(&mut state.phases) {
+                *phase = partial.ratio * self.phase + partial.phase;
+            }
+        }
+    }
+
+    /// if the last sample started a new cycle, the point a hard synced oscillator resets at
+    pub fn wrapped(&self) -> bool {
+        self.wrapped
+    }
+
+    pub fn next(&mut self, freq: f32, modulation: f32) -> f32 {
+        let phase = self.phase + TAU * freq / (SAMPLE_RATE as f32);
+        self.wrapped = (phase / TAU).floor() != (self.phase / TAU).floor();
+        self.phase = phase % TAU;
+        let t = self.phase.rem_euclid(TAU) / TAU;
+        let dt = (freq / SAMPLE_RATE as f32).abs().min(0.5);
+
+        use Oscillator::*;
+        match &self.oscillator {
+            Additive(partials) => match &mut self.additive {
+                Some(state) => {
+                    let nyquist = SAMPLE_RATE as f32 / 2.0;
+                    let step = TAU * freq / SAMPLE_RATE as f32;
+                    let mut out = 0.0;
+                    for ((partial, phase), weight) in
+                        partials.iter().zip(&mut state.phases).zip(&state.weights)
+                    {
+                        *phase = (*phase + partial.ratio * step) % TAU;
+                        if (partial.ratio * freq).abs() < nyquist {
+                            out += weight * phase.sin();
+                        }
+                    }
+                    out
+                }
+                None => 0.0,
+            },
+            Wavetable(_) => match &self.table {
+                Some(table) => table.get_sample(self.phase, modulation, freq),
+                None => 0.0,
+            },
+            WavetableSource(_) => 0.0,
+            Saw => 2.0 * t - 1.0 - poly_blep(t, dt),
+            Square => pulse(t, dt, 0.5),
+            Pulse => pulse(t, dt, modulation.clamp(0.01, 0.99)),
+            Triangle => {
+                // integrated band limited square
+                self.triangle += 4.0 * dt * pulse(t, dt, 0.5);
+                self.triangle = self.triangle.clamp(-1.0, 1.0);
+                self.triangle
+            }
+            WhiteNoise => self.rng.noise(),
+            PinkNoise => {
+                // Paul Kellet's economy filter
+                let white = self.rng.noise();
+                self.pink[0] = 0.99765 * self.pink[0] + white * 0.099046;
+                self.pink[1] = 0.963 * self.pink[1] + white * 0.2965164;
+                self.pink[2] = 0.57 * self.pink[2] + white * 1.0526913;
+                ((self.pink.iter().sum::<f32>() + white * 0.1848) * 0.25).clamp(-1.0, 1.0)
+            }
+            BrownNoise => {
+                self.brown = (self.brown + 0.02 * self.rng.noise()) / 1.02;
+                (self.brown * 3.5).clamp(-1.0, 1.0)
+            }
+            Sine | ModSquare | ModSaw => self.oscillator.get_sample(self.phase, modulation),
+        }
+    }
+}
+
+fn total_amplitude(partials: &[Partial]) -> f32 {
+    partials
+        .iter()
+        .map(|p| p.amplitude.abs())
+        .sum::<f32>()
+        .max(f32::EPSILON)
+}
+
+/// weights of the partials, scaled so the untilted sum can't exceed 1.0
+fn spectrum(partials: &[Partial], tilt: f32, balance: f32) -> Vec<f32> {
+    let total = total_amplitude(partials);
+    partials
+        .iter()
+        .map(|p| {
+            let tilt = utils::db_to_factor(tilt * p.ratio.max(f32::EPSILON).log2());
+            let balance = if p.is_odd() {
+                1.0 - balance.max(0.0)
+            } else {
+                1.0 + balance.min(0.0)
+            };
+            p.amplitude * tilt * balance / total
+        })
+        .collect()
+}
+
+/// smooths the discontinuity of a rising step at `t == 0`, `dt` is the phase increment per sample
+fn poly_blep(t: f32, dt: f32) -> f32 {
+    if dt <= 0.0 {
+        0.0
+    } else if t < dt {
+        let t = t / dt;
+        t + t - t * t - 1.0
+    } else if t > 1.0 - dt {
+        let t = (t - 1.0) / dt;
+        t * t + t + t + 1.0
+    } else {
+        0.0
+    }
+}
+
+fn pulse(t: f32, dt: f32, width: f32) -> f32 {
+    let naive = if t < width { 1.0 } else { -1.0 };
+    naive + poly_blep(t, dt) - poly_blep((t + 1.0 - width) % 1.0, dt)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn band_limited_and_noise() {
+        let samples = 4410;
+        let freq = vec![3000.0; samples];
+        let modulation = vec![0.3; samples];
+        for osc in [
+            Oscillator::Saw,
+            Oscillator::Square,
+            Oscillator::Triangle,
+            Oscillator::Pulse,
+            Oscillator::WhiteNoise,
+            Oscillator::PinkNoise,
+            Oscillator::BrownNoise,
+        ] {
+            let out = osc.play_seeded(&freq, &modulation, samples, 0.0, 7);
+            assert!(out.iter().all(|x| x.abs() <= 1.2), "{osc:?} out of range");
+            assert_eq!(out, osc.play_seeded(&freq, &modulation, samples, 0.0, 7));
+        }
+
+        // the blep removes the hard jump of the naive saw
+        let saw = Oscillator::Saw.play(&freq, &modulation, samples);
+        let max_step = saw
+            .windows(2)
+            .map(|w| (w[1] - w[0]).abs())
+            .fold(0.0, f32::max);
+        assert!(max_step < 1.9);
+
+        let white = Oscillator::WhiteNoise.play_seeded(&freq, &modulation, samples, 0.0, 1);
+        let other = Oscillator::WhiteNoise.play_seeded(&freq, &modulation, samples, 0.0, 2);
+        assert_ne!(white, other);
+    }
+
+    #[test]
+    fn additive_partials_and_shape() {
+        let samples = 4410;
+        let freq = vec![3000.0; samples];
+        let modulation = vec![0.3; samples];
+
+        let additive = Oscillator::Additive(Partial::harmonics(&[1.0, 0.5, 0.5, 0.5, 0.5, 0.5]));
+        let high_freq = vec![8000.0; samples];
+        let high = additive.play(&high_freq, &modulation, samples);
+        let sine = Oscillator::Sine.play(&freq, &modulation, samples);
+        // only the fundamental and the second harmonic are below nyquist
+        let expected: Vec<f32> = Oscillator::Additive(Partial::harmonics(&[1.0, 0.5]))
+            .play(&high_freq, &modulation, samples)
+            .iter()
+            .map(|x| x * 1.5 / 3.5)
+            .collect();
+        assert!(high
+            .iter()
+            .zip(&expected)
+            .all(|(a, b)| (a - b).abs() < 1e-3));
+
+        let mut state = OscState::new(
+            Oscillator::Additive(Partial::harmonics(&[1.0, 1.0])),
+            0.0,
+            0,
+        );
+        state.set_shape(0.0, -1.0);
+        let odd: Vec<f32> = (0..samples).map(|_| state.next(3000.0, 0.0)).collect();
+        assert!(odd
+            .iter()
+            .zip(&sine)
+            .all(|(a, b)| (a - b * 0.5).abs() < 1e-3));
+    }
+
+    #[test]
+    fn sync_restarts_the_cycle() {
+        for osc in [
+            Oscillator::Saw,
+            Oscillator::Additive(Partial::harmonics(&[1.0, 0.5, 0.25])),
+        ] {
+            let mut fresh = OscState::new(osc.clone(), 0.0, 0);
+            let mut synced = OscState::new(osc.clone(), 0.0, 0);
+            for _ in 0..37 {
+                synced.next(441.0, 0.0);
+            }
+            synced.sync(1.0);
+            for _ in 0..100 {
+                let (a, b) = (fresh.next(441.0, 0.0), synced.next(441.0, 0.0));
+                assert!((a - b).abs() < 1e-4, "{osc:?} didn't restart");
+            }
+        }
+
+        let mut state = OscState::new(Oscillator::Sine, 0.0, 0);
+        let wraps = (0..441)
+            .filter(|_| {
+                state.next(441.0, 0.0);
+                state.wrapped()
+            })
+            .count();
+        assert_eq!(wraps, 4);
+    }
+
+    #[test]
+    fn wavetables_are_saved_with_their_source() {
+        let source = WavetableSource::Harmonics(vec![vec![1.0], vec![1.0, 0.5]]);
+        let mut osc = Oscillator::WavetableSource(source.clone());
+        osc.load_wavetable().unwrap();
+        assert!(matches!(osc, Oscillator::Wavetable(_)));
+        assert!(matches!(osc.extract(), Oscillator::WavetableSource(saved) if saved == source));
     }
 }
@@ -0,0 +1,201 @@
+use std::f32::consts::TAU;
+
+use crate::{globals::SAMPLE_RATE, Error};
+
+/// samples per frame, frames of other lengths are resampled
+pub const FRAME_SIZE: usize = 2048;
+
+/// frames of a single cycle each, with band limited copies per octave
+#[derive(Debug, Clone)]
+pub struct Wavetable {
+    /// `levels[k]` only holds harmonics up to `FRAME_SIZE / 2 >> k`
+    levels: Vec<Vec<Vec<f32>>>,
+}
+
+impl Wavetable {
+    pub fn from_frames(frames: Vec<Vec<f32>>) -> Result<Self, Error> {
+        if frames.is_empty() || frames.iter().any(|frame| frame.is_empty()) {
+            return Err(Error::Value);
+        }
+        let spectra: Vec<(Vec<f32>, Vec<f32>)> = frames
+            .iter()
+            .map(|frame| {
+                let mut re = resample_cycle(frame);
+                let mut im = vec![0.0; FRAME_SIZE];
+                fft(&mut re, &mut im, false);
+                (re, im)
+            })
+            .collect();
+
+        let mut levels = Vec::new();
+        let mut max_harmonic = FRAME_SIZE / 2;
+        while max_harmonic >= 1 {
+            levels.push(
+                spectra
+                    .iter()
+                    .map(|(re, im)| band_limit(re, im, max_harmonic))
+                    .collect::<Vec<_>>(),
+            );
+            max_harmonic /= 2;
+        }
+
+        let peak = levels[0]
+            .iter()
+            .flatten()
+            .fold(0.0_f32, |max, x| max.max(x.abs()));
+        if peak > 0.0 {
+            levels
+                .iter_mut()
+                .flatten()
+                .flatten()
+                .for_each(|x| *x /= peak);
+        }
+        Ok(Self { levels })
+    }
+
+    /// every frame is given as the amplitudes of its harmonics, starting at the fundamental
+    pub fn from_harmonics(frames: &[Vec<f32>]) -> Result<Self, Error> {
+        let frames = frames
+            .iter()
+            .map(|harmonics| {
+                (0..FRAME_SIZE)
+                    .map(|i| {
+                        harmonics
+                            .iter()
+                            .take(FRAME_SIZE / 2)
+                            .enumerate()
+                            .map(|(h, amp)| {
+                                amp * (TAU * ((h + 1) * i % FRAME_SIZE) as f32 / FRAME_SIZE as f32)
+                                    .sin()
+                            })
+                            .sum()
+                    })
+                    .collect()
+            })
+            .collect();
+        Self::from_frames(frames)
+    }
+
+    pub fn frames(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    /// `phase` is in radians, `position` scans through the frames from 0.0 to 1.0,
+    /// harmonics above the nyquist frequency at `freq` are left out
+    pub fn get_sample(&self, phase: f32, position: f32, freq: f32) -> f32 {
+        let level = &self.levels[self.level(freq)];
+        let pos = position.clamp(0.0, 1.0) * (level.len() - 1) as f32;
+        let frame = pos as usize;
+        let frame_frac = pos - frame as f32;
+
+        let index = phase.rem_euclid(TAU) / TAU * FRAME_SIZE as f32;
+        let i = (index as usize).min(FRAME_SIZE - 1);
+        let frac = index - i as f32;
+        let read = |frame: &[f32]| frame[i] * (1.0 - frac) + frame[(i + 1) % FRAME_SIZE] * frac;
+
+        let current = read(&level[frame]);
+        if frame_frac > 0.0 {
+            current * (1.0 - frame_frac) + read(&level[frame + 1]) * frame_frac
+        } else {
+            current
+        }
+    }
+
+    /// the first level without harmonics above the nyquist frequency
+    fn level(&self, freq: f32) -> usize {
+        let max_harmonic = (SAMPLE_RATE as f32 / 2.0 / freq.abs().max(1.0)) as usize;
+        let mut level = 0;
+        while level + 1 < self.levels.len() && (FRAME_SIZE / 2) >> level > max_harmonic {
+            level += 1;
+        }
+        level
+    }
+}
+
+/// stretches a single cycle to `FRAME_SIZE` samples
+fn resample_cycle(frame: &[f32]) -> Vec<f32> {
+    if frame.len() == FRAME_SIZE {
+        return frame.to_vec();
+    }
+    (0..FRAME_SIZE)
+        .map(|i| {
+            let pos = i as f32 * frame.len() as f32 / FRAME_SIZE as f32;
+            let index = pos as usize;
+            let frac = pos - index as f32;
+            frame[index] * (1.0 - frac) + frame[(index + 1) % frame.len()] * frac
+        })
+        .collect()
+}
+
+/// the frame with only the harmonics `1..=max_harmonic` of its spectrum
+fn band_limit(re: &[f32], im: &[f32], max_harmonic: usize) -> Vec<f32> {
+    let mut re = re.to_vec();
+    let mut im = im.to_vec();
+    for bin in 0..FRAME_SIZE {
+        let harmonic = bin.min(FRAME_SIZE - bin);
+        if harmonic == 0 || harmonic > max_harmonic {
+            re[bin] = 0.0;
+            im[bin] = 0.0;
+        }
+    }
+    fft(&mut re, &mut im, true);
+    re.iter().map(|x| x / FRAME_SIZE as f32).collect()
+}
+
+/// in place radix-2 fft, the inverse isn't scaled
+fn fft(re: &mut [f32], im: &mut [f32], inverse: bool) {
+    let n = re.len();
+    debug_assert!(n.is_power_of_two(), "fft length has to be a power of two");
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut len = 2;
+    while len <= n {
+        let angle = sign * TAU / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f32).sin_cos();
+                let a = start + k;
+                let b = a + len / 2;
+                let t_re = re[b] * cos - im[b] * sin;
+                let t_im = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn band_limited_levels() {
+        // saw wave
+        let harmonics: Vec<f32> = (1..=1024).map(|h| 1.0 / h as f32).collect();
+        let table = Wavetable::from_harmonics(&[harmonics, vec![1.0]]).unwrap();
+        assert_eq!(table.frames(), 2);
+        assert_eq!(table.level(20.0), 0);
+        assert_eq!((FRAME_SIZE / 2) >> table.level(5000.0), 4);
+
+        // the second frame is a sine, scaled by the same factor as the saw
+        let quarter = table.get_sample(TAU / 4.0, 1.0, 100.0);
+        assert!(quarter > 0.0 && quarter < 1.0);
+        assert!(table.get_sample(0.0, 1.0, 100.0).abs() < 1e-3);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{globals::SAMPLE_RATE, utils};
+use crate::{analysis, globals::SAMPLE_RATE, utils};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use hound::WavSpec;
 use itertools::interleave;
@@ -91,16 +91,62 @@ impl Wave {
         self.left = self.left.iter().map(|x| x * value).collect()
     }
 
+    /// equal power panning, -1.0 is fully left and 1.0 fully right
+    pub fn pan(&mut self, pan: f32) {
+        let angle = (pan.clamp(-1.0, 1.0) + 1.0) * std::f32::consts::FRAC_PI_4;
+        let right = angle.sin() * std::f32::consts::SQRT_2;
+        let left = angle.cos() * std::f32::consts::SQRT_2;
+        self.right.iter_mut().for_each(|x| *x *= right);
+        self.left.iter_mut().for_each(|x| *x *= left);
+    }
+
+    /// reads through the wave with linear interpolation, stepping `ratio` samples per output sample,
+    /// the wave is returned as it is for ratios which aren't positive and finite
+    pub fn resample(&self, ratio: f32) -> Self {
+        if !(ratio > 0.0 && ratio.is_finite()) {
+            return self.clone();
+        }
+        let len = (self.len() as f64 / ratio as f64) as usize;
+        let mut right = Vec::with_capacity(len);
+        let mut left = Vec::with_capacity(len);
+        for i in 0..len {
+            let pos = i as f64 * ratio as f64;
+            let index = pos as usize;
+            let frac = (pos - index as f64) as f32;
+            let next = (index + 1).min(self.len() - 1);
+            right.push(self.right[index] * (1.0 - frac) + self.right[next] * frac);
+            left.push(self.left[index] * (1.0 - frac) + self.left[next] * frac);
+        }
+        Self::from_vecs(right, left)
+    }
+
+    pub fn clamp(&mut self, limit: f32) {
+        self.right
+            .iter_mut()
+            .for_each(|x| *x = x.clamp(-limit, limit));
+        self.left
+            .iter_mut()
+            .for_each(|x| *x = x.clamp(-limit, limit))
+    }
+
     pub fn scale_by_vec(&mut self, vec: Vec<f32>) {
         debug_assert_eq!(self.len(), vec.len(), "error in scale_by_vec");
         for (e1, e2) in zip(&mut self.right, vec.iter()) {
             *e1 *= e2;
         }
-        for (e1, e2) in zip(&mut self.left, vec.into_iter()) {
+        for (e1, e2) in zip(&mut self.left, vec) {
             *e1 *= e2;
         }
     }
 
+    pub fn right(&self) -> &[f32] {
+        &self.right
+    }
+
+    pub fn left(&self) -> &[f32] {
+        &self.left
+    }
+
     pub fn len(&self) -> usize {
         self.right.len()
     }
@@ -109,11 +155,15 @@ impl Wave {
         self.right.is_empty()
     }
 
-    pub fn rms_normalize(&mut self) {
-        let rms = ((self.left.iter().fold(0.0, |i, x| i + x * x)
+    pub fn rms(&self) -> f32 {
+        ((self.left.iter().fold(0.0, |i, x| i + x * x)
             + self.right.iter().fold(0.0, |i, x| i + x * x))
             / (2.0 * self.len() as f32))
-            .sqrt();
+            .sqrt()
+    }
+
+    pub fn rms_normalize(&mut self) {
+        let rms = self.rms();
         self.right.iter_mut().for_each(|x| *x /= rms * 10.0);
         self.left.iter_mut().for_each(|x| *x /= rms * 10.0); // TODO
     }
@@ -127,10 +177,22 @@ impl Wave {
         self.scale(scale)
     }
 
+    /// scales the wave to the given integrated loudness in LUFS
+    pub fn loudness_normalize(&mut self, target: f32) {
+        let loudness = analysis::integrated_loudness(self);
+        if loudness.is_finite() {
+            self.scale(utils::db_to_factor(target - loudness))
+        }
+    }
+
     pub fn save(&self, path: impl AsRef<Path>) {
         let mut wave = self.clone();
         wave.rms_normalize();
+        wave.write_wav(path).expect("Error while saving wave!");
+    }
 
+    /// writes the wave as 16 bit wav without normalizing it first
+    pub fn write_wav(&self, path: impl AsRef<Path>) -> Result<(), hound::Error> {
         let spec = WavSpec {
             channels: 2,
             sample_rate: SAMPLE_RATE as u32,
@@ -138,18 +200,18 @@ impl Wave {
             sample_format: hound::SampleFormat::Int,
         };
 
-        let mut writer = hound::WavWriter::create(path, spec).expect("Error while saving wave!");
-        let mut writer_i16 = writer.get_i16_writer(wave.len() as u32 * 2);
-        let right = wave.right.iter().map(|x| (x * i16::MAX as f32) as i16);
-        let left = wave.left.iter().map(|x| (x * i16::MAX as f32) as i16);
+        let mut writer = hound::WavWriter::create(path, spec)?;
+        let mut writer_i16 = writer.get_i16_writer(self.len() as u32 * 2);
+        let right = self.right.iter().map(|x| (x * i16::MAX as f32) as i16);
+        let left = self.left.iter().map(|x| (x * i16::MAX as f32) as i16);
         for (r, l) in zip(right, left) {
             unsafe {
                 writer_i16.write_sample_unchecked(r);
                 writer_i16.write_sample_unchecked(l);
             }
         }
-        writer_i16.flush().expect("Error while saving wave!");
-        writer.finalize().expect("Error while saving wave!");
+        writer_i16.flush()?;
+        writer.finalize()
     }
 }
 